@@ -0,0 +1,53 @@
+use std::time::Instant;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::Error;
+use tracing::info;
+
+/// Routes whose path segments are secrets, logged by their pattern instead
+const SECRET_ROUTES: &[&str] = &["/wake/{token}"];
+
+/// Middleware logging one line per request, WebSocket upgrades included, with
+/// its method, path, status, latency and peer address as structured fields;
+/// with `WOL_LOG_FORMAT=json` each is a JSON object.
+///
+/// Bodies and query strings are never logged since they carry device passwords
+/// (`/wake` bodies, `/ws?password=...`), nor the token of a wake link.
+/// `RUST_LOG=info,wol_server::access_log=off` turns the lines off.
+pub async fn log_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    // The request moves into the handler, so note what to log beforehand
+    let method = req.method().clone();
+    let mut path = req.path().to_string();
+    let peer = req.peer_addr().map_or_else(|| "-".to_string(), |addr| addr.ip().to_string());
+    let started = Instant::now();
+
+    let result = next.call(req).await;
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+    let status = match &result {
+        Ok(response) => {
+            if let Some(pattern) = response.request().match_pattern().filter(|pattern| SECRET_ROUTES.contains(&pattern.as_str())) {
+                path = pattern;
+            }
+            response.status()
+        },
+        Err(e) => e.as_response_error().status_code(),
+    };
+    info!(
+        method = %method,
+        path = %path,
+        status = status.as_u16(),
+        latency_ms,
+        peer = %peer,
+        "[Access] {} {} {} {:.1}ms",
+        method,
+        path,
+        status.as_u16(),
+        latency_ms
+    );
+    result
+}
@@ -0,0 +1,55 @@
+use std::future::{ready, Ready};
+
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpRequest};
+use tracing::warn;
+
+use crate::config::Config;
+use crate::error::ApiError;
+
+/// Extractor guarding admin-only handlers.
+///
+/// Requires `Authorization: Bearer <WOL_ADMIN_KEY>`. When no admin key is
+/// configured every admin endpoint is disabled.
+pub struct AdminAuth;
+
+impl FromRequest for AdminAuth {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(check(req))
+    }
+}
+
+fn check(req: &HttpRequest) -> Result<AdminAuth, actix_web::Error> {
+    let admin_key = req
+        .app_data::<web::Data<Config>>()
+        .and_then(|config| config.admin_key.clone());
+
+    let Some(admin_key) = admin_key else {
+        return Err(ApiError::Forbidden("Admin API disabled".to_string()).into());
+    };
+
+    let supplied = req
+        .headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    if constant_time_eq(supplied.as_bytes(), admin_key.as_bytes()) {
+        Ok(AdminAuth)
+    } else {
+        warn!("[Admin] Rejected admin request: path={}", req.path());
+        Err(ApiError::InvalidToken("Invalid admin key".to_string()).into())
+    }
+}
+
+/// Compare two byte strings without short-circuiting on the first mismatch
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,94 @@
+use std::net::IpAddr;
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpRequest, ResponseError};
+use ipnet::IpNet;
+use tracing::warn;
+
+use crate::config::Config;
+use crate::error::ApiError;
+
+/// Read a CIDR block such as `192.168.1.0/24`; a bare address stands for itself alone
+pub fn parse_cidr(spec: &str) -> Result<IpNet, String> {
+    let spec = spec.trim();
+    spec.parse::<IpNet>()
+        .or_else(|_| spec.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("'{}' is not a CIDR block or IP address", spec))
+}
+
+/// The address a request came from.
+///
+/// Behind a trusted proxy this is the last `X-Forwarded-For` entry, the one
+/// the proxy itself appended; earlier entries are whatever the client sent.
+/// Otherwise it is the peer of the connection.
+pub fn client_ip(req: &HttpRequest, trust_proxy: bool) -> Option<IpAddr> {
+    let forwarded = trust_proxy
+        .then(|| req.headers().get("X-Forwarded-For"))
+        .flatten()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|last| last.trim().parse::<IpAddr>().ok());
+    // IPv4 clients of a dual-stack listener show up as ::ffff:a.b.c.d
+    forwarded.or_else(|| req.peer_addr().map(|addr| addr.ip())).map(|ip| ip.to_canonical())
+}
+
+/// Whether `ip` falls in one of `allowed`; an empty list allows everything
+pub fn permits(allowed: &[IpNet], ip: Option<IpAddr>) -> bool {
+    allowed.is_empty() || ip.is_some_and(|ip| allowed.iter().any(|net| net.contains(&ip)))
+}
+
+/// Routes that wake devices: `/wake` and its batch, group, by-MAC and waiting
+/// variants, wake links (`/wake/{token}`, not `/wake/{esp_id}/ping`), and
+/// `POST /schedule`, which queues a wake to be sent later
+fn is_wake_route(method: &Method, path: &str) -> bool {
+    matches!(path, "/wake" | "/wake-batch" | "/wake-group" | "/wake-by-mac" | "/wake-and-wait")
+        || path.strip_prefix("/wake/").is_some_and(|token| !token.contains('/'))
+        || (method == Method::POST && path == "/schedule")
+}
+
+/// Middleware answering 403 to wake requests from clients outside `WOL_WAKE_ALLOW_CIDR`.
+///
+/// Runs before the handler, so a refused client learns nothing about the device.
+pub async fn restrict_wakes(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let Some(config) = req.app_data::<web::Data<Config>>().cloned() else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+    if config.wake_allow_cidrs.is_empty() || !is_wake_route(req.method(), req.path()) {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    }
+
+    let client = client_ip(req.request(), config.trust_proxy);
+    if !permits(&config.wake_allow_cidrs, client) {
+        let client = client.map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+        warn!("[Wake] Client not in WOL_WAKE_ALLOW_CIDR: path={}, client={}", req.path(), client);
+        let response = ApiError::Forbidden("Wake requests are not allowed from this address".to_string()).error_response();
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_wake_entry_point_is_restricted() {
+        for path in ["/wake", "/wake-batch", "/wake-group", "/wake-by-mac", "/wake-and-wait", "/wake/abc123"] {
+            assert!(is_wake_route(&Method::POST, path), "{}", path);
+        }
+        assert!(is_wake_route(&Method::POST, "/schedule"));
+        for path in ["/wake/desk/ping", "/wake-by-mac/aa:bb:cc:dd:ee:ff/routing", "/devices", "/ws"] {
+            assert!(!is_wake_route(&Method::POST, path), "{}", path);
+        }
+        // Listing and cancelling scheduled wakes doesn't wake anything
+        assert!(!is_wake_route(&Method::GET, "/schedule"));
+        assert!(!is_wake_route(&Method::DELETE, "/schedule/abc123"));
+    }
+}
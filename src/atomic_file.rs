@@ -0,0 +1,60 @@
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Replace `path` with `contents` without ever leaving it half-written.
+///
+/// The data goes to `<path>.tmp` and is flushed to disk before being renamed
+/// over the original, so a crash at any point leaves either the old file or
+/// the new one in place.
+pub fn write(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    write_with(path, |file| file.write_all(contents.as_ref()))
+}
+
+/// `write`, with `fill` producing the new contents into the temporary file
+fn write_with(path: &Path, fill: impl FnOnce(&mut File) -> io::Result<()>) -> io::Result<()> {
+    let tmp = tmp_path(path);
+    let written = File::create(&tmp).and_then(|mut file| {
+        fill(&mut file)?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|_| fs::rename(&tmp, path)) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// `devices.json` -> `devices.json.tmp`, next to the original so the rename
+/// never crosses filesystems
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_dying_partway_leaves_the_original_intact() {
+        let dir = std::env::temp_dir().join(format!("wol-server-test-{}-atomic", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("devices.json");
+        write(&path, r#"{"desk":{"esp_id":"desk"}}"#).unwrap();
+
+        let replacement = r#"{"desk":{"esp_id":"desk"},"nas":{"esp_id":"nas"}}"#;
+        let result = write_with(&path, |file| {
+            file.write_all(&replacement.as_bytes()[..replacement.len() / 2])?;
+            Err(io::Error::other("killed mid-write"))
+        });
+
+        assert!(result.is_err());
+        let original: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(original["desk"]["esp_id"], "desk");
+        assert!(!tmp_path(&path).exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,58 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Keeps the newest `keep` timestamped copies of the device file in `dir`
+#[derive(Debug, Clone)]
+pub struct BackupPolicy {
+    pub dir: PathBuf,
+    pub keep: usize,
+}
+
+impl BackupPolicy {
+    /// Copy `source` into the backup directory, then drop the oldest copies
+    pub fn rotate(&self, source: &Path) -> io::Result<()> {
+        if !source.exists() {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.dir)?;
+
+        let (stem, ext) = name_parts(source);
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        // Fixed-width timestamps keep lexical order equal to age order
+        fs::copy(source, self.dir.join(format!("{}-{:013}{}", stem, millis, ext)))?;
+
+        let prefix = format!("{}-", stem);
+        let mut backups = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with(&prefix) && name.ends_with(&ext))
+            })
+            .collect::<Vec<_>>();
+        backups.sort();
+
+        let excess = backups.len().saturating_sub(self.keep);
+        for old in &backups[..excess] {
+            fs::remove_file(old)?;
+        }
+        Ok(())
+    }
+}
+
+/// Split `devices.json` into (`devices`, `.json`)
+fn name_parts(path: &Path) -> (String, String) {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("devices").to_string();
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| format!(".{}", e))
+        .unwrap_or_default();
+    (stem, ext)
+}
@@ -0,0 +1,166 @@
+use std::fs;
+use std::net::TcpListener;
+use std::path::Path;
+
+use crate::config::{Config, LogOutput};
+use crate::crypto::{self, StorageCipher};
+use crate::repository::{Backend, DeviceRepository};
+use crate::sqlite::SqliteRepository;
+use crate::{schema, tls};
+
+/// Validate configuration and data without serving, printing one line per check.
+///
+/// Returns whether every check passed; used by `--check-config`.
+pub fn run(backend: Backend, device_file: &str, bind_addr: &str, tls: (Option<&Path>, Option<&Path>)) -> bool {
+    let mut results = Vec::new();
+
+    let config = Config::from_env();
+    results.push(("configuration", config.as_ref().map(|_| "ok".to_string()).map_err(|e| e.to_string())));
+    if let Ok(config) = &config {
+        let cipher = config.storage_key.as_deref().map(StorageCipher::new);
+        let devices = match backend {
+            Backend::Json => check_devices(device_file, config.device_dir.as_deref(), cipher.as_ref()),
+            Backend::Sqlite => check_database(device_file, config),
+        };
+        results.push(("device data", devices));
+        if let LogOutput::File(path) = &config.log_output {
+            results.push(("log file", check_writable_file(Path::new(path))));
+        }
+        if let Some(backup) = &config.backup {
+            results.push(("backup directory", check_dir(&backup.dir)));
+        }
+        results.push(("webhook dead-letter file", check_writable_file(&config.webhook_retry.dead_letter)));
+        results.push(("wake history file", check_writable_file(&config.history_file)));
+        results.push(("web UI", check_static_dir(&config.static_dir)));
+    }
+    results.push(("bind address", check_bind(bind_addr)));
+    if let Some(result) = check_tls(tls) {
+        results.push(("TLS certificate", result));
+    }
+
+    let mut ok = true;
+    for (name, result) in &results {
+        match result {
+            Ok(detail) => println!("[ OK ] {}: {}", name, detail),
+            Err(e) => {
+                ok = false;
+                println!("[FAIL] {}: {}", name, e);
+            },
+        }
+    }
+    println!(
+        "{} of {} checks passed",
+        results.iter().filter(|(_, result)| result.is_ok()).count(),
+        results.len()
+    );
+    ok
+}
+
+fn check_static_dir(dir: &Path) -> Result<String, String> {
+    let index = dir.join("index.html");
+    fs::metadata(&index)
+        .map(|_| format!("{} found", index.display()))
+        .map_err(|e| format!("cannot read {}: {}", index.display(), e))
+}
+
+fn check_devices(device_file: &str, dir: Option<&Path>, cipher: Option<&StorageCipher>) -> Result<String, String> {
+    let Some(dir) = dir else {
+        return match fs::read_to_string(device_file) {
+            Ok(content) => crypto::decode(&content, cipher)
+                .map_err(|e| format!("{}: {}", device_file, e))
+                .and_then(|json| {
+                    schema::parse_devices(&json).map_err(|e| format!("{} is invalid: {}", device_file, e))
+                })
+                .map(|devices| format!("{} device(s) in {}", devices.len(), device_file)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(format!("{} will be created", device_file)),
+            Err(e) => Err(format!("cannot read {}: {}", device_file, e)),
+        };
+    };
+
+    if !dir.exists() {
+        return Ok(format!("{} will be created", dir.display()));
+    }
+    let entries = fs::read_dir(dir).map_err(|e| format!("cannot read {}: {}", dir.display(), e))?;
+    let mut count = 0;
+    let mut invalid = Vec::new();
+    for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+        if !path.is_file() || path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let parsed = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|content| crypto::decode(&content, cipher).map_err(|e| e.to_string()))
+            .and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string()))
+            .and_then(|value| schema::parse_device(value, None));
+        match parsed {
+            Ok(_) => count += 1,
+            Err(e) => invalid.push(format!("{}: {}", path.display(), e)),
+        }
+    }
+    if invalid.is_empty() {
+        Ok(format!("{} device file(s) in {}", count, dir.display()))
+    } else {
+        Err(format!("invalid device file(s): {}", invalid.join("; ")))
+    }
+}
+
+/// The file can be appended to, or created if missing, without changing its contents
+fn check_writable_file(path: &Path) -> Result<String, String> {
+    let existed = path.exists();
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("cannot write {}: {}", path.display(), e))?;
+    if !existed {
+        // Don't leave a stray empty file behind
+        let _ = fs::remove_file(path);
+    }
+    Ok(format!("{} is writable", path.display()))
+}
+
+fn check_database(path: &str, config: &Config) -> Result<String, String> {
+    if config.device_dir.is_some() {
+        return Err("WOL_DEVICE_DIR only applies to the json backend".to_string());
+    }
+    if config.storage_key.is_some() {
+        return Err("WOL_STORAGE_KEY is not supported by the sqlite backend".to_string());
+    }
+    if !Path::new(path).exists() {
+        return Ok(format!("{} will be created", path));
+    }
+    SqliteRepository::open_read_only(Path::new(path))
+        .and_then(|db| db.list())
+        .map(|devices| format!("{} device(s) in {}", devices.len(), path))
+        .map_err(|e| format!("{} is invalid: {}", path, e))
+}
+
+fn check_dir(dir: &Path) -> Result<String, String> {
+    if dir.exists() && !dir.is_dir() {
+        return Err(format!("{} is not a directory", dir.display()));
+    }
+    if dir.exists() {
+        Ok(format!("{} exists", dir.display()))
+    } else {
+        Ok(format!("{} will be created", dir.display()))
+    }
+}
+
+fn check_bind(addr: &str) -> Result<String, String> {
+    TcpListener::bind(addr)
+        .map(|_| format!("{} is available", addr))
+        .map_err(|e| format!("cannot bind {}: {}", addr, e))
+}
+
+/// `None` when TLS is not configured at all
+fn check_tls(paths: (Option<&Path>, Option<&Path>)) -> Option<Result<String, String>> {
+    match paths {
+        (Some(cert), Some(key)) => Some(
+            tls::load(cert, key)
+                .map(|_| format!("{} with key {}", cert.display(), key.display()))
+                .map_err(|e| format!("{:#}", e)),
+        ),
+        (None, None) => None,
+        _ => Some(Err("--tls-cert and --tls-key must be given together".to_string())),
+    }
+}
@@ -0,0 +1,128 @@
+use chrono::Utc;
+
+use crate::config::Config;
+use crate::crypto::StorageCipher;
+use crate::repository::{self, Backend, DeviceRepository};
+use crate::{description_taken, new_device_id, password, wol, Device};
+
+const USAGE: &str = "usage:
+  wol-server device list
+  wol-server device add <esp_id> <mac_address[,mac_address...]> <password> [--description TEXT] [--ip ADDRESS] [--tag TAG]...
+  wol-server device remove <esp_id>";
+
+/// Run `wol-server device ...` against the device data without starting the server.
+///
+/// Changes go through the same `DeviceRepository` as the server's, so backups,
+/// encryption and the storage layout behave exactly as they do there. A
+/// running server keeps its own copy in memory and will overwrite edits made
+/// here on its next save, so stop it first.
+pub fn run(backend: Backend, device_file: &str, args: &[String]) -> anyhow::Result<()> {
+    let config = Config::from_env()?;
+    let repo = repository::open(
+        backend,
+        device_file,
+        config.device_dir.as_deref(),
+        config.backup.clone(),
+        config.storage_key.as_deref().map(StorageCipher::new),
+    )?;
+
+    match args.split_first() {
+        Some((command, rest)) if command == "list" && rest.is_empty() => list(&*repo),
+        Some((command, rest)) if command == "add" => add(&*repo, &config, rest),
+        Some((command, [esp_id])) if command == "remove" => remove(&*repo, esp_id),
+        _ => anyhow::bail!("{}", USAGE),
+    }
+}
+
+fn list(repo: &dyn DeviceRepository) -> anyhow::Result<()> {
+    let devices = repo.list()?;
+    let mut devices = devices.values().collect::<Vec<_>>();
+    devices.sort_by(|a, b| a.esp_id.cmp(&b.esp_id));
+    for device in &devices {
+        println!(
+            "{}\t{}\t{}\t{}",
+            device.esp_id,
+            device.mac_addresses.join(","),
+            device.description,
+            device.tags.join(",")
+        );
+    }
+    println!("{} device(s)", devices.len());
+    Ok(())
+}
+
+fn add(repo: &dyn DeviceRepository, config: &Config, args: &[String]) -> anyhow::Result<()> {
+    let [esp_id, mac_list, password, options @ ..] = args else {
+        anyhow::bail!("{}", USAGE);
+    };
+    if esp_id.trim().is_empty() {
+        anyhow::bail!("esp_id must not be empty");
+    }
+    let mut mac_addresses = Vec::new();
+    for mac_address in mac_list.split(',').map(str::trim) {
+        let mac = wol::parse_mac(mac_address).map_err(|e| anyhow::anyhow!("Invalid MAC address '{}': {}", mac_address, e))?;
+        let mac = wol::format_mac(&mac);
+        if !mac_addresses.contains(&mac) {
+            mac_addresses.push(mac);
+        }
+    }
+
+    let mut description = String::new();
+    let mut ip_address = None;
+    let mut tags = Vec::new();
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let Some(value) = options.next() else {
+            anyhow::bail!("{} needs a value\n{}", option, USAGE);
+        };
+        match option.as_str() {
+            "--description" => description = value.clone(),
+            "--ip" => ip_address = Some(value.clone()),
+            "--tag" => tags.push(value.clone()),
+            _ => anyhow::bail!("Unknown option '{}'\n{}", option, USAGE),
+        }
+    }
+
+    if config.unique_descriptions && description_taken(&repo.list()?, esp_id, &description) {
+        anyhow::bail!("Description already used by another device");
+    }
+    // Server-maintained state and fields the CLI can't set carry over
+    let previous = repo.get(esp_id)?;
+    let previous = previous.as_ref();
+    let device = Device {
+        esp_id: esp_id.clone(),
+        id: previous.map_or_else(new_device_id, |previous| previous.id.clone()),
+        mac_addresses,
+        description,
+        password: String::new(),
+        password_hash: Some(password::hash(password)),
+        credentials: previous.map(|previous| previous.credentials.clone()).unwrap_or_default(),
+        ip_address,
+        wake_target: previous.and_then(|previous| previous.wake_target.clone()),
+        wake_port: previous.and_then(|previous| previous.wake_port),
+        health_url: previous.and_then(|previous| previous.health_url.clone()),
+        health_status: previous.and_then(|previous| previous.health_status),
+        keepalive_secs: previous.and_then(|previous| previous.keepalive_secs),
+        auto_restore: previous.is_some_and(|previous| previous.auto_restore),
+        power_state: previous.and_then(|previous| previous.power_state),
+        tags,
+        wake_note: previous.and_then(|previous| previous.wake_note.clone()),
+        allowed_commands: previous.map(|previous| previous.allowed_commands.clone()).unwrap_or_default(),
+        registered_at: previous.and_then(|previous| previous.registered_at).or(Some(Utc::now())),
+        last_seen: previous.and_then(|previous| previous.last_seen),
+        wake_count: previous.map_or(0, |previous| previous.wake_count),
+        last_wake: previous.and_then(|previous| previous.last_wake),
+    };
+
+    repo.insert(&device)?;
+    println!("{} device {}", if previous.is_some() { "Updated" } else { "Added" }, esp_id);
+    Ok(())
+}
+
+fn remove(repo: &dyn DeviceRepository, esp_id: &str) -> anyhow::Result<()> {
+    if !repo.remove(esp_id)? {
+        anyhow::bail!("Device not found: {}", esp_id);
+    }
+    println!("Removed device {}", esp_id);
+    Ok(())
+}
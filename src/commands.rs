@@ -0,0 +1,163 @@
+use std::fmt;
+
+use serde_json::{json, Map, Value};
+
+use crate::{wol, Device};
+
+/// Parameters supplied with a command
+pub type Params = Map<String, Value>;
+
+/// A relay command and how to build its frame.
+///
+/// `build` validates the parameters and returns the fields sent alongside
+/// `"type": name`. Parameters not listed in `params` are rejected before
+/// `build` runs, so builders only check the ones they know.
+pub struct Command {
+    pub name: &'static str,
+    /// Accepted parameter names
+    pub params: &'static [&'static str],
+    build: fn(&Device, &Params) -> Result<Params, String>,
+}
+
+/// Reasons a command can't be built
+#[derive(Debug, PartialEq, Eq)]
+pub enum CommandError {
+    /// No command with that name is registered
+    Unknown(String),
+    /// The parameters failed validation
+    InvalidParams(String),
+    /// The device's `allowed_commands` excludes it
+    NotAllowed(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Unknown(name) => write!(f, "Unknown command '{}'", name),
+            CommandError::InvalidParams(reason) => write!(f, "Invalid params: {}", reason),
+            CommandError::NotAllowed(name) => write!(f, "Command '{}' is not allowed for this device", name),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// Every command the server can send to a relay; add new ones here
+pub static COMMANDS: &[Command] = &[
+    Command { name: "wake", params: &["mac_address", "repeat", "interval_ms"], build: build_wake },
+    Command { name: "reboot", params: &["delay_secs"], build: build_reboot },
+    Command { name: "sleep", params: &["duration_secs"], build: build_sleep },
+    Command { name: "factory_reset", params: &["confirm"], build: build_factory_reset },
+    Command { name: "ota_check", params: &["channel"], build: build_ota_check },
+    Command { name: "ping", params: &[], build: build_bare },
+    Command { name: "status", params: &[], build: build_bare },
+];
+
+/// Look up a registered command by name
+pub fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+/// Validate `params` for `name` and build the text frame for `device`'s relay
+pub fn build(name: &str, device: &Device, params: &Params) -> Result<String, CommandError> {
+    let command = find(name).ok_or_else(|| CommandError::Unknown(name.to_string()))?;
+    // An empty list leaves the device unrestricted
+    if !device.allowed_commands.is_empty() && !device.allowed_commands.iter().any(|allowed| allowed == command.name) {
+        return Err(CommandError::NotAllowed(name.to_string()));
+    }
+    build_frame(command, device, params)
+}
+
+/// Build the `wake` frame the server itself sends when the device is woken.
+///
+/// `allowed_commands` limits what `/command` may send; waking is what the
+/// device is registered for, so it is never excluded here.
+pub fn wake_frame(device: &Device, params: &Params) -> Result<String, CommandError> {
+    let command = find("wake").ok_or_else(|| CommandError::Unknown("wake".to_string()))?;
+    build_frame(command, device, params)
+}
+
+fn build_frame(command: &Command, device: &Device, params: &Params) -> Result<String, CommandError> {
+    let name = command.name;
+    if let Some(unknown) = params.keys().find(|key| !command.params.contains(&key.as_str())) {
+        return Err(CommandError::InvalidParams(format!("'{}' is not accepted by {}", unknown, name)));
+    }
+
+    let mut frame = (command.build)(device, params).map_err(CommandError::InvalidParams)?;
+    frame.insert("type".to_string(), json!(command.name));
+    Ok(Value::Object(frame).to_string())
+}
+
+/// Wake one of the device's MACs, the first unless `mac_address` picks another.
+///
+/// `repeat` and `interval_ms` ask the relay to send that many packets that far apart.
+fn build_wake(device: &Device, params: &Params) -> Result<Params, String> {
+    let mac = match params.get("mac_address") {
+        None => device.primary_mac(),
+        Some(Value::String(requested)) => {
+            let requested = wol::parse_mac(requested).map_err(|e| format!("mac_address: {}", e))?;
+            device
+                .mac_addresses
+                .iter()
+                .find(|mac| wol::parse_mac(mac).is_ok_and(|mac| mac == requested))
+                .ok_or("mac_address is not one of the device's MACs")?
+        },
+        Some(_) => return Err("mac_address must be a string".to_string()),
+    };
+    let mut frame = fields([("mac_address", json!(mac))]);
+    for name in ["repeat", "interval_ms"] {
+        if let Some(value) = params.get(name) {
+            let value = value.as_u64().ok_or_else(|| format!("{} must be a non-negative integer", name))?;
+            frame.insert(name.to_string(), json!(value));
+        }
+    }
+    Ok(frame)
+}
+
+fn build_reboot(_device: &Device, params: &Params) -> Result<Params, String> {
+    Ok(fields([("delay_secs", json!(optional_secs(params, "delay_secs")?.unwrap_or(0)))]))
+}
+
+fn build_sleep(_device: &Device, params: &Params) -> Result<Params, String> {
+    match optional_secs(params, "duration_secs")? {
+        Some(secs) => Ok(fields([("duration_secs", json!(secs))])),
+        None => Ok(Params::new()),
+    }
+}
+
+fn build_factory_reset(_device: &Device, params: &Params) -> Result<Params, String> {
+    // Destructive, so the caller has to say so explicitly
+    match params.get("confirm") {
+        Some(Value::Bool(true)) => Ok(Params::new()),
+        _ => Err("factory_reset requires \"confirm\": true".to_string()),
+    }
+}
+
+fn build_ota_check(_device: &Device, params: &Params) -> Result<Params, String> {
+    match params.get("channel") {
+        None => Ok(Params::new()),
+        Some(Value::String(channel)) if !channel.is_empty() => Ok(fields([("channel", json!(channel))])),
+        Some(_) => Err("channel must be a non-empty string".to_string()),
+    }
+}
+
+/// Commands that are just their type, e.g. `ping` (answered with `ack`) and
+/// `status` (answered with a `status` report)
+fn build_bare(_device: &Device, _params: &Params) -> Result<Params, String> {
+    Ok(Params::new())
+}
+
+/// Read an optional non-negative integer number of seconds
+fn optional_secs(params: &Params, name: &str) -> Result<Option<u64>, String> {
+    match params.get(name) {
+        None => Ok(None),
+        Some(value) => value
+            .as_u64()
+            .map(Some)
+            .ok_or_else(|| format!("{} must be a non-negative integer", name)),
+    }
+}
+
+fn fields<const N: usize>(entries: [(&str, Value); N]) -> Params {
+    entries.into_iter().map(|(key, value)| (key.to_string(), value)).collect()
+}
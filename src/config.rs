@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use ipnet::IpNet;
+
+use crate::allowlist;
+use crate::backup::BackupPolicy;
+use crate::email::EmailSettings;
+use crate::notify::{QuietHours, RetryPolicy};
+
+/// Where log events are written
+#[derive(Debug, Clone)]
+pub enum LogOutput {
+    /// Lines on stdout
+    Stdout,
+    /// Lines appended to a file
+    File(String),
+    /// The local syslog daemon (via /dev/log)
+    Syslog,
+}
+
+/// How stdout and file log lines are formatted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable text
+    Text,
+    /// One JSON object per event, with its fields and enclosing spans
+    Json,
+}
+
+/// Visual style of the web UI banner
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BannerStyle {
+    Info,
+    Warning,
+}
+
+/// Which connected relays `/health` pings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthRelayCheck {
+    Off,
+    /// A randomly chosen relay
+    One,
+    All,
+}
+
+/// What `/wake-by-mac` does when several devices share the requested MAC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacAmbiguity {
+    /// Wake through every matching device's relay
+    WakeAll,
+    /// Wake through the first matching device, by esp_id, with a relay online
+    FirstOnline,
+    /// Refuse and list the candidates
+    Reject,
+}
+
+impl MacAmbiguity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MacAmbiguity::WakeAll => "wake_all",
+            MacAmbiguity::FirstOnline => "first_online",
+            MacAmbiguity::Reject => "reject",
+        }
+    }
+}
+
+/// Server configuration, read from `WOL_*` environment variables
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Log destination (`WOL_LOG_OUTPUT` = stdout | file | syslog)
+    pub log_output: LogOutput,
+    /// Line format for stdout and file output; syslog is always text (`WOL_LOG_FORMAT` = text | json)
+    pub log_format: LogFormat,
+    /// Rotate the log file once it would exceed this many bytes; 0 disables (`WOL_LOG_MAX_BYTES`)
+    pub log_max_bytes: u64,
+    /// Rotated log files kept as `<file>.1` .. `<file>.N` (`WOL_LOG_KEEP`)
+    pub log_keep: usize,
+    /// Syslog facility name, e.g. `daemon` or `local0` (`WOL_SYSLOG_FACILITY`)
+    pub syslog_facility: String,
+    /// TCP port probed to decide whether a machine is up (`WOL_PROBE_PORT`)
+    pub probe_port: u16,
+    /// How long a reachability probe may take (`WOL_PROBE_TIMEOUT_MS`)
+    pub probe_timeout: Duration,
+    /// How long a device `health_url` check may take (`WOL_HEALTH_URL_TIMEOUT_MS`)
+    pub health_url_timeout: Duration,
+    /// Default wait for `/wake-and-wait` (`WOL_WAKE_WAIT_TIMEOUT_SECS`)
+    pub wake_wait_timeout: Duration,
+    /// Emit per-device labeled series on `/metrics` (`WOL_METRICS_DEVICE_LABELS`)
+    pub metrics_device_labels: bool,
+    /// Operator message shown at the top of the web UI (`WOL_BANNER`)
+    pub banner: Option<String>,
+    /// Banner style (`WOL_BANNER_STYLE` = info | warning)
+    pub banner_style: BannerStyle,
+    /// Bearer key for admin endpoints; admin API is disabled when unset (`WOL_ADMIN_KEY`)
+    pub admin_key: Option<String>,
+    /// Token required by the `/schedules.ics` feed; the feed is disabled when unset (`WOL_CALENDAR_TOKEN`)
+    pub calendar_token: Option<String>,
+    /// Secret used to sign wake links; random per process when unset (`WOL_LINK_SECRET`)
+    pub link_secret: Option<String>,
+    /// Default lifetime of issued wake links (`WOL_LINK_TTL_SECS`)
+    pub link_ttl: Duration,
+    /// Load above which non-critical routes return 503; 0 disables shedding (`WOL_SHED_THRESHOLD`)
+    pub shed_threshold: usize,
+    /// Allow `GET /devices?callback=` to answer as JSONP (`WOL_JSONP`)
+    pub jsonp: bool,
+    /// Device file backups, enabled by `WOL_BACKUP_DIR` and capped by `WOL_BACKUP_KEEP`
+    pub backup: Option<BackupPolicy>,
+    /// Webhook endpoints notified of wake and relay events (`WOL_WEBHOOK_URL`, comma-separated)
+    pub webhook_urls: Vec<String>,
+    /// Device tag -> webhook endpoints, loaded from the JSON file at `WOL_WEBHOOK_ROUTES`
+    pub webhook_routes: HashMap<String, Vec<String>>,
+    /// Events sent to webhooks, e.g. `wake,wake_ack`; empty sends all (`WOL_WEBHOOK_EVENTS`)
+    pub webhook_events: Vec<String>,
+    /// Maximum concurrent webhook calls (`WOL_WEBHOOK_CONCURRENCY`)
+    pub webhook_concurrency: usize,
+    /// Pending webhook deliveries kept before the oldest is dropped (`WOL_WEBHOOK_QUEUE`)
+    pub webhook_queue: usize,
+    /// Webhook retries (`WOL_WEBHOOK_RETRIES`), backoff (`WOL_WEBHOOK_RETRY_DELAY_MS`) and
+    /// dead-letter file (`WOL_WEBHOOK_DEAD_LETTER`)
+    pub webhook_retry: RetryPolicy,
+    /// Local-time window holding back wake webhooks (`WOL_QUIET_HOURS` = HH:MM-HH:MM,
+    /// `WOL_QUIET_HOURS_DIGEST` sends a summary afterwards)
+    pub quiet_hours: Option<QuietHours>,
+    /// MQTT broker (`host[:port]`) receiving device status and wake events (`WOL_MQTT_BROKER`)
+    pub mqtt_broker: Option<String>,
+    /// Reject devices whose description another device already uses (`WOL_UNIQUE_DESCRIPTIONS`)
+    pub unique_descriptions: bool,
+    /// Delay coalescing background saves; 0 saves immediately (`WOL_SAVE_DEBOUNCE_MS`)
+    pub save_debounce: Duration,
+    /// Random extra delay added to each debounced save (`WOL_SAVE_JITTER_MS`)
+    pub save_jitter: Duration,
+    /// Relays pinged by `/health` (`WOL_HEALTH_RELAY_CHECK` = off | one | all)
+    pub health_relay_check: HealthRelayCheck,
+    /// How long `/health` waits for relay pongs (`WOL_HEALTH_PING_TIMEOUT_MS`)
+    pub health_ping_timeout: Duration,
+    /// Resolution of MACs shared by several devices (`WOL_MAC_AMBIGUITY` = wake_all | first_online | reject)
+    pub mac_ambiguity: MacAmbiguity,
+    /// Broadcast the magic packet from the server when no relay is connected (`WOL_UDP_FALLBACK`)
+    pub udp_fallback: bool,
+    /// Broadcast address used by the UDP fallback (`WOL_UDP_BROADCAST`)
+    pub udp_broadcast: Ipv4Addr,
+    /// Magic packets sent per MAC for each wake; relays are asked to do the same (`WOL_WAKE_REPEAT`)
+    pub wake_repeat: u32,
+    /// Delay between repeated magic packets (`WOL_WAKE_INTERVAL_MS`)
+    pub wake_interval: Duration,
+    /// Default interval between keep-alive pings to relays; 0 disables (`WOL_KEEPALIVE_SECS`)
+    pub keepalive_interval: Duration,
+    /// Drop relays silent for this long, at least two keep-alive intervals; 0 disables (`WOL_HEARTBEAT_TIMEOUT_SECS`)
+    pub heartbeat_timeout: Option<Duration>,
+    /// Where the `/wake` audit trail is kept (`WOL_HISTORY_FILE`)
+    pub history_file: PathBuf,
+    /// Web UI files: `index.html` plus the assets served under `/static` (`WOL_STATIC_DIR`)
+    pub static_dir: PathBuf,
+    /// Where device groups for `/wake-group` are kept (`WOL_GROUPS_FILE`)
+    pub groups_file: PathBuf,
+    /// Wake attempts kept in the history before the oldest is dropped (`WOL_HISTORY_LIMIT`)
+    pub history_limit: usize,
+    /// Simultaneous relay connections allowed per esp_id (`WOL_MAX_CONNECTIONS_PER_DEVICE`)
+    pub max_connections_per_device: usize,
+    /// Delay between consecutive wakes in a batch (`WOL_WAKE_STAGGER_MS`)
+    pub wake_stagger: Duration,
+    /// After a wake, watch this long for the relay dropping or acking before reporting success;
+    /// 0 disables (`WOL_WAKE_VERIFY_MS`)
+    pub wake_verify: Option<Duration>,
+    /// Window in which a repeated UI click token is ignored; 0 disables (`WOL_WAKE_GRACE_MS`)
+    pub wake_grace: Duration,
+    /// Minimum time between successful `/wake`s of one device; 0 disables (`WOL_WAKE_COOLDOWN_SECS`)
+    pub wake_cooldown: Duration,
+    /// Add a `Server-Timing` header to `/wake` responses (`WOL_SERVER_TIMING`)
+    pub server_timing: bool,
+    /// Store one JSON file per device in this directory instead of `devices.json` (`WOL_DEVICE_DIR`)
+    pub device_dir: Option<PathBuf>,
+    /// Enable test affordances like simulated wake failures (`WOL_DEV_MODE`, needs the `dev-mode` feature)
+    pub dev_mode: bool,
+    /// Remove devices that never connect within this long of registering (`WOL_EXPIRE_UNCONNECTED_SECS`, unset disables)
+    pub expire_unconnected: Option<Duration>,
+    /// Wake-by-email through IMAP polling, enabled by `WOL_IMAP_HOST`
+    pub email: Option<EmailSettings>,
+    /// Extra origins CORS allows on `/ws`, for browser-based relays; `*` allows any (`WOL_WS_ALLOWED_ORIGINS`)
+    pub ws_allowed_origins: Vec<String>,
+    /// Token relays present in a `register` frame to register themselves over `/ws`;
+    /// self-registration is disabled when unset (`WOL_REGISTRATION_TOKEN`)
+    pub registration_token: Option<String>,
+    /// Browser origins allowed to call the API; `*` allows any, empty sends no CORS headers (`WOL_ALLOWED_ORIGINS`)
+    pub allowed_origins: Vec<String>,
+    /// Longest a handler may run before the request gets a 504; 0 disables (`WOL_REQUEST_TIMEOUT_SECS`)
+    pub request_timeout: Duration,
+    /// Client ranges the wake routes accept requests from; empty allows any (`WOL_WAKE_ALLOW_CIDR`)
+    pub wake_allow_cidrs: Vec<IpNet>,
+    /// Take the client address from `X-Forwarded-For` rather than the connection (`WOL_TRUST_PROXY`)
+    pub trust_proxy: bool,
+    /// Secret the device data is encrypted with at rest (`WOL_STORAGE_KEY`)
+    pub storage_key: Option<String>,
+}
+
+impl Config {
+    /// Build the configuration from the environment, falling back to defaults
+    pub fn from_env() -> anyhow::Result<Self> {
+        let log_output = match env_or("WOL_LOG_OUTPUT", "stdout").to_lowercase().as_str() {
+            "stdout" => LogOutput::Stdout,
+            "file" => LogOutput::File(env_or("WOL_LOG_FILE", "wol-server.log")),
+            "syslog" => LogOutput::Syslog,
+            other => anyhow::bail!("Unknown WOL_LOG_OUTPUT '{}', expected stdout, file or syslog", other),
+        };
+
+        let log_format = match env_or("WOL_LOG_FORMAT", "text").to_lowercase().as_str() {
+            "text" => LogFormat::Text,
+            "json" => LogFormat::Json,
+            other => anyhow::bail!("Unknown WOL_LOG_FORMAT '{}', expected text or json", other),
+        };
+
+        let banner_style = match env_or("WOL_BANNER_STYLE", "info").to_lowercase().as_str() {
+            "info" => BannerStyle::Info,
+            "warning" => BannerStyle::Warning,
+            other => anyhow::bail!("Unknown WOL_BANNER_STYLE '{}', expected info or warning", other),
+        };
+
+        let health_relay_check = match env_or("WOL_HEALTH_RELAY_CHECK", "off").to_lowercase().as_str() {
+            "off" => HealthRelayCheck::Off,
+            "one" => HealthRelayCheck::One,
+            "all" => HealthRelayCheck::All,
+            other => anyhow::bail!("Unknown WOL_HEALTH_RELAY_CHECK '{}', expected off, one or all", other),
+        };
+
+        let mac_ambiguity = match env_or("WOL_MAC_AMBIGUITY", "reject").to_lowercase().as_str() {
+            "wake_all" => MacAmbiguity::WakeAll,
+            "first_online" => MacAmbiguity::FirstOnline,
+            "reject" => MacAmbiguity::Reject,
+            other => anyhow::bail!("Unknown WOL_MAC_AMBIGUITY '{}', expected wake_all, first_online or reject", other),
+        };
+
+        let dev_mode = env_flag("WOL_DEV_MODE", false)?;
+        if dev_mode && !cfg!(feature = "dev-mode") {
+            anyhow::bail!("WOL_DEV_MODE is only available in builds with the dev-mode feature");
+        }
+
+        let email = match env_opt("WOL_IMAP_HOST") {
+            Some(imap_host) => Some(EmailSettings {
+                imap_host,
+                imap_port: env_parse("WOL_IMAP_PORT", 993)?,
+                user: env_opt("WOL_IMAP_USER")
+                    .ok_or_else(|| anyhow::anyhow!("WOL_IMAP_USER is required with WOL_IMAP_HOST"))?,
+                password: env_opt("WOL_IMAP_PASSWORD")
+                    .ok_or_else(|| anyhow::anyhow!("WOL_IMAP_PASSWORD is required with WOL_IMAP_HOST"))?,
+                mailbox: env_or("WOL_IMAP_MAILBOX", "INBOX"),
+                poll_interval: Duration::from_secs(env_parse("WOL_IMAP_POLL_SECS", 60)?.max(1)),
+                allowed_senders: env_list("WOL_IMAP_ALLOWED_SENDERS"),
+                smtp_host: env_opt("WOL_SMTP_HOST"),
+                smtp_port: env_parse("WOL_SMTP_PORT", 465)?,
+            }),
+            None => None,
+        };
+
+        Ok(Self {
+            log_output,
+            log_format,
+            log_max_bytes: env_parse("WOL_LOG_MAX_BYTES", 0)?,
+            log_keep: env_parse("WOL_LOG_KEEP", 5)?,
+            syslog_facility: env_or("WOL_SYSLOG_FACILITY", "daemon"),
+            probe_port: env_parse("WOL_PROBE_PORT", 22)?,
+            probe_timeout: Duration::from_millis(env_parse("WOL_PROBE_TIMEOUT_MS", 1000)?),
+            health_url_timeout: Duration::from_millis(env_parse("WOL_HEALTH_URL_TIMEOUT_MS", 2000)?),
+            wake_wait_timeout: Duration::from_secs(env_parse("WOL_WAKE_WAIT_TIMEOUT_SECS", 120)?),
+            metrics_device_labels: env_flag("WOL_METRICS_DEVICE_LABELS", true)?,
+            banner: env_opt("WOL_BANNER"),
+            banner_style,
+            admin_key: env_opt("WOL_ADMIN_KEY"),
+            calendar_token: env_opt("WOL_CALENDAR_TOKEN"),
+            link_secret: env_opt("WOL_LINK_SECRET"),
+            link_ttl: Duration::from_secs(env_parse("WOL_LINK_TTL_SECS", 86400)?),
+            shed_threshold: env_parse("WOL_SHED_THRESHOLD", 0)?,
+            jsonp: env_flag("WOL_JSONP", false)?,
+            backup: match env_opt("WOL_BACKUP_DIR") {
+                Some(dir) => Some(BackupPolicy {
+                    dir: PathBuf::from(dir),
+                    keep: env_parse("WOL_BACKUP_KEEP", 10)?,
+                }),
+                None => None,
+            },
+            webhook_urls: env_list("WOL_WEBHOOK_URL"),
+            webhook_routes: match env_opt("WOL_WEBHOOK_ROUTES") {
+                Some(path) => load_webhook_routes(&path)?,
+                None => HashMap::new(),
+            },
+            webhook_events: env_list("WOL_WEBHOOK_EVENTS"),
+            quiet_hours: match env_opt("WOL_QUIET_HOURS") {
+                Some(spec) => Some(
+                    QuietHours::parse(&spec, env_flag("WOL_QUIET_HOURS_DIGEST", false)?)
+                        .map_err(|e| anyhow::anyhow!("Invalid WOL_QUIET_HOURS '{}': {}", spec, e))?,
+                ),
+                None => None,
+            },
+            webhook_concurrency: env_parse("WOL_WEBHOOK_CONCURRENCY", 4)?,
+            webhook_queue: env_parse("WOL_WEBHOOK_QUEUE", 100)?,
+            webhook_retry: RetryPolicy {
+                retries: env_parse("WOL_WEBHOOK_RETRIES", 3)?,
+                base_delay: Duration::from_millis(env_parse("WOL_WEBHOOK_RETRY_DELAY_MS", 1000)?),
+                dead_letter: PathBuf::from(env_or("WOL_WEBHOOK_DEAD_LETTER", "webhook-dead-letters.jsonl")),
+            },
+            mqtt_broker: env_opt("WOL_MQTT_BROKER"),
+            unique_descriptions: env_flag("WOL_UNIQUE_DESCRIPTIONS", false)?,
+            save_debounce: Duration::from_millis(env_parse("WOL_SAVE_DEBOUNCE_MS", 0)?),
+            save_jitter: Duration::from_millis(env_parse("WOL_SAVE_JITTER_MS", 0)?),
+            health_relay_check,
+            health_ping_timeout: Duration::from_millis(env_parse("WOL_HEALTH_PING_TIMEOUT_MS", 2000)?),
+            mac_ambiguity,
+            udp_fallback: env_flag("WOL_UDP_FALLBACK", false)?,
+            udp_broadcast: env_parse("WOL_UDP_BROADCAST", Ipv4Addr::BROADCAST)?,
+            wake_repeat: env_parse("WOL_WAKE_REPEAT", 3)?,
+            wake_interval: Duration::from_millis(env_parse("WOL_WAKE_INTERVAL_MS", 100)?),
+            keepalive_interval: Duration::from_secs(env_parse("WOL_KEEPALIVE_SECS", 15)?),
+            heartbeat_timeout: match env_parse("WOL_HEARTBEAT_TIMEOUT_SECS", 40)? {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            history_file: PathBuf::from(env_or("WOL_HISTORY_FILE", "wake_history.json")),
+            static_dir: PathBuf::from(env_or("WOL_STATIC_DIR", "static")),
+            groups_file: PathBuf::from(env_or("WOL_GROUPS_FILE", "groups.json")),
+            history_limit: env_parse("WOL_HISTORY_LIMIT", 1000)?,
+            max_connections_per_device: env_parse("WOL_MAX_CONNECTIONS_PER_DEVICE", 1)?,
+            wake_stagger: Duration::from_millis(env_parse("WOL_WAKE_STAGGER_MS", 0)?),
+            server_timing: env_flag("WOL_SERVER_TIMING", false)?,
+            wake_grace: Duration::from_millis(env_parse("WOL_WAKE_GRACE_MS", 2000)?),
+            wake_cooldown: Duration::from_secs(env_parse("WOL_WAKE_COOLDOWN_SECS", 5)?),
+            wake_verify: match env_parse("WOL_WAKE_VERIFY_MS", 0)? {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            device_dir: env_opt("WOL_DEVICE_DIR").map(PathBuf::from),
+            dev_mode,
+            email,
+            ws_allowed_origins: env_list_or("WOL_WS_ALLOWED_ORIGINS", "*"),
+            allowed_origins: env_list("WOL_ALLOWED_ORIGINS"),
+            registration_token: env_opt("WOL_REGISTRATION_TOKEN"),
+            request_timeout: Duration::from_secs(env_parse("WOL_REQUEST_TIMEOUT_SECS", 30)?),
+            wake_allow_cidrs: env_list("WOL_WAKE_ALLOW_CIDR")
+                .iter()
+                .map(|spec| allowlist::parse_cidr(spec))
+                .collect::<Result<_, _>>()
+                .map_err(|e| anyhow::anyhow!("Invalid WOL_WAKE_ALLOW_CIDR: {}", e))?,
+            trust_proxy: env_flag("WOL_TRUST_PROXY", false)?,
+            storage_key: env_opt("WOL_STORAGE_KEY"),
+            expire_unconnected: match env_parse("WOL_EXPIRE_UNCONNECTED_SECS", 0)? {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+        })
+    }
+}
+
+/// Read a `{"tag": ["https://...", ...]}` routing file
+fn load_webhook_routes(path: &str) -> anyhow::Result<HashMap<String, Vec<String>>> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read WOL_WEBHOOK_ROUTES file '{}': {}", path, e))?;
+    serde_json::from_str(&content)
+        .map_err(|e| anyhow::anyhow!("Invalid WOL_WEBHOOK_ROUTES file '{}': {}", path, e))
+}
+
+/// Read an environment variable, using `default` when it is unset or empty
+fn env_or(name: &str, default: &str) -> String {
+    match env::var(name) {
+        Ok(value) if !value.trim().is_empty() => value.trim().to_string(),
+        _ => default.to_string(),
+    }
+}
+
+/// Read an optional environment variable, treating empty values as unset
+fn env_opt(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.trim().is_empty())
+}
+
+/// Read a comma-separated list, skipping empty entries
+fn env_list(name: &str) -> Vec<String> {
+    env_list_or(name, "")
+}
+
+/// Read a comma-separated list, using `default` when the variable is unset or empty
+fn env_list_or(name: &str, default: &str) -> Vec<String> {
+    env_or(name, default)
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Parse an environment variable, using `default` when it is unset or empty
+fn env_parse<T: FromStr>(name: &str, default: T) -> anyhow::Result<T> {
+    match env::var(name) {
+        Ok(value) if !value.trim().is_empty() => value
+            .trim()
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid value '{}' for {}", value, name)),
+        _ => Ok(default),
+    }
+}
+
+/// Read a boolean flag such as `1`/`0`, `true`/`false` or `yes`/`no`
+fn env_flag(name: &str, default: bool) -> anyhow::Result<bool> {
+    match env_or(name, "").to_lowercase().as_str() {
+        "" => Ok(default),
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        other => anyhow::bail!("Invalid value '{}' for {}, expected true or false", other, name),
+    }
+}
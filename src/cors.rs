@@ -0,0 +1,34 @@
+use actix_cors::Cors;
+use actix_web::http::{header, Method};
+
+/// How long browsers may cache a preflight answer
+const PREFLIGHT_MAX_AGE_SECS: usize = 600;
+
+/// The relay WebSocket, which also admits `WOL_WS_ALLOWED_ORIGINS`
+const WS_PATH: &str = "/ws";
+
+/// CORS for every route, from `WOL_ALLOWED_ORIGINS`; `*` allows any origin.
+/// `/ws` additionally allows `ws_origins` (`WOL_WS_ALLOWED_ORIGINS`), for
+/// browser-based relays served from elsewhere.
+///
+/// Requests from other origins are still served, just without the headers a
+/// browser needs to let the page read the response.
+pub fn middleware(origins: &[String], ws_origins: &[String]) -> Cors {
+    let cors = if origins.iter().any(|origin| origin == "*") {
+        Cors::default().allow_any_origin().send_wildcard()
+    } else {
+        let ws_any = ws_origins.iter().any(|origin| origin == "*");
+        let ws_origins = ws_origins.to_vec();
+        origins
+            .iter()
+            .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+            .allowed_origin_fn(move |origin, head| {
+                head.uri.path() == WS_PATH && (ws_any || ws_origins.iter().any(|allowed| origin == allowed.as_str()))
+            })
+    };
+    cors.allowed_methods([Method::GET, Method::POST, Method::PATCH, Method::DELETE])
+        .allowed_headers([header::AUTHORIZATION, header::CONTENT_TYPE, header::SEC_WEBSOCKET_PROTOCOL])
+        // Lets clients back off after a 429
+        .expose_headers([header::RETRY_AFTER])
+        .max_age(PREFLIGHT_MAX_AGE_SECS)
+}
@@ -0,0 +1,104 @@
+use std::fmt;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rand::RngCore;
+use sha2::Sha256;
+
+/// Prefix marking an encrypted device file; the rest is `base64(nonce || ciphertext)`
+const SEALED_PREFIX: &str = "WOLENC1:";
+
+/// Context string binding derived keys to this use
+const KEY_INFO: &[u8] = b"wol-server device storage v1";
+
+const NONCE_LEN: usize = 12;
+
+/// Why stored data couldn't be read
+#[derive(Debug, PartialEq, Eq)]
+pub enum StorageError {
+    /// The file is encrypted but no `WOL_STORAGE_KEY` is set
+    MissingKey,
+    /// Not valid encrypted data, or the key is wrong
+    Undecryptable,
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::MissingKey => write!(f, "device data is encrypted but WOL_STORAGE_KEY is not set"),
+            StorageError::Undecryptable => write!(f, "device data could not be decrypted, check WOL_STORAGE_KEY"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+/// ChaCha20-Poly1305 encryption for device data at rest.
+///
+/// The cipher key is derived from `WOL_STORAGE_KEY` with HKDF-SHA256, so the
+/// configured key should be a long random secret rather than a short password.
+#[derive(Clone)]
+pub struct StorageCipher {
+    cipher: ChaCha20Poly1305,
+}
+
+impl StorageCipher {
+    pub fn new(secret: &str) -> Self {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, secret.as_bytes())
+            .expand(KEY_INFO, &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Self { cipher: ChaCha20Poly1305::new(Key::from_slice(&key)) }
+    }
+
+    fn seal(&self, plaintext: &str) -> String {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::rng().fill_bytes(&mut nonce);
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes())
+            .expect("ChaCha20-Poly1305 encryption doesn't fail for in-memory data");
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        format!("{}{}", SEALED_PREFIX, STANDARD.encode(sealed))
+    }
+
+    fn open(&self, sealed: &str) -> Result<String, StorageError> {
+        let data = STANDARD.decode(sealed.trim()).map_err(|_| StorageError::Undecryptable)?;
+        if data.len() < NONCE_LEN {
+            return Err(StorageError::Undecryptable);
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| StorageError::Undecryptable)?;
+        String::from_utf8(plaintext).map_err(|_| StorageError::Undecryptable)
+    }
+}
+
+/// Whether file contents are encrypted
+pub fn is_sealed(content: &str) -> bool {
+    content.starts_with(SEALED_PREFIX)
+}
+
+/// Turn file contents into JSON text, decrypting when the file is sealed
+pub fn decode(content: &str, cipher: Option<&StorageCipher>) -> Result<String, StorageError> {
+    match content.strip_prefix(SEALED_PREFIX) {
+        Some(sealed) => cipher.ok_or(StorageError::MissingKey)?.open(sealed),
+        // Plaintext files are still read so enabling encryption migrates them on the next save
+        None => Ok(content.to_string()),
+    }
+}
+
+/// Turn JSON text into file contents, encrypting when a key is configured
+pub fn encode(json: String, cipher: Option<&StorageCipher>) -> String {
+    match cipher {
+        Some(cipher) => cipher.seal(&json),
+        None => json,
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use tracing::{info, warn};
+
+use crate::backup::BackupPolicy;
+use crate::crypto::{self, StorageCipher};
+use crate::locks::MutexExt;
+use crate::repository::DeviceRepository;
+use crate::{atomic_file, schema, Device};
+
+/// Device storage as a directory holding one `*.json` file per device.
+///
+/// Files may be named freely; the esp_id inside each one is authoritative and
+/// the file it was loaded from is remembered so changes are written back in
+/// place. Devices added at runtime get `<esp_id>.json`.
+pub struct DeviceDir {
+    dir: PathBuf,
+    /// esp_id -> file holding that device
+    files: Mutex<HashMap<String, PathBuf>>,
+    /// Backup rotation applied before each file is changed
+    backup: Option<BackupPolicy>,
+    /// Encrypts each file when `WOL_STORAGE_KEY` is set
+    cipher: Option<StorageCipher>,
+}
+
+impl DeviceDir {
+    /// Open `dir`, creating it if needed and rewriting files saved in an older format
+    pub fn open(dir: &Path, backup: Option<BackupPolicy>, cipher: Option<StorageCipher>) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let device_dir = Self { dir: dir.to_path_buf(), files: Mutex::new(HashMap::new()), backup, cipher };
+
+        let devices = device_dir.load()?;
+        info!("[Storage] Loaded {} device file(s) from {}", devices.len(), dir.display());
+        // Only files whose contents change are rewritten, which upgrades older ones in place
+        if let Err(e) = device_dir.save_all(&devices) {
+            warn!("[Storage] Failed to rewrite device files in the current format: {}", e);
+        }
+        Ok(device_dir)
+    }
+
+    /// Read every device file, remembering which file each device came from
+    fn load(&self) -> io::Result<HashMap<String, Device>> {
+        let mut paths = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "json"))
+            .collect::<Vec<_>>();
+        paths.sort();
+
+        let mut devices = HashMap::new();
+        let mut files: HashMap<String, PathBuf> = HashMap::new();
+        for path in paths {
+            let device = match self.read_device(&path)? {
+                Ok(device) => device,
+                Err(reason) => {
+                    warn!("[Storage] Skipping device file {}: {}", path.display(), reason);
+                    continue;
+                },
+            };
+            if let Some(first) = files.get(&device.esp_id) {
+                warn!(
+                    "[Storage] Skipping {}: esp_id {} is already defined in {}",
+                    path.display(), device.esp_id, first.display()
+                );
+                continue;
+            }
+            files.insert(device.esp_id.clone(), path);
+            devices.insert(device.esp_id.clone(), device);
+        }
+
+        *self.files.lock_or_recover() = files;
+        Ok(devices)
+    }
+
+    /// Read one device file. A file that can't be read or parsed gives the reason
+    /// to skip it, while a decryption failure is an error: a key problem affects
+    /// every file, so the directory is refused rather than skipped file by file.
+    fn read_device(&self, path: &Path) -> io::Result<Result<Device, String>> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) => return Ok(Err(format!("unreadable: {}", e))),
+        };
+        let json = crypto::decode(&content, self.cipher.as_ref())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+        let parsed = serde_json::from_str(&json).map_err(|e| e.to_string());
+        Ok(parsed.and_then(|value| schema::parse_device(value, None)).map_err(|e| format!("invalid: {}", e)))
+    }
+
+    /// Write `device` to its file unless the file already holds exactly that
+    fn write_device(&self, files: &mut HashMap<String, PathBuf>, device: &Device) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(device)?;
+        let path = match files.get(&device.esp_id) {
+            Some(path) => path.clone(),
+            None => {
+                let path = self.new_file_path(&device.esp_id, files);
+                files.insert(device.esp_id.clone(), path.clone());
+                path
+            },
+        };
+
+        // Leave untouched files alone so unchanged devices don't show up as diffs
+        let current = fs::read_to_string(&path).ok();
+        let unchanged = current.as_deref().is_some_and(|current| {
+            // Sealed files must also stay sealed, or a newly set key wouldn't migrate them
+            crypto::is_sealed(current) == self.cipher.is_some()
+                && crypto::decode(current, self.cipher.as_ref()).is_ok_and(|current| current == json)
+        });
+        if unchanged {
+            return Ok(());
+        }
+        back_up(self.backup.as_ref(), &path);
+        atomic_file::write(&path, crypto::encode(json, self.cipher.as_ref()))
+    }
+
+    /// Delete the file holding `esp_id`, returning whether there was one
+    fn remove_device(&self, files: &mut HashMap<String, PathBuf>, esp_id: &str) -> io::Result<bool> {
+        let Some(path) = files.remove(esp_id) else {
+            return Ok(false);
+        };
+        back_up(self.backup.as_ref(), &path);
+        match fs::remove_file(&path) {
+            Ok(()) => info!("[Storage] Removed device file {}", path.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+        Ok(true)
+    }
+
+    /// Pick an unused `<esp_id>.json` name for a new device
+    fn new_file_path(&self, esp_id: &str, files: &HashMap<String, PathBuf>) -> PathBuf {
+        let stem = esp_id
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' })
+            .collect::<String>();
+        let stem = if stem.trim_matches('.').is_empty() { "device".to_string() } else { stem };
+
+        let taken = files.values().collect::<HashSet<_>>();
+        let mut path = self.dir.join(format!("{}.json", stem));
+        let mut suffix = 2;
+        while taken.contains(&path) || path.exists() {
+            path = self.dir.join(format!("{}-{}.json", stem, suffix));
+            suffix += 1;
+        }
+        path
+    }
+}
+
+impl DeviceRepository for DeviceDir {
+    fn list(&self) -> io::Result<HashMap<String, Device>> {
+        self.load()
+    }
+
+    /// Reads only the file remembered for `esp_id`, so devices whose files were
+    /// added since the directory was last listed aren't found
+    fn get(&self, esp_id: &str) -> io::Result<Option<Device>> {
+        let path = self.files.lock_or_recover().get(esp_id).cloned();
+        let Some(path) = path else {
+            return Ok(None);
+        };
+        match self.read_device(&path)? {
+            Ok(device) => Ok(Some(device).filter(|device| device.esp_id == esp_id)),
+            Err(reason) => {
+                warn!("[Storage] Ignoring device file {}: {}", path.display(), reason);
+                Ok(None)
+            },
+        }
+    }
+
+    fn insert(&self, device: &Device) -> io::Result<()> {
+        self.write_device(&mut self.files.lock_or_recover(), device)
+    }
+
+    fn remove(&self, esp_id: &str) -> io::Result<bool> {
+        self.remove_device(&mut self.files.lock_or_recover(), esp_id)
+    }
+
+    /// Write changed devices to their files and delete files of removed devices
+    fn save_all(&self, devices: &HashMap<String, Device>) -> io::Result<()> {
+        let mut files = self.files.lock_or_recover();
+
+        let removed = files
+            .keys()
+            .filter(|esp_id| !devices.contains_key(*esp_id))
+            .cloned()
+            .collect::<Vec<_>>();
+        for esp_id in removed {
+            self.remove_device(&mut files, &esp_id)?;
+        }
+
+        let mut esp_ids = devices.keys().collect::<Vec<_>>();
+        esp_ids.sort();
+        for esp_id in esp_ids {
+            self.write_device(&mut files, &devices[esp_id])?;
+        }
+        Ok(())
+    }
+}
+
+fn back_up(backup: Option<&BackupPolicy>, path: &Path) {
+    if let Some(backup) = backup {
+        if let Err(e) = backup.rotate(path) {
+            warn!("[Backup] Failed to back up device file {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_reads_only_the_device_file() {
+        let dir = std::env::temp_dir().join(format!("wol-server-test-{}-device-dir", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let devices = schema::parse_devices(include_str!("../tests/fixtures/devices_v1.json")).unwrap();
+        for device in devices.values() {
+            fs::write(dir.join(format!("{}.json", device.esp_id)), serde_json::to_string(device).unwrap()).unwrap();
+        }
+        let device_dir = DeviceDir::open(&dir, None, None).unwrap();
+
+        // A broken neighbour doesn't matter when only one file is read
+        fs::write(dir.join("nas.json"), "not json").unwrap();
+        assert_eq!(device_dir.get("desk").unwrap().unwrap().description, "Desk PC");
+        assert!(device_dir.get("nas").unwrap().is_none());
+        assert!(device_dir.get("missing").unwrap().is_none());
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,220 @@
+use std::thread;
+use std::time::Duration;
+
+use actix_web::web;
+use lettre::message::header::ContentType;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use mailparse::{MailHeaderMap, ParsedMail};
+use tracing::{error, info, warn};
+
+use crate::config::Config;
+use crate::history::{WakeHistory, WakeSource};
+use crate::locks::RwLockExt;
+use crate::metrics::Metrics;
+use crate::notify::Notifier;
+use crate::{dispatch_wake, DeviceStore};
+
+/// Mailbox polled for wake requests and the SMTP server replies go through
+#[derive(Debug, Clone)]
+pub struct EmailSettings {
+    pub imap_host: String,
+    pub imap_port: u16,
+    pub user: String,
+    pub password: String,
+    pub mailbox: String,
+    pub poll_interval: Duration,
+    /// Sender addresses whose requests are honored; compared case-insensitively
+    pub allowed_senders: Vec<String>,
+    /// SMTP relay for result replies; no replies are sent when unset
+    pub smtp_host: Option<String>,
+    pub smtp_port: u16,
+}
+
+/// A wake request parsed out of an email
+struct WakeMail {
+    sender: String,
+    esp_id: String,
+    password: String,
+    subject: String,
+    message_id: Option<String>,
+}
+
+/// Start the IMAP polling thread.
+///
+/// Unread messages from allowlisted senders with a `WAKE <esp_id>` subject and
+/// the device password as the first line of the body trigger a wake. Fetching
+/// marks messages as read, so each one is handled once.
+pub fn start(
+    settings: EmailSettings,
+    store: web::Data<DeviceStore>,
+    metrics: web::Data<Metrics>,
+    notifier: web::Data<Notifier>,
+    config: web::Data<Config>,
+    history: web::Data<WakeHistory>,
+) {
+    info!(
+        "[Email] Polling {}@{}:{}/{} every {:?}",
+        settings.user, settings.imap_host, settings.imap_port, settings.mailbox, settings.poll_interval
+    );
+    if settings.allowed_senders.is_empty() {
+        warn!("[Email] WOL_IMAP_ALLOWED_SENDERS is empty, every wake email will be ignored");
+    }
+
+    thread::spawn(move || {
+        // Wakes are async (verification waits on the relay), so the poller drives its own runtime
+        let runtime = match tokio::runtime::Builder::new_current_thread().enable_time().build() {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                error!("[Email] Failed to start mail poller: {}", e);
+                return;
+            },
+        };
+        loop {
+            match fetch_unread(&settings) {
+                Ok(messages) => {
+                    for raw in messages {
+                        runtime.block_on(handle_message(&settings, &store, &metrics, &notifier, &config, &history, &raw));
+                    }
+                },
+                Err(e) => error!("[Email] Mailbox poll failed: {}", e),
+            }
+            thread::sleep(settings.poll_interval);
+        }
+    });
+}
+
+/// Fetch the raw contents of every unread message, marking them read
+fn fetch_unread(settings: &EmailSettings) -> imap::Result<Vec<Vec<u8>>> {
+    let client = imap::ClientBuilder::new(settings.imap_host.as_str(), settings.imap_port).connect()?;
+    let mut session = client.login(&settings.user, &settings.password).map_err(|e| e.0)?;
+    session.select(&settings.mailbox)?;
+
+    let uids = session.uid_search("UNSEEN")?;
+    let mut messages = Vec::with_capacity(uids.len());
+    if !uids.is_empty() {
+        let set = uids.iter().map(u32::to_string).collect::<Vec<_>>().join(",");
+        // RFC822 (unlike BODY.PEEK) sets \Seen
+        for fetch in session.uid_fetch(set, "RFC822")?.iter() {
+            if let Some(body) = fetch.body() {
+                messages.push(body.to_vec());
+            }
+        }
+    }
+
+    session.logout()?;
+    Ok(messages)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_message(
+    settings: &EmailSettings,
+    store: &DeviceStore,
+    metrics: &Metrics,
+    notifier: &Notifier,
+    config: &Config,
+    history: &WakeHistory,
+    raw: &[u8],
+) {
+    let mail = match parse_wake_mail(raw) {
+        Some(mail) => mail,
+        None => {
+            warn!("[Email] Ignoring message that isn't a wake request");
+            return;
+        },
+    };
+    if !settings.allowed_senders.iter().any(|allowed| allowed.eq_ignore_ascii_case(&mail.sender)) {
+        // No reply: answering arbitrary senders would turn us into a backscatter source
+        warn!("[Email] Ignoring wake request from unlisted sender: {}", mail.sender);
+        return;
+    }
+    info!("[Email] Wake request from {}: ID={}", mail.sender, mail.esp_id);
+
+    let device = {
+        let devices = store.devices.read_or_recover();
+        devices.get(&mail.esp_id).cloned()
+    };
+    let source = WakeSource { via: "email", source_ip: None };
+    let result = match device {
+        None => {
+            warn!("[Email] Device not found: ID={}", mail.esp_id);
+            history.record_attempt(&source, &mail.esp_id, "not_found", None);
+            "Device not found".to_string()
+        },
+        Some(device) if !store.check_password(&device, &mail.password) => {
+            warn!("[Email] Password verification failed: ID={}", mail.esp_id);
+            history.record_attempt(&source, &mail.esp_id, "unauthorized", None);
+            "Incorrect password".to_string()
+        },
+        Some(device) => {
+            let outcome = dispatch_wake(store, metrics, notifier, config, &device).await;
+            history.record_attempt(&source, &device.esp_id, outcome.as_str(), None);
+            format!("Wake result: {}", outcome.as_str())
+        },
+    };
+
+    if let Err(e) = send_reply(settings, &mail, &result) {
+        error!("[Email] Failed to reply to {}: {}", mail.sender, e);
+    }
+}
+
+/// Extract sender, target and password; None if the subject isn't `WAKE <esp_id>`
+fn parse_wake_mail(raw: &[u8]) -> Option<WakeMail> {
+    let parsed = mailparse::parse_mail(raw).ok()?;
+    let headers = parsed.get_headers();
+
+    let subject = headers.get_first_value("Subject")?;
+    let (command, esp_id) = subject.trim().split_once(char::is_whitespace)?;
+    if !command.eq_ignore_ascii_case("WAKE") || esp_id.trim().is_empty() {
+        return None;
+    }
+
+    let sender = mailparse::addrparse_header(headers.get_first_header("From")?)
+        .ok()?
+        .extract_single_info()?
+        .addr;
+    let password = plain_text(&parsed)?
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())?
+        .to_string();
+
+    Some(WakeMail {
+        sender,
+        esp_id: esp_id.trim().to_string(),
+        password,
+        subject: subject.trim().to_string(),
+        message_id: headers.get_first_value("Message-ID"),
+    })
+}
+
+/// The first text/plain part of a message
+fn plain_text(mail: &ParsedMail) -> Option<String> {
+    if mail.subparts.is_empty() {
+        return (mail.ctype.mimetype == "text/plain").then(|| mail.get_body().ok()).flatten();
+    }
+    mail.subparts.iter().find_map(plain_text)
+}
+
+fn send_reply(settings: &EmailSettings, mail: &WakeMail, result: &str) -> anyhow::Result<()> {
+    let Some(smtp_host) = &settings.smtp_host else {
+        return Ok(());
+    };
+
+    let mut builder = Message::builder()
+        .from(settings.user.parse()?)
+        .to(mail.sender.parse()?)
+        .subject(format!("Re: {}", mail.subject))
+        .header(ContentType::TEXT_PLAIN);
+    if let Some(message_id) = &mail.message_id {
+        builder = builder.in_reply_to(message_id.clone()).references(message_id.clone());
+    }
+    let reply = builder.body(format!("{}\n", result))?;
+
+    let mailer = SmtpTransport::relay(smtp_host)?
+        .port(settings.smtp_port)
+        .credentials(Credentials::new(settings.user.clone(), settings.password.clone()))
+        .build();
+    mailer.send(&reply)?;
+    Ok(())
+}
@@ -0,0 +1,174 @@
+use std::fmt;
+use std::time::Duration;
+
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::error::JsonPayloadError;
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use serde_json::{json, Value};
+
+/// A failed API request, answered as `{"error": "<code>", "message": "<text>"}`.
+///
+/// `error` is a stable machine-readable code; `message` is for people and may change.
+#[derive(Debug)]
+pub enum ApiError {
+    /// The request itself is malformed or out of range
+    BadRequest(String),
+    /// The JSON body couldn't be read into the request type; `field` and
+    /// `expected` are filled in when serde names them
+    InvalidJson {
+        message: String,
+        field: Option<String>,
+        expected: Option<String>,
+        line: usize,
+        column: usize,
+    },
+    /// Wrong or missing device password
+    Unauthorized,
+    /// Wrong or missing key or token, such as the admin key; the message names it
+    InvalidToken(String),
+    /// The request is valid but not permitted for this device or server
+    Forbidden(String),
+    /// No device (or other resource) matches the request
+    NotFound(String),
+    /// The request clashes with existing state
+    Conflict(String),
+    /// The resource existed but can no longer be used, such as a redeemed wake link
+    Gone(String),
+    /// No relay is connected for the device
+    Offline,
+    /// The device was woken too recently; it can be tried again after the given time
+    RateLimited(Duration),
+    /// A relay took the request but didn't carry it out; `code` says how
+    BadGateway { code: &'static str, message: String },
+    /// Something failed on the server's side
+    Internal(String),
+    /// The handler didn't answer within `WOL_REQUEST_TIMEOUT_SECS`
+    TimedOut,
+    /// The server can't provide this right now or on this platform
+    Unavailable(String),
+}
+
+impl ApiError {
+    /// The device named in the request isn't registered
+    pub fn device_not_found() -> Self {
+        ApiError::NotFound("Device not found".to_string())
+    }
+
+    /// Machine-readable code reported in the `error` field
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "invalid_request",
+            ApiError::InvalidJson { .. } => "invalid_json",
+            ApiError::Unauthorized | ApiError::InvalidToken(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::Gone(_) => "gone",
+            ApiError::Offline => "device_offline",
+            ApiError::RateLimited(_) => "rate_limited",
+            ApiError::BadGateway { code, .. } => code,
+            ApiError::Internal(_) => "internal_error",
+            ApiError::TimedOut => "timeout",
+            ApiError::Unavailable(_) => "unavailable",
+        }
+    }
+
+    /// The error's response with the fields of `extra` added to its body, for
+    /// errors that come with more than a message, like the choices available
+    pub fn response_with(&self, extra: Value) -> HttpResponse {
+        let mut body = json!({
+            "error": self.code(),
+            "message": self.to_string(),
+        });
+        let mut response = HttpResponse::build(self.status_code());
+        if let ApiError::RateLimited(wait) = self {
+            // Whole seconds, rounded up so retrying at that point succeeds
+            let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            body["retry_after"] = json!(retry_after);
+            response.insert_header((RETRY_AFTER, retry_after.to_string()));
+        }
+        if let ApiError::InvalidJson { field, expected, line, column, .. } = self {
+            body["field"] = json!(field);
+            body["expected"] = json!(expected);
+            body["line"] = json!(line);
+            body["column"] = json!(column);
+        }
+        if let (Value::Object(body), Value::Object(extra)) = (&mut body, extra) {
+            body.extend(extra);
+        }
+        response.json(body)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Unauthorized => write!(f, "Incorrect password"),
+            ApiError::Offline => write!(f, "Device offline"),
+            ApiError::TimedOut => write!(f, "Request timed out"),
+            ApiError::RateLimited(_) => write!(f, "Device was woken recently, try again later"),
+            ApiError::BadRequest(message)
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::InvalidToken(message)
+            | ApiError::Conflict(message)
+            | ApiError::Gone(message)
+            | ApiError::Unavailable(message)
+            | ApiError::InvalidJson { message, .. }
+            | ApiError::BadGateway { message, .. }
+            | ApiError::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) | ApiError::InvalidJson { .. } => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized | ApiError::InvalidToken(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            // Kept at 404 as before so existing clients treating it as "not reachable" still work
+            ApiError::NotFound(_) | ApiError::Offline => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Gone(_) => StatusCode::GONE,
+            ApiError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::BadGateway { .. } => StatusCode::BAD_GATEWAY,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::TimedOut => StatusCode::GATEWAY_TIMEOUT,
+            ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        self.response_with(Value::Null)
+    }
+}
+
+/// `web::JsonConfig` error handler answering unreadable bodies with an [`ApiError`]
+/// instead of actix's plain-text 400
+pub fn json_error(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    match err {
+        JsonPayloadError::Deserialize(e) => {
+            let (line, column) = (e.line(), e.column());
+            let message = e.to_string();
+            // serde_json appends the position, which is reported separately
+            let message = message
+                .strip_suffix(&format!(" at line {} column {}", line, column))
+                .unwrap_or(&message)
+                .to_string();
+            let field = ["missing field `", "unknown field `", "duplicate field `"]
+                .iter()
+                .find_map(|prefix| message.strip_prefix(prefix))
+                .and_then(|rest| rest.split_once('`'))
+                .map(|(name, _)| name.to_string());
+            let expected = message
+                .split_once(", expected ")
+                .filter(|_| message.starts_with("invalid type: ") || message.starts_with("invalid value: "))
+                .map(|(_, expected)| expected.to_string());
+            ApiError::InvalidJson { message, field, expected, line, column }.into()
+        },
+        JsonPayloadError::ContentType => ApiError::BadRequest("Content-Type must be application/json".to_string()).into(),
+        other => other.into(),
+    }
+}
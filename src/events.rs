@@ -0,0 +1,49 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::locks::MutexExt;
+
+/// Entries kept before the oldest is discarded
+const CAPACITY: usize = 200;
+
+/// A relay lifecycle or wake event shown in the live view
+#[derive(Debug, Clone, Serialize)]
+pub struct LifecycleEvent {
+    pub timestamp: DateTime<Utc>,
+    pub esp_id: String,
+    /// `connect`, `disconnect`, `wake` or `wake_ack`
+    pub event: &'static str,
+    /// Wake result, ack round-trip and the like
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Fixed-size buffer of the most recent lifecycle events
+#[derive(Default)]
+pub struct RecentEvents {
+    entries: Mutex<VecDeque<LifecycleEvent>>,
+}
+
+impl RecentEvents {
+    pub fn push(&self, esp_id: &str, event: &'static str, detail: Option<String>) {
+        let mut entries = self.entries.lock_or_recover();
+        if entries.len() >= CAPACITY {
+            entries.pop_front();
+        }
+        entries.push_back(LifecycleEvent {
+            timestamp: Utc::now(),
+            esp_id: esp_id.to_string(),
+            event,
+            detail,
+        });
+    }
+
+    /// Events newest first, at most `limit`
+    pub fn latest(&self, limit: usize) -> Vec<LifecycleEvent> {
+        let entries = self.entries.lock_or_recover();
+        entries.iter().rev().take(limit).cloned().collect()
+    }
+}
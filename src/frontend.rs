@@ -0,0 +1,30 @@
+use std::fs;
+use std::path::Path;
+
+/// The web UI page, read from `<WOL_STATIC_DIR>/index.html` and rendered once at startup.
+///
+/// The rest of the directory (stylesheet, scripts) is served as-is under `/static`.
+pub struct Frontend {
+    /// The rendered page, or why it couldn't be loaded
+    page: Result<String, String>,
+}
+
+impl Frontend {
+    /// Read the page template from `dir`, filling in the server-side settings it shows
+    pub fn load(dir: &Path, banner: &str, udp_fallback: bool, wake_grace_ms: u128) -> Self {
+        let path = dir.join("index.html");
+        let page = fs::read_to_string(&path)
+            .map(|template| {
+                template
+                    .replace("{{BANNER}}", banner)
+                    .replace("{{WAKE_GRACE_MS}}", &wake_grace_ms.to_string())
+                    .replace("{{UDP_FALLBACK}}", if udp_fallback { "true" } else { "false" })
+            })
+            .map_err(|e| format!("Web UI unavailable: cannot read {}: {}", path.display(), e));
+        Self { page }
+    }
+
+    pub fn page(&self) -> Result<&str, &str> {
+        self.page.as_deref().map_err(String::as_str)
+    }
+}
@@ -0,0 +1,44 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::locks::MutexExt;
+
+/// Recently seen UI click tokens, used to drop accidental double-clicks.
+///
+/// Only requests carrying a `click_token` are de-duplicated, so scripts that
+/// wake on purpose without one are never held back.
+pub struct WakeGrace {
+    window: Duration,
+    seen: Mutex<HashMap<(String, String), Instant>>,
+}
+
+impl WakeGrace {
+    /// Ignore repeats of a token within `window`; zero disables de-duplication
+    pub fn new(window: Duration) -> Self {
+        Self { window, seen: Mutex::new(HashMap::new()) }
+    }
+
+    /// Record a click, returning whether the same token was already seen for
+    /// `esp_id` within the window
+    pub fn is_duplicate(&self, esp_id: &str, token: &str) -> bool {
+        if self.window.is_zero() {
+            return false;
+        }
+
+        let now = Instant::now();
+        let mut seen = self.seen.lock_or_recover();
+        seen.retain(|_, at| now.duration_since(*at) < self.window);
+
+        let key = (esp_id.to_string(), token.to_string());
+        if seen.contains_key(&key) {
+            return true;
+        }
+        seen.insert(key, now);
+        false
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+}
@@ -0,0 +1,27 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use tracing::warn;
+
+use crate::atomic_file;
+
+/// Group name → esp_ids of its members, in wake order
+pub type Groups = HashMap<String, Vec<String>>;
+
+/// Read the groups kept in `path`, starting empty when there is no file yet
+pub fn load(path: &Path) -> Groups {
+    match fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+            warn!("[Group] Ignoring unreadable groups file {}: {}", path.display(), e);
+            Groups::new()
+        }),
+        Err(_) => Groups::new(),
+    }
+}
+
+/// Write `groups` to `path`
+pub fn save(path: &Path, groups: &Groups) -> io::Result<()> {
+    atomic_file::write(path, serde_json::to_string_pretty(groups)?)
+}
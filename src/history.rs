@@ -0,0 +1,152 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use actix_web::web;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tracing::warn;
+
+use crate::locks::MutexExt;
+use crate::{atomic_file, WakeOutcome};
+
+/// One wake attempt as recorded in the audit trail
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WakeEvent {
+    pub timestamp: DateTime<Utc>,
+    pub esp_id: String,
+    /// The requesting client as `allowlist::client_ip` sees it; absent for scheduled and emailed wakes
+    pub source_ip: Option<String>,
+    /// A wake outcome such as `sent` or `offline`, or `not_found` / `unauthorized` / `rate_limited`
+    pub result: String,
+    pub success: bool,
+    /// Named credential the request authenticated with; absent for the device's main password
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+    /// Endpoint or job that made the attempt, such as `wake`, `wake-batch` or
+    /// `schedule`; absent in entries from older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub via: Option<String>,
+}
+
+/// Where wake attempts come from, for the entries they leave in the history
+pub struct WakeSource {
+    /// Recorded as `WakeEvent::via`
+    pub via: &'static str,
+    /// The requesting client, if the attempt came over HTTP
+    pub source_ip: Option<String>,
+}
+
+/// Recent wake attempts, persisted so they survive restarts
+pub struct WakeHistory {
+    entries: Mutex<VecDeque<WakeEvent>>,
+    path: PathBuf,
+    limit: usize,
+    /// Held while the file is written so an older snapshot can't overwrite a newer one
+    writing: Mutex<()>,
+    /// Set by `start_writer`; until then `record` writes the file itself
+    background: AtomicBool,
+    write_requested: Notify,
+}
+
+impl WakeHistory {
+    /// Load the history kept in `path`, keeping at most `limit` entries
+    pub fn open(path: PathBuf, limit: usize) -> Self {
+        let mut entries = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str::<VecDeque<WakeEvent>>(&content).unwrap_or_else(|e| {
+                warn!("[History] Ignoring unreadable history file {}: {}", path.display(), e);
+                VecDeque::new()
+            }),
+            Err(_) => VecDeque::new(),
+        };
+        while entries.len() > limit {
+            entries.pop_front();
+        }
+        Self {
+            entries: Mutex::new(entries),
+            path,
+            limit,
+            writing: Mutex::new(()),
+            background: AtomicBool::new(false),
+            write_requested: Notify::new(),
+        }
+    }
+
+    /// Start the task serving `record`, which writes the file on the blocking
+    /// pool; attempts recorded while a write is underway share the next one
+    pub fn start_writer(history: web::Data<Self>) {
+        history.background.store(true, Ordering::Relaxed);
+
+        actix::spawn(async move {
+            loop {
+                history.write_requested.notified().await;
+                let writer = history.clone();
+                if let Err(e) = web::block(move || writer.flush()).await {
+                    warn!("[History] Failed to write {}: {}", history.path.display(), e);
+                }
+            }
+        });
+    }
+
+    /// Append an attempt, dropping the oldest beyond the limit, and have the file written
+    fn record(&self, event: WakeEvent) {
+        {
+            let mut entries = self.entries.lock_or_recover();
+            entries.push_back(event);
+            while entries.len() > self.limit {
+                entries.pop_front();
+            }
+        }
+        if self.background.load(Ordering::Relaxed) {
+            self.write_requested.notify_one();
+        } else {
+            self.flush();
+        }
+    }
+
+    /// Record an attempt by `source` on `esp_id`; `result` is as in `WakeEvent`
+    pub fn record_attempt(&self, source: &WakeSource, esp_id: &str, result: &str, credential: Option<String>) {
+        self.record(WakeEvent {
+            timestamp: Utc::now(),
+            esp_id: esp_id.to_string(),
+            source_ip: source.source_ip.clone(),
+            result: result.to_string(),
+            success: result == WakeOutcome::Sent.as_str(),
+            credential,
+            via: Some(source.via.to_string()),
+        });
+    }
+
+    /// Replace the file with the current entries. Written atomically, since a
+    /// truncated file would lose the whole history on the next start
+    pub fn flush(&self) {
+        let _writing = self.writing.lock_or_recover();
+        // Taken after `writing`, so the last write always holds the latest entries
+        let json = serde_json::to_string(&*self.entries.lock_or_recover());
+        let written = json.map_err(io::Error::from).and_then(|json| atomic_file::write(&self.path, json));
+        if let Err(e) = written {
+            warn!("[History] Failed to write {}: {}", self.path.display(), e);
+        }
+    }
+
+    /// Successful wakes recorded at or after `since`
+    pub fn successes_since(&self, since: DateTime<Utc>) -> usize {
+        let entries = self.entries.lock_or_recover();
+        // Entries are in time order, so the scan can stop at the first older one
+        entries.iter().rev().take_while(|event| event.timestamp >= since).filter(|event| event.success).count()
+    }
+
+    /// Attempts oldest first, only those for `esp_id` when given
+    pub fn list(&self, esp_id: Option<&str>) -> Vec<WakeEvent> {
+        let entries = self.entries.lock_or_recover();
+        entries
+            .iter()
+            .filter(|event| esp_id.is_none_or(|esp_id| event.esp_id == esp_id))
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+use actix_web::web;
+use chrono::Utc;
+use tracing::{error, info};
+
+use crate::config::Config;
+use crate::links::WakeLinks;
+use crate::locks::RwLockExt;
+use crate::schedule::Schedules;
+use crate::DeviceStore;
+
+/// How often the janitor runs its housekeeping pass
+const JANITOR_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Start the periodic housekeeping task if any of its policies are enabled
+pub fn start(
+    store: web::Data<DeviceStore>,
+    config: web::Data<Config>,
+    links: web::Data<WakeLinks>,
+    schedules: web::Data<Schedules>,
+) {
+    let Some(grace) = config.expire_unconnected else {
+        return;
+    };
+    info!("[Janitor] Removing devices that never connect within {:?}", grace);
+
+    actix::spawn(async move {
+        let mut interval = tokio::time::interval(JANITOR_INTERVAL);
+        loop {
+            interval.tick().await;
+            expire_unconnected(&store, &links, &schedules, grace).await;
+        }
+    });
+}
+
+/// Remove devices registered longer than `grace` ago that have never had a relay
+/// connect, detaching them from links, groups and schedules as DELETE does
+async fn expire_unconnected(store: &web::Data<DeviceStore>, links: &WakeLinks, schedules: &Schedules, grace: Duration) {
+    let now = Utc::now();
+    let grace = chrono::Duration::from_std(grace).unwrap_or(chrono::Duration::MAX);
+
+    let mut changed = false;
+    let mut expired = Vec::new();
+    {
+        let mut devices = store.devices.write_or_recover();
+        devices.retain(|esp_id, device| {
+            if device.last_seen.is_some() {
+                return true;
+            }
+            let Some(registered_at) = device.registered_at else {
+                // Registered before timestamps were recorded; start its grace period now
+                device.registered_at = Some(now);
+                changed = true;
+                return true;
+            };
+            if now.signed_duration_since(registered_at) < grace {
+                return true;
+            }
+            info!("[Janitor] Removing device that never connected: ID={}, registered_at={}", esp_id, registered_at);
+            expired.push(esp_id.clone());
+            changed = true;
+            false
+        });
+    }
+
+    let mut grouped = false;
+    for esp_id in &expired {
+        grouped |= crate::forget_device(store, links, schedules, esp_id).2;
+    }
+    if grouped {
+        if let Err(e) = DeviceStore::persist(store, DeviceStore::save_groups).await {
+            error!("[Group] Failed to save groups: {}", e);
+        }
+    }
+
+    if changed {
+        if let Err(e) = DeviceStore::save_async(store).await {
+            error!("[Janitor] Failed to save device info: {}", e);
+        }
+    }
+}
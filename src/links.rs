@@ -0,0 +1,186 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::locks::MutexExt;
+use crate::unix_now;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// An issued one-time wake link
+#[derive(Debug, Serialize, Clone)]
+pub struct IssuedLink {
+    /// Random link identifier, used to revoke it
+    pub id: String,
+    /// Device the link wakes
+    pub esp_id: String,
+    /// Expiry as a unix timestamp (seconds)
+    pub expires_at: u64,
+    /// Whether the link has already been redeemed
+    pub used: bool,
+}
+
+/// Reasons a wake link can't be redeemed
+#[derive(Debug, PartialEq, Eq)]
+pub enum LinkError {
+    Invalid,
+    Expired,
+    Revoked,
+    Used,
+}
+
+/// Issues and tracks HMAC-signed one-time wake links.
+///
+/// A token is `base64(id:expires_at:esp_id).base64(hmac)`. Issued links are
+/// kept in memory so they can be listed, and revoked ids go into a
+/// revocation set checked on every redemption.
+pub struct WakeLinks {
+    secret: Vec<u8>,
+    issued: Mutex<HashMap<String, IssuedLink>>,
+    revoked: Mutex<HashSet<String>>,
+}
+
+impl WakeLinks {
+    /// Create a link registry signing with `secret`
+    pub fn new(secret: Vec<u8>) -> Self {
+        Self {
+            secret,
+            issued: Mutex::new(HashMap::new()),
+            revoked: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Issue a new link for `esp_id`, returning the token and its record
+    pub fn issue(&self, esp_id: &str, ttl: Duration) -> (String, IssuedLink) {
+        let mut id = [0u8; 12];
+        rand::rng().fill_bytes(&mut id);
+
+        let link = IssuedLink {
+            id: URL_SAFE_NO_PAD.encode(id),
+            esp_id: esp_id.to_string(),
+            expires_at: unix_now().saturating_add(ttl.as_secs()),
+            used: false,
+        };
+        let payload = format!("{}:{}:{}", link.id, link.expires_at, link.esp_id);
+        let token = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(&payload),
+            URL_SAFE_NO_PAD.encode(self.sign(&payload)),
+        );
+
+        self.prune();
+        let mut issued = self.issued.lock_or_recover();
+        issued.insert(link.id.clone(), link.clone());
+
+        (token, link)
+    }
+
+    /// Verify and consume a token, returning the esp_id it wakes
+    pub fn redeem(&self, token: &str) -> Result<String, LinkError> {
+        let (payload, signature) = token.split_once('.').ok_or(LinkError::Invalid)?;
+        let payload = URL_SAFE_NO_PAD.decode(payload).map_err(|_| LinkError::Invalid)?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| LinkError::Invalid)?;
+
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(&payload);
+        mac.verify_slice(&signature).map_err(|_| LinkError::Invalid)?;
+
+        let payload = String::from_utf8(payload).map_err(|_| LinkError::Invalid)?;
+        let mut parts = payload.splitn(3, ':');
+        let (Some(id), Some(expires_at), Some(esp_id)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(LinkError::Invalid);
+        };
+        let expires_at: u64 = expires_at.parse().map_err(|_| LinkError::Invalid)?;
+
+        if expires_at <= unix_now() {
+            return Err(LinkError::Expired);
+        }
+        if self.revoked.lock_or_recover().contains(id) {
+            return Err(LinkError::Revoked);
+        }
+
+        let mut issued = self.issued.lock_or_recover();
+        // Links signed before a restart aren't in the registry; track them from here on
+        let link = issued.entry(id.to_string()).or_insert_with(|| IssuedLink {
+            id: id.to_string(),
+            esp_id: esp_id.to_string(),
+            expires_at,
+            used: false,
+        });
+        if link.used {
+            return Err(LinkError::Used);
+        }
+        link.used = true;
+
+        Ok(esp_id.to_string())
+    }
+
+    /// List links that are neither expired, used nor revoked
+    pub fn active(&self, esp_id: Option<&str>) -> Vec<IssuedLink> {
+        self.prune();
+        let issued = self.issued.lock_or_recover();
+        let revoked = self.revoked.lock_or_recover();
+
+        let mut links = issued
+            .values()
+            .filter(|link| !link.used && !revoked.contains(&link.id))
+            .filter(|link| esp_id.is_none_or(|esp_id| link.esp_id == esp_id))
+            .cloned()
+            .collect::<Vec<_>>();
+        links.sort_by(|a, b| a.expires_at.cmp(&b.expires_at).then_with(|| a.id.cmp(&b.id)));
+        links
+    }
+
+    /// Revoke a single link by id, returning false if it isn't active
+    pub fn revoke(&self, id: &str) -> bool {
+        let active = self.active(None).iter().any(|link| link.id == id);
+        if active {
+            self.revoked.lock_or_recover().insert(id.to_string());
+        }
+        active
+    }
+
+    /// Revoke every active link for a device, returning how many were revoked
+    pub fn revoke_device(&self, esp_id: &str) -> usize {
+        let links = self.active(Some(esp_id));
+        let mut revoked = self.revoked.lock_or_recover();
+        for link in &links {
+            revoked.insert(link.id.clone());
+        }
+        links.len()
+    }
+
+    fn sign(&self, payload: &str) -> Vec<u8> {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(payload.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    /// Forget expired links along with their revocation entries
+    fn prune(&self) {
+        let now = unix_now();
+        let mut issued = self.issued.lock_or_recover();
+        let mut revoked = self.revoked.lock_or_recover();
+        issued.retain(|id, link| {
+            let keep = link.expires_at > now;
+            if !keep {
+                revoked.remove(id);
+            }
+            keep
+        });
+    }
+}
+
+/// Generate a random signing secret for when none is configured
+pub fn random_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; 32];
+    rand::rng().fill_bytes(&mut secret);
+    secret
+}
@@ -0,0 +1,139 @@
+use std::time::{Duration, Instant};
+
+use actix::{Actor, ActorContext, AsyncContext, Handler, MailboxError, Message, StreamHandler};
+use actix_web_actors::ws;
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{info, warn};
+
+/// Updates buffered for a browser that has fallen behind before it is told to resync
+const CHANNEL_CAPACITY: usize = 256;
+
+/// How often browsers are pinged, and how long they may stay silent before being dropped
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Device list changes pushed to browsers following `/live`.
+///
+/// Each update is a JSON text frame:
+/// `{"type":"device","device":{...}}` carries the device as `GET /devices`
+/// lists it, after it connects, disconnects, registers or changes;
+/// `{"type":"removed","esp_id":"..."}` follows a deletion;
+/// `{"type":"wake","esp_id":"...","result":"..."}` follows each wake.
+/// A browser that misses updates gets `{"type":"resync"}` and should fetch
+/// `/devices` again.
+pub struct LiveUpdates {
+    sender: broadcast::Sender<String>,
+}
+
+impl LiveUpdates {
+    pub fn new() -> Self {
+        Self { sender: broadcast::channel(CHANNEL_CAPACITY).0 }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.sender.subscribe()
+    }
+
+    pub fn device(&self, device: &impl Serialize) {
+        self.send(json!({ "type": "device", "device": device }));
+    }
+
+    pub fn removed(&self, esp_id: &str) {
+        self.send(json!({ "type": "removed", "esp_id": esp_id }));
+    }
+
+    pub fn wake(&self, esp_id: &str, result: &str) {
+        self.send(json!({ "type": "wake", "esp_id": esp_id, "result": result }));
+    }
+
+    fn send(&self, update: serde_json::Value) {
+        // Fails only when no browser is listening
+        let _ = self.sender.send(update.to_string());
+    }
+}
+
+/// An update to forward to one browser
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Update(String);
+
+/// A browser following `/live`
+pub struct LiveConnection {
+    /// Taken by `started` to forward updates
+    updates: Option<broadcast::Receiver<String>>,
+    last_heartbeat: Instant,
+}
+
+impl LiveConnection {
+    pub fn new(live: &LiveUpdates) -> Self {
+        Self { updates: Some(live.subscribe()), last_heartbeat: Instant::now() }
+    }
+}
+
+impl Actor for LiveConnection {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!("[Live] Browser connected");
+        let Some(mut updates) = self.updates.take() else {
+            return;
+        };
+        let addr = ctx.address();
+        actix::spawn(async move {
+            loop {
+                let frame = match updates.recv().await {
+                    Ok(frame) => frame,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("[Live] Browser fell {} update(s) behind, asking it to resync", missed);
+                        json!({ "type": "resync" }).to_string()
+                    },
+                    Err(RecvError::Closed) => break,
+                };
+                // Waits while the mailbox is full, leaving a slow browser's backlog
+                // in the channel, where falling too far behind triggers a resync
+                if let Err(MailboxError::Closed) = addr.send(Update(frame)).await {
+                    break;
+                }
+            }
+        });
+
+        ctx.run_interval(PING_INTERVAL, |act, ctx| {
+            if act.last_heartbeat.elapsed() > CLIENT_TIMEOUT {
+                info!("[Live] Browser went quiet, closing");
+                ctx.stop();
+                return;
+            }
+            ctx.ping(b"");
+        });
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        info!("[Live] Browser disconnected");
+    }
+}
+
+impl Handler<Update> for LiveConnection {
+    type Result = ();
+
+    fn handle(&mut self, msg: Update, ctx: &mut Self::Context) {
+        ctx.text(msg.0);
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for LiveConnection {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        self.last_heartbeat = Instant::now();
+        match msg {
+            Ok(ws::Message::Ping(bytes)) => ctx.pong(&bytes),
+            // Browsers only listen; anything they send besides pongs is ignored
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            },
+            Err(_) => ctx.stop(),
+            _ => {},
+        }
+    }
+}
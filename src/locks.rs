@@ -0,0 +1,58 @@
+use std::panic::Location;
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use tracing::warn;
+
+/// Acquire a `Mutex` even after a thread panicked while holding it.
+///
+/// The data is used as the panicking thread left it: every critical section
+/// here keeps its data consistent or its inconsistency harmless, so one failed
+/// request shouldn't take every later one down with it.
+pub trait MutexExt<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T>;
+}
+
+/// Like [`MutexExt`], for a `RwLock`
+pub trait RwLockExt<T> {
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T>;
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T>;
+}
+
+impl<T> MutexExt<T> for Mutex<T> {
+    #[track_caller]
+    fn lock_or_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(|poisoned| {
+            let guard = recover(poisoned);
+            self.clear_poison();
+            guard
+        })
+    }
+}
+
+impl<T> RwLockExt<T> for RwLock<T> {
+    #[track_caller]
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T> {
+        self.read().unwrap_or_else(|poisoned| {
+            let guard = recover(poisoned);
+            self.clear_poison();
+            guard
+        })
+    }
+
+    #[track_caller]
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T> {
+        self.write().unwrap_or_else(|poisoned| {
+            let guard = recover(poisoned);
+            self.clear_poison();
+            guard
+        })
+    }
+}
+
+/// Take the guard out of `poisoned`, noting where. The poison is cleared by
+/// the caller, so this is logged once per panic rather than on every access
+#[track_caller]
+fn recover<G>(poisoned: PoisonError<G>) -> G {
+    warn!("[Lock] Recovered a lock poisoned by a panicking thread at {}", Location::caller());
+    poisoned.into_inner()
+}
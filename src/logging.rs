@@ -0,0 +1,170 @@
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use syslog::{Facility, Formatter3164, Logger, LoggerBackend};
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Registry};
+
+use crate::config::{Config, LogFormat, LogOutput};
+
+/// Install the global tracing subscriber for the configured output.
+///
+/// Levels follow `RUST_LOG` (e.g. `warn` or `wol_server=debug`), defaulting to info.
+pub fn init(config: &Config) -> anyhow::Result<()> {
+    let output: Box<dyn Layer<Registry> + Send + Sync> = match &config.log_output {
+        LogOutput::Stdout => fmt_layer(io::stdout, config.log_format, true),
+        LogOutput::File(path) => {
+            let file = RotatingFile::open(path, config.log_max_bytes, config.log_keep)?;
+            fmt_layer(Mutex::new(file), config.log_format, false)
+        },
+        LogOutput::Syslog => {
+            let facility: Facility = config.syslog_facility.parse().map_err(|_| {
+                anyhow::anyhow!("Unknown syslog facility '{}'", config.syslog_facility)
+            })?;
+            Box::new(SyslogLayer::connect(facility)?)
+        },
+    };
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy();
+    tracing_subscriber::registry().with(output).with(filter).init();
+    Ok(())
+}
+
+fn fmt_layer<W>(writer: W, format: LogFormat, ansi: bool) -> Box<dyn Layer<Registry> + Send + Sync>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    match format {
+        LogFormat::Text => layer.with_ansi(ansi).boxed(),
+        LogFormat::Json => layer.json().with_ansi(false).boxed(),
+    }
+}
+
+/// Append-only file that is rotated to `<path>.1`, `<path>.2`, ... once it
+/// would grow past `max_bytes`, keeping at most `keep` rotated files
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    /// Current size of `file`
+    size: u64,
+    /// 0 disables rotation
+    max_bytes: u64,
+    keep: usize,
+}
+
+impl RotatingFile {
+    pub fn open(path: impl AsRef<Path>, max_bytes: u64, keep: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, file, size, max_bytes, keep })
+    }
+
+    /// Shift `<path>.N` up by one, dropping the oldest, and start a fresh file
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.rotated(self.keep));
+            for n in (1..self.keep).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A single oversized record still goes into an empty file rather than rotating forever
+        if self.max_bytes > 0 && self.size > 0 && self.size + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Tracing layer forwarding every event to the local syslog daemon
+struct SyslogLayer {
+    logger: Mutex<Logger<LoggerBackend, Formatter3164>>,
+}
+
+impl SyslogLayer {
+    /// Connect to the local syslog socket with the given facility
+    fn connect(facility: Facility) -> anyhow::Result<Self> {
+        let formatter = Formatter3164 {
+            facility,
+            hostname: None,
+            process: env!("CARGO_PKG_NAME").to_string(),
+            pid: std::process::id(),
+        };
+        let logger = syslog::unix(formatter)
+            .map_err(|e| anyhow::anyhow!("Failed to connect to syslog: {}", e))?;
+
+        Ok(Self { logger: Mutex::new(logger) })
+    }
+}
+
+impl<S: Subscriber> Layer<S> for SyslogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+
+        let mut logger = match self.logger.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        // Syslog has no trace severity, so trace and debug both map to debug
+        let _ = match *event.metadata().level() {
+            Level::ERROR => logger.err(visitor.message),
+            Level::WARN => logger.warning(visitor.message),
+            Level::INFO => logger.info(visitor.message),
+            Level::DEBUG | Level::TRACE => logger.debug(visitor.message),
+        };
+    }
+}
+
+/// Flattens an event's message and extra fields into a single line
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.message, " {}={:?}", field.name(), value);
+        }
+    }
+}
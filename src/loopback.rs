@@ -0,0 +1,66 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::net::UdpSocket;
+
+use crate::wol;
+
+/// How long to keep listening for the last packet after sending
+const CAPTURE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Outcome of a loopback capture run
+#[derive(Debug, Serialize)]
+pub struct CaptureReport {
+    /// Port the capture socket listened on
+    pub port: u16,
+    /// Packets the server sent
+    pub sent: u32,
+    /// Datagrams received by the capture socket
+    pub captured: u32,
+    /// Captured datagrams that were byte-for-byte the expected magic packet
+    pub matching: u32,
+    /// Whether every sent packet was captured intact
+    pub confirmed: bool,
+}
+
+/// Send `count` magic packets for `mac` to `target` on `port` through the
+/// regular send path while a capture socket listens on that port, then
+/// compare what arrived against the expected bytes.
+///
+/// Port 0 picks a free port, useful in CI where binding port 9 needs privileges.
+pub async fn capture_own_packets(mac: &[u8; 6], target: IpAddr, port: u16, count: u32) -> io::Result<CaptureReport> {
+    let bind: SocketAddr = match target {
+        IpAddr::V4(_) => ([0, 0, 0, 0], port).into(),
+        IpAddr::V6(_) => ([0u16; 8], port).into(),
+    };
+    let capture = UdpSocket::bind(bind).await?;
+    let port = capture.local_addr()?.port();
+
+    wol::send_magic_packet(mac, SocketAddr::new(target, port), count, Duration::ZERO).await?;
+
+    let expected = wol::magic_packet(mac);
+    let mut buf = [0u8; 1500];
+    let (mut captured, mut matching) = (0, 0);
+    while captured < count {
+        match tokio::time::timeout(CAPTURE_TIMEOUT, capture.recv_from(&mut buf)).await {
+            Ok(Ok((len, _))) => {
+                captured += 1;
+                if buf[..len] == expected[..] {
+                    matching += 1;
+                }
+            },
+            Ok(Err(e)) => return Err(e),
+            Err(_) => break,
+        }
+    }
+
+    Ok(CaptureReport {
+        port,
+        sent: count,
+        captured,
+        matching,
+        confirmed: matching == count,
+    })
+}
@@ -1,24 +1,272 @@
-use actix_web::{web, App, HttpResponse, HttpServer, Responder, HttpRequest};
+use actix_web::http::header::{HeaderName, HeaderValue, AUTHORIZATION};
+use actix_web::{middleware, web, App, FromRequest, HttpResponse, HttpServer, Responder, HttpRequest, ResponseError};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::fs;
-use std::sync::Mutex;
-use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use actix_web_actors::ws;
-use actix::{Actor, StreamHandler, Handler, Message, AsyncContext};
-use serde_json;
+use actix::{Actor, ActorContext, ActorFutureExt, StreamHandler, Handler, Message, AsyncContext, WrapFuture};
 use serde_json::json;
+use clap::{Parser, Subcommand};
+use rand::Rng;
+use tokio::sync::Notify;
+use tracing::{error, info, warn};
+
+mod access_log;
+mod admin;
+mod allowlist;
+mod atomic_file;
+mod backup;
+mod check;
+mod cli;
+mod commands;
+mod config;
+mod cors;
+mod crypto;
+mod device_dir;
+mod email;
+mod error;
+mod frontend;
+mod events;
+mod grace;
+mod groups;
+mod history;
+mod janitor;
+mod links;
+mod live;
+mod locks;
+mod logging;
+mod loopback;
+mod metrics;
+mod mqtt;
+mod notify;
+mod password;
+mod probe;
+mod repository;
+mod schedule;
+mod schema;
+mod shedding;
+mod sqlite;
+mod system;
+mod timeout;
+mod tls;
+mod wol;
+
+use admin::AdminAuth;
+use backup::BackupPolicy;
+use config::{BannerStyle, Config, HealthRelayCheck, MacAmbiguity};
+use crypto::StorageCipher;
+use error::ApiError;
+use frontend::Frontend;
+use grace::WakeGrace;
+use groups::Groups;
+use live::{LiveConnection, LiveUpdates};
+use locks::{MutexExt, RwLockExt};
+use history::{WakeHistory, WakeSource};
+use links::{LinkError, WakeLinks};
+use metrics::Metrics;
+use mqtt::Mqtt;
+use notify::Notifier;
+use probe::Probe;
+use repository::{Backend, DeviceRepository};
+use schedule::Schedules;
+use shedding::LoadMonitor;
+use system::SystemMonitor;
 
 /// Device registration information
 #[derive(Debug, Deserialize, Serialize, Clone)]
 struct Device {
     /// ESP8266 Device ID
     esp_id: String,
-    /// Target computer MAC address
-    mac_address: String,
+    /// Server-assigned UUID that stays fixed when the esp_id is changed or reused
+    #[serde(default)]
+    id: String,
+    /// MAC addresses of the target computer's NICs; a wake goes to each of them.
+    /// Entries saved with a single `mac_address` string load as a one-item list
+    #[serde(alias = "mac_address", alias = "mac", deserialize_with = "schema::mac_addresses")]
+    mac_addresses: Vec<String>,
     /// Device description name
+    #[serde(default, alias = "name")]
     description: String,
-    /// Password
+    /// Password as supplied at registration. Only files written before hashing
+    /// hold it in plaintext, and only until the device next authenticates
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    password: String,
+    /// Argon2id hash of the password as a PHC string, with 19 MiB of memory,
+    /// 2 iterations and 1 lane (see `password.rs`); the string records its own
+    /// parameters, so raising them later doesn't invalidate stored hashes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password_hash: Option<String>,
+    /// Further passwords accepted for this device, e.g. one per household
+    /// member; wakes record which one was used
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    credentials: Vec<Credential>,
+    /// Target computer IP address or hostname, used for reachability checks
+    #[serde(default, alias = "ip")]
+    ip_address: Option<String>,
+    /// Where the server sends its own magic packets for this device instead of
+    /// `WOL_UDP_BROADCAST`: a directed broadcast or unicast address, IPv4 or IPv6,
+    /// optionally with a port (see `wol::parse_target`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    wake_target: Option<String>,
+    /// UDP port for the server's own magic packets, for NICs that don't listen
+    /// on 9; a port given in `wake_target` takes precedence
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    wake_port: Option<u16>,
+    /// HTTP endpoint that answers once the machine has fully booted; preferred over a TCP probe
+    #[serde(default)]
+    health_url: Option<String>,
+    /// Status `health_url` must return; any 2xx when unset
+    #[serde(default)]
+    health_status: Option<u16>,
+    /// Seconds between keep-alive pings to this device's relays, overriding `WOL_KEEPALIVE_SECS`
+    #[serde(default)]
+    keepalive_secs: Option<u64>,
+    /// Wake automatically on relay reconnect if the machine was last meant to be on
+    #[serde(default)]
+    auto_restore: bool,
+    /// Last known intended power state
+    #[serde(default)]
+    power_state: Option<PowerState>,
+    /// Free-form labels, used e.g. to route webhooks
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Operator context included in wake notifications, e.g. what to check after boot
+    #[serde(default)]
+    wake_note: Option<String>,
+    /// Commands `/command` may send to this device's relays; empty allows all
+    #[serde(default)]
+    allowed_commands: Vec<String>,
+    /// When the device was first registered
+    #[serde(default)]
+    registered_at: Option<DateTime<Utc>>,
+    /// When a relay for the device was last heard from, updated at most every `LAST_SEEN_INTERVAL`
+    /// while connected and on connect and disconnect
+    #[serde(default)]
+    last_seen: Option<DateTime<Utc>>,
+    /// Wakes sent successfully, through any endpoint
+    #[serde(default)]
+    wake_count: u64,
+    /// When the device was last woken successfully
+    #[serde(default)]
+    last_wake: Option<DateTime<Utc>>,
+}
+
+/// A named password accepted for a device in addition to its main one
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct Credential {
+    /// Shown in the wake history, e.g. who the password was given to
+    name: String,
+    /// As supplied at registration; hashed into `password_hash` before saving
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     password: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password_hash: Option<String>,
+}
+
+/// Whether `supplied` matches `hash`, or `plaintext` for an entry not yet migrated
+fn secret_matches(hash: Option<&str>, plaintext: &str, supplied: &str) -> bool {
+    match hash {
+        Some(hash) => password::verify(hash, supplied),
+        None => admin::constant_time_eq(plaintext.as_bytes(), supplied.as_bytes()),
+    }
+}
+
+impl Device {
+    /// A device with nothing but its esp_id set, to fill in before registering it
+    fn new(esp_id: &str) -> Self {
+        Self {
+            esp_id: esp_id.to_string(),
+            id: String::new(),
+            mac_addresses: Vec::new(),
+            description: String::new(),
+            password: String::new(),
+            password_hash: None,
+            credentials: Vec::new(),
+            ip_address: None,
+            wake_target: None,
+            wake_port: None,
+            health_url: None,
+            health_status: None,
+            keepalive_secs: None,
+            auto_restore: false,
+            power_state: None,
+            tags: Vec::new(),
+            wake_note: None,
+            allowed_commands: Vec::new(),
+            registered_at: None,
+            last_seen: None,
+            wake_count: 0,
+            last_wake: None,
+        }
+    }
+
+    /// Whether `supplied` matches the main password's hash, or its plaintext for an entry not yet migrated.
+    ///
+    /// A device registered with only named credentials has no main password.
+    fn password_matches(&self, supplied: &str) -> bool {
+        if self.password_hash.is_none() && self.password.is_empty() && !self.credentials.is_empty() {
+            return false;
+        }
+        secret_matches(self.password_hash.as_deref(), &self.password, supplied)
+    }
+
+    /// Which of the device's passwords `supplied` is: `Some(None)` for the main
+    /// password, `Some(Some(name))` for a named credential
+    fn matching_credential(&self, supplied: &str) -> Option<Option<&str>> {
+        if self.password_matches(supplied) {
+            return Some(None);
+        }
+        self.credentials
+            .iter()
+            .find(|credential| secret_matches(credential.password_hash.as_deref(), &credential.password, supplied))
+            .map(|credential| Some(credential.name.as_str()))
+    }
+
+    /// The first MAC, for places that need exactly one such as relay notifications
+    fn primary_mac(&self) -> &str {
+        self.mac_addresses.first().map_or("", String::as_str)
+    }
+}
+
+/// Check `supplied` against `device`'s passwords, returning which one matched
+/// and, when that one is still kept in plaintext, the hash to replace it with.
+///
+/// Argon2 is deliberately slow, so this is called without holding the device
+/// lock, and off the async workers through `DeviceStore::authenticate_async`.
+fn verify_password(device: &Device, supplied: &str) -> Option<(Option<String>, Option<String>)> {
+    let matched = device.matching_credential(supplied)?.map(str::to_string);
+    let plaintext = match &matched {
+        None => device.password_hash.is_none(),
+        Some(name) => device.credentials.iter().any(|c| &c.name == name && c.password_hash.is_none()),
+    };
+    Some((matched, plaintext.then(|| password::hash(supplied))))
+}
+
+/// Generate a random (version 4) UUID for `Device::id`
+fn new_device_id() -> String {
+    let mut bytes: [u8; 16] = rand::rng().random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// Look a device up by esp_id, or failing that by its stable `id`
+fn find_device<'a>(devices: &'a HashMap<String, Device>, key: &str) -> Option<&'a Device> {
+    devices.get(key).or_else(|| devices.values().find(|device| device.id == key))
+}
+
+/// Intended power state of a target computer
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum PowerState {
+    On,
+    Off,
 }
 
 /// Wake request
@@ -26,284 +274,3286 @@ struct Device {
 struct WakeRequest {
     esp_id: String,
     password: String,
+    /// Generated by the web UI per click; repeats within `WOL_WAKE_GRACE_MS` are ignored
+    click_token: Option<String>,
+    /// Check the request and report how the wake would go out, without sending anything
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Power state change request
+#[derive(Deserialize)]
+struct PowerStateRequest {
+    esp_id: String,
+    password: String,
+    state: PowerState,
+}
+
+/// A connected relay and the latest state it reported
+#[derive(Clone)]
+struct RelayConnection {
+    /// Unique per connection, so a closing actor only removes its own entry
+    id: u64,
+    addr: actix::Addr<WsConnection>,
+    /// Address the relay connected from, which tells a reconnect from another relay
+    peer: Option<IpAddr>,
+    /// When the relay connected (unix seconds)
+    connected_at: u64,
+    /// Latest WiFi signal strength reported by the relay (dBm)
+    rssi: Option<i32>,
+    /// When the relay last sent an `ack` frame
+    acked_at: Option<Instant>,
+    /// When a wake was sent that the relay hasn't acked yet
+    wake_sent_at: Option<Instant>,
+    /// When the relay last reported that it couldn't emit a magic packet
+    wake_failed_at: Option<Instant>,
+    /// When the relay last answered a server ping
+    ponged_at: Option<Instant>,
+}
+
+/// Device data storage
+struct DeviceStore {
+    /// Read-mostly: lookups share a read guard, only changes take `write()`
+    devices: RwLock<HashMap<String, Device>>,
+    /// Device file, database or directory the devices are kept in
+    file_path: String,
+    /// Where changes are persisted, chosen by `--backend`
+    repo: Box<dyn DeviceRepository>,
+    /// Connected relays per esp_id, oldest first
+    active_connections: RwLock<HashMap<String, Vec<RelayConnection>>>,
+    /// When each device was last woken, for `WOL_WAKE_COOLDOWN_SECS`.
+    /// Kept apart from `devices` so rate checks never wait on the device map
+    last_wakes: Mutex<HashMap<String, Instant>>,
+    /// Named sets of devices woken together by `/wake-group`
+    groups: RwLock<Groups>,
+    /// Where the groups are kept
+    groups_file: PathBuf,
+    /// Set once the debounced saver task is running
+    debounced: AtomicBool,
+    /// Wakes the debounced saver task
+    save_requested: Notify,
+    /// Held for the whole of each write so concurrent ones can't interleave
+    saving: Mutex<()>,
+    /// When the store was created, for uptime reporting
+    started_at: Instant,
+    /// Set once the server is listening with the devices loaded, cleared on shutdown; see `/ready`
+    ready: AtomicBool,
+    /// Where device status and wakes are published, if `WOL_MQTT_BROKER` is set
+    mqtt: Mqtt,
+    /// Device list changes for browsers following `/live`
+    live: LiveUpdates,
+}
+
+impl DeviceStore {
+    /// Create a new device storage instance
+    fn new(
+        backend: Backend,
+        file_path: &str,
+        dir: Option<&Path>,
+        backup: Option<BackupPolicy>,
+        cipher: Option<StorageCipher>,
+        groups_file: PathBuf,
+        mqtt: Mqtt,
+    ) -> anyhow::Result<Self> {
+        let repo = repository::open(backend, file_path, dir, backup, cipher)?;
+        let location = dir.map_or_else(|| file_path.to_string(), |dir| dir.display().to_string());
+        let devices = repo.list().map_err(|e| anyhow::anyhow!("Failed to load {}: {}", location, e))?;
+        Ok(Self {
+            devices: RwLock::new(devices),
+            file_path: location,
+            repo,
+            active_connections: RwLock::new(HashMap::new()),
+            last_wakes: Mutex::new(HashMap::new()),
+            groups: RwLock::new(groups::load(&groups_file)),
+            groups_file,
+            debounced: AtomicBool::new(false),
+            save_requested: Notify::new(),
+            saving: Mutex::new(()),
+            started_at: Instant::now(),
+            ready: AtomicBool::new(false),
+            mqtt,
+            live: LiveUpdates::new(),
+        })
+    }
+
+    /// Save device data to file, blocking until it is on disk
+    fn save(&self) -> std::io::Result<()> {
+        let _saving = self.saving.lock_or_recover();
+        let devices = self.devices.read_or_recover().clone();
+        self.repo.save_all(&devices)
+    }
+
+    /// Persist one added or replaced device
+    fn insert(&self, device: &Device) -> std::io::Result<()> {
+        let _saving = self.saving.lock_or_recover();
+        self.repo.insert(device)
+    }
+
+    /// Persist the removal of one device
+    fn remove(&self, esp_id: &str) -> std::io::Result<bool> {
+        let _saving = self.saving.lock_or_recover();
+        self.repo.remove(esp_id)
+    }
+
+    /// Write the device groups to their file
+    fn save_groups(&self) -> std::io::Result<()> {
+        let _saving = self.saving.lock_or_recover();
+        let groups = self.groups.read_or_recover().clone();
+        groups::save(&self.groups_file, &groups)
+    }
+
+    /// Run `op` on the blocking thread pool, for use from async handlers
+    async fn persist<R: Send + 'static>(
+        store: &web::Data<Self>,
+        op: impl FnOnce(&Self) -> std::io::Result<R> + Send + 'static,
+    ) -> std::io::Result<R> {
+        let store = store.clone();
+        web::block(move || op(&store)).await.map_err(std::io::Error::other)?
+    }
+
+    /// `save` on the blocking thread pool, for use from async handlers
+    async fn save_async(store: &web::Data<Self>) -> std::io::Result<()> {
+        Self::persist(store, Self::save).await
+    }
+
+    /// Claim a wake of `esp_id`, or the time left until its cooldown ends.
+    ///
+    /// The slot is taken before dispatching so concurrent requests can't all
+    /// get through; pass the returned previous wake to `release_wake` if it
+    /// isn't sent after all.
+    fn claim_wake(&self, esp_id: &str, cooldown: Duration) -> Result<Option<Instant>, Duration> {
+        let now = Instant::now();
+        let mut last_wakes = self.last_wakes.lock_or_recover();
+        if let Some(last) = last_wakes.get(esp_id) {
+            let elapsed = now.duration_since(*last);
+            if elapsed < cooldown {
+                return Err(cooldown - elapsed);
+            }
+        }
+        Ok(last_wakes.insert(esp_id.to_string(), now))
+    }
+
+    /// Time left until `esp_id`'s cooldown ends, without claiming a wake
+    fn cooldown_remaining(&self, esp_id: &str, cooldown: Duration) -> Option<Duration> {
+        let elapsed = self.last_wakes.lock_or_recover().get(esp_id)?.elapsed();
+        cooldown.checked_sub(elapsed).filter(|left| !left.is_zero())
+    }
+
+    /// Undo a `claim_wake` whose wake failed, so it doesn't start a cooldown
+    fn release_wake(&self, esp_id: &str, previous: Option<Instant>) {
+        let mut last_wakes = self.last_wakes.lock_or_recover();
+        match previous {
+            Some(previous) => last_wakes.insert(esp_id.to_string(), previous),
+            None => last_wakes.remove(esp_id),
+        };
+    }
+
+    /// Close every connected relay with `code`, returning how many there were
+    fn close_relays(&self, code: u16, reason: &str) -> usize {
+        let connections = self.active_connections.read_or_recover();
+        let mut closed = 0;
+        for conn in connections.values().flatten() {
+            conn.addr.do_send(Disconnect { code, reason: reason.to_string() });
+            closed += 1;
+        }
+        closed
+    }
+
+    /// Addresses of every relay connected for `esp_id`
+    fn relay_addrs(&self, esp_id: &str) -> Vec<actix::Addr<WsConnection>> {
+        self.relay_connections(esp_id).into_iter().map(|(_, addr)| addr).collect()
+    }
+
+    /// Connection ids and source addresses of every relay connected for `esp_id`, oldest first
+    fn relay_peers(&self, esp_id: &str) -> Vec<(u64, Option<IpAddr>)> {
+        let connections = self.active_connections.read_or_recover();
+        connections
+            .get(esp_id)
+            .map(|conns| conns.iter().map(|conn| (conn.id, conn.peer)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Connection ids and addresses of every relay connected for `esp_id`
+    fn relay_connections(&self, esp_id: &str) -> Vec<(u64, actix::Addr<WsConnection>)> {
+        let connections = self.active_connections.read_or_recover();
+        connections
+            .get(esp_id)
+            .map(|conns| conns.iter().map(|conn| (conn.id, conn.addr.clone())).collect())
+            .unwrap_or_default()
+    }
+
+    /// A copy of the device with esp_id or `id` equal to `key`
+    fn find(&self, key: &str) -> Option<Device> {
+        find_device(&self.devices.read_or_recover(), key).cloned()
+    }
+
+    /// Verify `supplied` against any of `device`'s passwords
+    fn check_password(&self, device: &Device, supplied: &str) -> bool {
+        self.authenticate(device, supplied).is_some()
+    }
+
+    /// `check_password` on the blocking thread pool, for use from async handlers
+    async fn check_password_async(&self, device: &Device, supplied: &str) -> bool {
+        self.authenticate_async(device, supplied).await.is_some()
+    }
+
+    /// Verify `supplied` for `device`, returning which password matched as
+    /// `Device::matching_credential` does. A plaintext password is replaced
+    /// with its hash the first time it is used successfully
+    fn authenticate(&self, device: &Device, supplied: &str) -> Option<Option<String>> {
+        let (matched, hash) = verify_password(device, supplied)?;
+        self.migrate_password(&device.esp_id, &matched, hash);
+        Some(matched)
+    }
+
+    /// `authenticate` with the hashing done on the blocking thread pool, for use from async handlers
+    async fn authenticate_async(&self, device: &Device, supplied: &str) -> Option<Option<String>> {
+        let (device, supplied) = (device.clone(), supplied.to_string());
+        let verified = web::block(move || verify_password(&device, &supplied).map(|verified| (device.esp_id, verified)))
+            .await
+            .unwrap_or_else(|e| {
+                error!("[Auth] Password verification did not run: {}", e);
+                None
+            });
+        let (esp_id, (matched, hash)) = verified?;
+        self.migrate_password(&esp_id, &matched, hash);
+        Some(matched)
+    }
+
+    /// Store `hash` from `verify_password` in place of the plaintext password `matched` named
+    fn migrate_password(&self, esp_id: &str, matched: &Option<String>, hash: Option<String>) {
+        if let Some(hash) = hash {
+            if let Some(stored) = self.devices.write_or_recover().get_mut(esp_id) {
+                match &matched {
+                    None => {
+                        stored.password_hash = Some(hash);
+                        stored.password.clear();
+                    },
+                    Some(name) => {
+                        for credential in stored.credentials.iter_mut().filter(|c| &c.name == name) {
+                            credential.password_hash = Some(hash.clone());
+                            credential.password.clear();
+                        }
+                    },
+                }
+            }
+            info!("[Storage] Replaced plaintext password with its hash: ID={}", esp_id);
+            self.save_later();
+        }
+    }
+
+    /// Whether at least one relay is connected for `esp_id`
+    fn is_online(&self, esp_id: &str) -> bool {
+        self.active_connections.read_or_recover().contains_key(esp_id)
+    }
+
+    /// Push the device as `GET /devices` lists it to browsers following `/live`
+    fn publish_device(&self, esp_id: &str) {
+        let online = self.is_online(esp_id);
+        if let Some(device) = self.devices.read_or_recover().get(esp_id) {
+            self.live.device(&DeviceView::new(device, online));
+        }
+    }
+
+    /// Persist soon rather than now, coalescing bursts of updates into one write.
+    ///
+    /// Saves immediately, blocking the caller, unless the saver task has been started.
+    fn save_later(&self) {
+        if self.debounced.load(Ordering::Relaxed) {
+            self.save_requested.notify_one();
+        } else if let Err(e) = self.save() {
+            error!("[Save] Failed to save device info: {}", e);
+        }
+    }
+
+    /// Start the task serving `save_later`, writing `delay` (plus up to `jitter`) after a request.
+    ///
+    /// A zero `delay` still moves the write off the caller onto the blocking pool.
+    fn start_debounced_saver(store: web::Data<Self>, delay: Duration, jitter: Duration) {
+        store.debounced.store(true, Ordering::Relaxed);
+
+        actix::spawn(async move {
+            loop {
+                store.save_requested.notified().await;
+                // Random jitter spreads writes from instances sharing storage
+                let jitter = if jitter.is_zero() {
+                    Duration::ZERO
+                } else {
+                    rand::rng().random_range(Duration::ZERO..=jitter)
+                };
+                if !(delay + jitter).is_zero() {
+                    tokio::time::sleep(delay + jitter).await;
+                }
+
+                if let Err(e) = Self::save_async(&store).await {
+                    error!("[Save] Debounced save failed: {}", e);
+                }
+            }
+        });
+    }
+}
+
+/// Whether a device other than `esp_id` already uses `description`
+fn description_taken(devices: &HashMap<String, Device>, esp_id: &str, description: &str) -> bool {
+    devices
+        .values()
+        .any(|other| other.esp_id != esp_id && other.description.trim() == description.trim())
+}
+
+/// Accepted range for a device's `keepalive_secs`
+const KEEPALIVE_SECS_RANGE: std::ops::RangeInclusive<u64> = 5..=3600;
+
+/// Longest `wake_note` accepted at registration
+const MAX_WAKE_NOTE_LEN: usize = 500;
+
+/// Query options for `/register`
+#[derive(Deserialize)]
+struct RegisterQuery {
+    /// Replace a device already registered under the same esp_id
+    #[serde(default)]
+    overwrite: bool,
+}
+
+/// Register a new device, or replace an existing one with `?overwrite=true` (admin)
+#[tracing::instrument(skip_all, fields(esp_id = %device.esp_id))]
+async fn register_device(
+    _admin: AdminAuth,
+    store: web::Data<DeviceStore>,
+    config: web::Data<Config>,
+    query: web::Query<RegisterQuery>,
+    device: web::Json<Device>,
+) -> Result<HttpResponse, ApiError> {
+    info!("[Register] New device registration request: ID={}", device.esp_id);
+
+    let device = device.into_inner();
+    let device = web::block(move || hash_passwords(device))
+        .await
+        .map_err(|e| ApiError::Internal(format!("Failed to hash passwords: {}", e)))?;
+    let (device, _) = upsert_device(&store, &config, device, query.overwrite)?;
+    let esp_id = device.esp_id.clone();
+    
+    DeviceStore::persist(&store, move |store| store.insert(&device)).await.map_err(|e| {
+        error!("[Register] Failed to save device info: {}", e);
+        ApiError::Internal(format!("Failed to save device info: {}", e))
+    })?;
+    info!("[Register] Device registered and saved successfully");
+    store.publish_device(&esp_id);
+    Ok(HttpResponse::Ok().json("Device registered successfully"))
+}
+
+/// Validate `device` and add it to the device map, replacing the one already
+/// registered under its esp_id only when `overwrite` is set.
+///
+/// Its passwords must already have gone through `hash_passwords`, which is too
+/// slow to run under the device lock. Returns the device as stored and whether
+/// it is new; persisting it is left to the caller.
+fn upsert_device(store: &DeviceStore, config: &Config, mut device: Device, overwrite: bool) -> Result<(Device, bool), ApiError> {
+    let macs = validate_device(&device).map_err(|e| {
+        warn!("[Register] Invalid device: ID={}, {}", device.esp_id, e);
+        ApiError::BadRequest(e)
+    })?;
+
+    let mut devices = store.devices.write_or_recover();
+    // Checked under the write lock so two registrations can't both claim a new esp_id
+    if !overwrite && devices.contains_key(&device.esp_id) {
+        warn!("[Register] esp_id already registered: ID={}", device.esp_id);
+        return Err(ApiError::Conflict(format!(
+            "Device {} is already registered; use ?overwrite=true to replace it",
+            device.esp_id
+        )));
+    }
+    if config.unique_descriptions && description_taken(&devices, &device.esp_id, &device.description) {
+        warn!("[Register] Description already in use: ID={}", device.esp_id);
+        return Err(ApiError::Conflict("Description already used by another device".to_string()));
+    }
+    device.mac_addresses = macs;
+    // The id and timestamps are server-maintained; keep them across re-registration
+    let previous = devices.get(&device.esp_id);
+    device.id = previous.map_or_else(new_device_id, |previous| previous.id.clone());
+    device.registered_at = previous.and_then(|previous| previous.registered_at).or(Some(Utc::now()));
+    device.last_seen = previous.and_then(|previous| previous.last_seen);
+    device.wake_count = previous.map_or(0, |previous| previous.wake_count);
+    device.last_wake = previous.and_then(|previous| previous.last_wake);
+    let created = devices.insert(device.esp_id.clone(), device.clone()).is_none();
+    Ok((device, created))
+}
+
+/// Check the fields of a device submitted by an admin, returning its MAC
+/// addresses normalized and without duplicates
+fn validate_device(device: &Device) -> Result<Vec<String>, String> {
+    if device.mac_addresses.is_empty() {
+        return Err("At least one MAC address is required".to_string());
+    }
+    let mut macs = Vec::with_capacity(device.mac_addresses.len());
+    for mac_address in &device.mac_addresses {
+        let mac = wol::parse_mac(mac_address).map_err(|e| format!("Invalid MAC address '{}': {}", mac_address, e))?;
+        let mac = wol::format_mac(&mac);
+        if !macs.contains(&mac) {
+            macs.push(mac);
+        }
+    }
+    if let Some(target) = &device.wake_target {
+        wol::parse_target(target).map_err(|e| format!("Invalid wake_target: {}", e))?;
+    }
+    if device.wake_port == Some(0) {
+        return Err("wake_port must not be 0".to_string());
+    }
+    if device.keepalive_secs.is_some_and(|secs| !KEEPALIVE_SECS_RANGE.contains(&secs)) {
+        return Err(format!(
+            "keepalive_secs must be between {} and {}",
+            KEEPALIVE_SECS_RANGE.start(),
+            KEEPALIVE_SECS_RANGE.end()
+        ));
+    }
+    if device.wake_note.as_ref().is_some_and(|note| note.chars().count() > MAX_WAKE_NOTE_LEN) {
+        return Err(format!("wake_note must be at most {} characters", MAX_WAKE_NOTE_LEN));
+    }
+    if let Some(unknown) = device.allowed_commands.iter().find(|name| commands::find(name).is_none()) {
+        return Err(format!("Unknown command '{}' in allowed_commands", unknown));
+    }
+    for (i, credential) in device.credentials.iter().enumerate() {
+        if credential.name.trim().is_empty() {
+            return Err("Credential names must not be empty".to_string());
+        }
+        if device.credentials[..i].iter().any(|other| other.name == credential.name) {
+            return Err(format!("Duplicate credential '{}'", credential.name));
+        }
+        if credential.password.is_empty() && credential.password_hash.is_none() {
+            return Err(format!("Credential '{}' needs a password", credential.name));
+        }
+    }
+    Ok(macs)
+}
+
+/// Replace the plaintext passwords of a device being registered by their hashes.
+///
+/// Naming credentials makes the main password optional. Slow by design, so
+/// async callers run it through `web::block`.
+fn hash_passwords(mut device: Device) -> Device {
+    device.password_hash = if device.password.is_empty() && !device.credentials.is_empty() {
+        None
+    } else {
+        Some(password::hash(&device.password))
+    };
+    device.password.clear();
+    hash_credentials(&mut device);
+    device
+}
+
+/// Replace the plaintext of each credential submitted with `device` by its hash
+fn hash_credentials(device: &mut Device) {
+    for credential in device.credentials.iter_mut().filter(|credential| !credential.password.is_empty()) {
+        credential.password_hash = Some(password::hash(&credential.password));
+        credential.password.clear();
+    }
+}
+
+/// Remove a registered device, closing its relays and revoking its wake links
+#[tracing::instrument(skip_all, fields(esp_id = %esp_id))]
+async fn delete_device(
+    _admin: AdminAuth,
+    store: web::Data<DeviceStore>,
+    links: web::Data<WakeLinks>,
+    schedules: web::Data<Schedules>,
+    esp_id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    info!("[Delete] Device removal request: ID={}", esp_id);
+
+    let removed = {
+        let mut devices = store.devices.write_or_recover();
+        let esp_id = find_device(&devices, &esp_id).map(|device| device.esp_id.clone());
+        esp_id.and_then(|esp_id| devices.remove(&esp_id))
+    };
+    let Some(device) = removed else {
+        warn!("[Delete] Device not found: ID={}", esp_id);
+        return Err(ApiError::device_not_found());
+    };
+    let esp_id = &device.esp_id;
+
+    let (relays, revoked, grouped) = forget_device(&store, &links, &schedules, esp_id);
+    if grouped {
+        if let Err(e) = DeviceStore::persist(&store, DeviceStore::save_groups).await {
+            error!("[Group] Failed to save groups: {}", e);
+        }
+    }
+
+    let stored_id = esp_id.clone();
+    match DeviceStore::persist(&store, move |store| store.remove(&stored_id)).await {
+        Ok(_) => {
+            info!(
+                "[Delete] Device removed and saved: ID={}, relays={}, links={}",
+                esp_id, relays, revoked
+            );
+            Ok(HttpResponse::Ok().json(json!({ "esp_id": esp_id, "description": device.description })))
+        },
+        Err(e) => {
+            error!("[Delete] Failed to save device info: {}", e);
+            Err(ApiError::Internal(e.to_string()))
+        },
+    }
+}
+
+/// Detach everything that refers to a device just taken out of the device map:
+/// close its relays, revoke its wake links, cancel its scheduled wakes and drop
+/// it from its groups.
+///
+/// Returns the relays closed, the links revoked and whether any group changed;
+/// the groups are left for the caller to save.
+fn forget_device(store: &DeviceStore, links: &WakeLinks, schedules: &Schedules, esp_id: &str) -> (usize, usize, bool) {
+    // The relays' actors find no entry left to remove once they stop
+    let connections = store.active_connections.write_or_recover().remove(esp_id).unwrap_or_default();
+    for conn in &connections {
+        conn.addr.do_send(Disconnect {
+            code: ADMIN_DISCONNECT_CODE,
+            reason: "Device removed".to_string(),
+        });
+    }
+    let revoked = links.revoke_device(esp_id);
+    let cancelled = schedules.cancel_device(esp_id);
+    if cancelled > 0 {
+        info!("[Schedule] Cancelled scheduled wakes of removed device: ID={}, jobs={}", esp_id, cancelled);
+    }
+    store.live.removed(esp_id);
+    let mut grouped = false;
+    for members in store.groups.write_or_recover().values_mut() {
+        let before = members.len();
+        members.retain(|member| member != esp_id);
+        grouped |= members.len() != before;
+    }
+    (connections.len(), revoked, grouped)
+}
+
+/// Partial device update; absent fields are left unchanged
+#[derive(Deserialize)]
+struct DeviceUpdate {
+    /// The device password; not needed when the admin key is sent
+    password: Option<String>,
+    description: Option<String>,
+    /// An empty string clears it
+    ip_address: Option<String>,
+}
+
+/// Change a device's description or IP address without re-registering it.
+///
+/// Authorized by the device password in the body, or by `Authorization: Bearer <WOL_ADMIN_KEY>`.
+#[tracing::instrument(skip_all, fields(esp_id = %esp_id))]
+async fn update_device(
+    req: HttpRequest,
+    store: web::Data<DeviceStore>,
+    config: web::Data<Config>,
+    esp_id: web::Path<String>,
+    update: web::Json<DeviceUpdate>,
+) -> Result<HttpResponse, ApiError> {
+    info!("[Update] Device update request: ID={}", esp_id);
+    if update.description.is_none() && update.ip_address.is_none() {
+        return Err(ApiError::BadRequest("Nothing to update: give description and/or ip_address".to_string()));
+    }
+
+    let device = store.find(&esp_id).ok_or_else(|| {
+        warn!("[Update] Device not found: ID={}", esp_id);
+        ApiError::device_not_found()
+    })?;
+    // Only try the admin key when one is sent, so password-authorized updates don't log a rejection
+    let admin = req.headers().contains_key(AUTHORIZATION) && AdminAuth::extract(&req).await.is_ok();
+    let authorized = match update.password.as_deref() {
+        _ if admin => true,
+        Some(password) => store.check_password_async(&device, password).await,
+        None => false,
+    };
+    if !authorized {
+        warn!("[Update] Password verification failed: ID={}", esp_id);
+        return Err(ApiError::Unauthorized);
+    }
+
+    let device = {
+        let mut devices = store.devices.write_or_recover();
+        if let Some(description) = &update.description {
+            if config.unique_descriptions && description_taken(&devices, &device.esp_id, description) {
+                warn!("[Update] Description already in use: ID={}", device.esp_id);
+                return Err(ApiError::Conflict("Description already used by another device".to_string()));
+            }
+        }
+        // Deleted since it was looked up
+        let stored = devices.get_mut(&device.esp_id).ok_or_else(ApiError::device_not_found)?;
+        if let Some(description) = &update.description {
+            stored.description = description.clone();
+        }
+        if let Some(ip_address) = &update.ip_address {
+            let ip_address = ip_address.trim();
+            stored.ip_address = (!ip_address.is_empty()).then(|| ip_address.to_string());
+        }
+        stored.clone()
+    };
+
+    let saved = device.clone();
+    DeviceStore::persist(&store, move |store| store.insert(&saved)).await.map_err(|e| {
+        error!("[Update] Failed to save device info: {}", e);
+        ApiError::Internal(format!("Failed to save device info: {}", e))
+    })?;
+    info!("[Update] Device updated and saved: ID={}", device.esp_id);
+    store.publish_device(&device.esp_id);
+    let online = store.is_online(&device.esp_id);
+    Ok(HttpResponse::Ok().json(DeviceView::new(&device, online)))
+}
+
+/// Query parameters for `GET /devices/export`
+#[derive(Deserialize)]
+struct ExportQuery {
+    /// Leave out passwords and their hashes
+    #[serde(default)]
+    redact_passwords: bool,
+}
+
+/// Download every device in the device file format, for backups or moving to another server
+async fn export_devices(
+    _admin: AdminAuth,
+    store: web::Data<DeviceStore>,
+    query: web::Query<ExportQuery>,
+) -> HttpResponse {
+    // Sorted so successive exports diff cleanly
+    let mut devices = store.devices.read_or_recover().clone().into_iter().collect::<BTreeMap<_, _>>();
+    if query.redact_passwords {
+        for device in devices.values_mut() {
+            device.password.clear();
+            device.password_hash = None;
+            device.credentials.clear();
+        }
+    }
+    info!(
+        "[Export] Exporting {} device(s), passwords {}",
+        devices.len(),
+        if query.redact_passwords { "redacted" } else { "included" }
+    );
+    HttpResponse::Ok()
+        .insert_header(("Content-Disposition", "attachment; filename=\"devices.json\""))
+        .json(&devices)
+}
+
+/// Query parameters for `POST /devices/import`
+#[derive(Deserialize)]
+struct ImportQuery {
+    /// Replace the whole device list, removing devices missing from the import,
+    /// instead of merging into it
+    #[serde(default)]
+    replace: bool,
+}
+
+/// Load devices in the format `GET /devices/export` produces.
+///
+/// Every entry is validated before anything changes, so one bad device
+/// rejects the whole import. Devices without a password, as in a redacted
+/// export, keep the password of the device already registered under their
+/// esp_id.
+async fn import_devices(
+    _admin: AdminAuth,
+    store: web::Data<DeviceStore>,
+    links: web::Data<WakeLinks>,
+    schedules: web::Data<Schedules>,
+    config: web::Data<Config>,
+    query: web::Query<ImportQuery>,
+    body: web::Json<serde_json::Value>,
+) -> Result<HttpResponse, ApiError> {
+    let mut imported = schema::devices_from_value(body.into_inner()).map_err(|e| {
+        warn!("[Import] Unreadable device data: {}", e);
+        ApiError::BadRequest(format!("Invalid device data: {}", e))
+    })?;
+    info!("[Import] Received {} device(s), replace={}", imported.len(), query.replace);
+
+    for device in imported.values_mut() {
+        let reject = |e: String| {
+            warn!("[Import] Invalid device: ID={}, {}", device.esp_id, e);
+            ApiError::BadRequest(format!("Device {}: {}", device.esp_id, e))
+        };
+        if device.esp_id.trim().is_empty() {
+            return Err(reject("esp_id must not be empty".to_string()));
+        }
+        let macs = validate_device(device).map_err(reject)?;
+        device.mac_addresses = macs;
+    }
+    // Hashing is deliberately slow, so a large import hashes off the workers
+    let mut imported = web::block(move || {
+        for device in imported.values_mut() {
+            if !device.password.is_empty() {
+                device.password_hash = Some(password::hash(&device.password));
+                device.password.clear();
+            }
+            hash_credentials(device);
+        }
+        imported
+    })
+    .await
+    .map_err(|e| ApiError::Internal(format!("Failed to hash passwords: {}", e)))?;
+
+    let (added, updated, removed) = {
+        let mut devices = store.devices.write_or_recover();
+        for device in imported.values_mut() {
+            let previous = devices.get(&device.esp_id);
+            if device.password_hash.is_none() && device.credentials.is_empty() {
+                let has_password = |previous: &&Device| {
+                    previous.password_hash.is_some() || !previous.password.is_empty() || !previous.credentials.is_empty()
+                };
+                let Some(previous) = previous.filter(has_password) else {
+                    warn!("[Import] No password for new device: ID={}", device.esp_id);
+                    return Err(ApiError::BadRequest(format!("Device {}: a password is required", device.esp_id)));
+                };
+                device.password = previous.password.clone();
+                device.password_hash = previous.password_hash.clone();
+                device.credentials = previous.credentials.clone();
+            }
+            // As with re-registration, a device already here keeps its id
+            if let Some(previous) = previous {
+                device.id = previous.id.clone();
+                device.last_seen = device.last_seen.max(previous.last_seen);
+                device.wake_count = device.wake_count.max(previous.wake_count);
+                device.last_wake = device.last_wake.max(previous.last_wake);
+            }
+            device.registered_at = device.registered_at.or(previous.and_then(|previous| previous.registered_at)).or(Some(Utc::now()));
+        }
+
+        let mut merged = if query.replace { HashMap::new() } else { devices.clone() };
+        merged.extend(imported.iter().map(|(esp_id, device)| (esp_id.clone(), device.clone())));
+        if config.unique_descriptions {
+            if let Some(device) = imported.values().find(|device| description_taken(&merged, &device.esp_id, &device.description)) {
+                warn!("[Import] Description already in use: ID={}", device.esp_id);
+                return Err(ApiError::Conflict(format!("Device {}: description already used by another device", device.esp_id)));
+            }
+        }
+
+        let added = imported.keys().filter(|esp_id| !devices.contains_key(*esp_id)).count();
+        let removed = devices.keys().filter(|esp_id| !merged.contains_key(*esp_id)).cloned().collect::<Vec<_>>();
+        *devices = merged;
+        (added, imported.len() - added, removed)
+    };
+
+    let mut grouped = false;
+    for esp_id in &removed {
+        grouped |= forget_device(&store, &links, &schedules, esp_id).2;
+    }
+    if grouped {
+        if let Err(e) = DeviceStore::persist(&store, DeviceStore::save_groups).await {
+            error!("[Group] Failed to save groups: {}", e);
+        }
+    }
+    DeviceStore::save_async(&store).await.map_err(|e| {
+        error!("[Import] Failed to save device info: {}", e);
+        ApiError::Internal(format!("Failed to save device info: {}", e))
+    })?;
+    info!("[Import] Devices imported and saved: added={}, updated={}, removed={}", added, updated, removed.len());
+    for esp_id in imported.keys() {
+        store.publish_device(esp_id);
+    }
+    Ok(HttpResponse::Ok().json(json!({ "added": added, "updated": updated, "removed": removed.len() })))
+}
+
+/// A device as listed by `GET /devices`: the stored fields minus the password,
+/// plus whether a relay is connected right now
+#[derive(Serialize)]
+struct DeviceView<'a> {
+    esp_id: &'a str,
+    id: &'a str,
+    mac_addresses: &'a [String],
+    description: &'a str,
+    ip_address: &'a Option<String>,
+    wake_target: &'a Option<String>,
+    wake_port: Option<u16>,
+    health_url: &'a Option<String>,
+    health_status: Option<u16>,
+    keepalive_secs: Option<u64>,
+    auto_restore: bool,
+    power_state: Option<PowerState>,
+    tags: &'a [String],
+    wake_note: &'a Option<String>,
+    allowed_commands: &'a [String],
+    registered_at: Option<DateTime<Utc>>,
+    last_seen: Option<DateTime<Utc>>,
+    wake_count: u64,
+    last_wake: Option<DateTime<Utc>>,
+    online: bool,
+}
+
+impl<'a> DeviceView<'a> {
+    fn new(device: &'a Device, online: bool) -> Self {
+        Self {
+            esp_id: &device.esp_id,
+            id: &device.id,
+            mac_addresses: &device.mac_addresses,
+            description: &device.description,
+            ip_address: &device.ip_address,
+            wake_target: &device.wake_target,
+            wake_port: device.wake_port,
+            health_url: &device.health_url,
+            health_status: device.health_status,
+            keepalive_secs: device.keepalive_secs,
+            auto_restore: device.auto_restore,
+            power_state: device.power_state,
+            tags: &device.tags,
+            wake_note: &device.wake_note,
+            allowed_commands: &device.allowed_commands,
+            registered_at: device.registered_at,
+            last_seen: device.last_seen,
+            wake_count: device.wake_count,
+            last_wake: device.last_wake,
+            online,
+        }
+    }
+}
+
+/// Query parameters for the device list
+#[derive(Deserialize)]
+struct DevicesQuery {
+    /// JSONP callback name, honored only when `WOL_JSONP` is enabled
+    callback: Option<String>,
+    /// Most devices to return
+    limit: Option<usize>,
+    /// Devices to skip, in esp_id order
+    offset: Option<usize>,
+    /// Case-insensitive substring the esp_id or description must contain
+    q: Option<String>,
+}
+
+impl DevicesQuery {
+    /// Whether the caller asked for a page rather than the plain full list
+    fn paginated(&self) -> bool {
+        self.limit.is_some() || self.offset.is_some() || self.q.is_some()
+    }
+}
+
+/// Whether `name` is a safe JavaScript callback such as `cb` or `app.onDevices`
+fn is_valid_callback(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name.split('.').all(|part| {
+            let mut chars = part.chars();
+            matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '$')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+        })
+}
+
+/// Get all registered devices
+async fn get_devices(
+    store: web::Data<DeviceStore>,
+    config: web::Data<Config>,
+    query: web::Query<DevicesQuery>,
+) -> Result<HttpResponse, ApiError> {
+    info!("[Query] Received request for device list");
+    
+    let devices_vec = {
+        let devices = store.devices.read_or_recover();
+        let needle = query.q.as_deref().map(str::to_lowercase).unwrap_or_default();
+        let mut matched = devices
+            .values()
+            .filter(|device| {
+                device.esp_id.to_lowercase().contains(&needle) || device.description.to_lowercase().contains(&needle)
+            })
+            .cloned()
+            .collect::<Vec<Device>>();
+        // Sorted so pages don't shift between requests
+        matched.sort_by(|a, b| a.esp_id.cmp(&b.esp_id));
+        matched
+    };
+    let total = devices_vec.len();
+    let views = {
+        let connections = store.active_connections.read_or_recover();
+        devices_vec
+            .iter()
+            .skip(query.offset.unwrap_or(0))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .map(|device| DeviceView::new(device, connections.contains_key(&device.esp_id)))
+            .collect::<Vec<_>>()
+    };
+    
+    info!("[Query] Returning device list, {} of {} devices", views.len(), total);
+    let body = if query.paginated() {
+        json!({ "total": total, "items": views })
+    } else {
+        json!(views)
+    };
+
+    if let (true, Some(callback)) = (config.jsonp, &query.callback) {
+        if !is_valid_callback(callback) {
+            warn!("[Query] Rejected invalid JSONP callback");
+            return Err(ApiError::BadRequest("Invalid callback name".to_string()));
+        }
+        let body = serde_json::to_string(&body).map_err(|e| {
+            error!("[Query] Failed to serialize device list: {}", e);
+            ApiError::Internal("Failed to get device list".to_string())
+        })?;
+        return Ok(HttpResponse::Ok()
+            .content_type("application/javascript")
+            .insert_header(("X-Content-Type-Options", "nosniff"))
+            .body(format!("/**/{}({});", callback, body)));
+    }
+    
+    Ok(HttpResponse::Ok().json(&body))
+}
+
+/// Send wake command to specified ESP8266
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip_all, fields(esp_id = %wake_req.esp_id))]
+async fn wake_device(
+    store: web::Data<DeviceStore>,
+    metrics: web::Data<Metrics>,
+    notifier: web::Data<Notifier>,
+    config: web::Data<Config>,
+    grace: web::Data<WakeGrace>,
+    history: web::Data<WakeHistory>,
+    req: HttpRequest,
+    query: web::Query<SimulateQuery>,
+    wake_req: web::Json<WakeRequest>,
+) -> Result<HttpResponse, ApiError> {
+    if let Some(mode) = &query.simulate {
+        return simulate_wake(&config, mode, &wake_req.esp_id);
+    }
+
+    info!("[Wake] Received wake request: ID={}", wake_req.esp_id);
+    
+    let started = Instant::now();
+    let device = store.find(&wake_req.esp_id);
+    let lookup = started.elapsed();
+    
+    // Name of the credential the request authenticated with, if not the main password
+    let mut credential = None;
+    let (response, timings, result) = match device {
+        Some(device) => {
+            // A dry run changes nothing, so it doesn't migrate a plaintext password either
+            let authenticated = if wake_req.dry_run {
+                let (checked, supplied) = (device.clone(), wake_req.password.clone());
+                web::block(move || checked.matching_credential(&supplied).map(|name| name.map(str::to_string)))
+                    .await
+                    .ok()
+                    .flatten()
+            } else {
+                store.authenticate_async(&device, &wake_req.password).await
+            };
+            credential = authenticated.clone().flatten();
+            if let Some(name) = &credential {
+                info!("[Wake] Authenticated with credential: ID={}, credential={}", device.esp_id, name);
+            }
+            if authenticated.is_none() {
+                warn!("[Wake] Password verification failed: ID={}", wake_req.esp_id);
+                (Err(ApiError::Unauthorized), None, Some("unauthorized"))
+            } else if wake_req.dry_run {
+                let via = wake_route(&store, &config, &device.esp_id).map(|route| match route {
+                    WakeRoute::Relay => "websocket",
+                    WakeRoute::Server => "udp",
+                });
+                let cooldown = store.cooldown_remaining(&device.esp_id, config.wake_cooldown);
+                info!("[Wake] Dry run: ID={}, via={}, cooldown={:?}", device.esp_id, via.unwrap_or("none"), cooldown);
+                // Nothing is sent, so nothing goes into the history
+                let response = match (via, cooldown) {
+                    (_, Some(wait)) => Err(ApiError::RateLimited(wait)),
+                    (Some(via), None) => Ok(HttpResponse::Ok().json(json!({ "would_wake": true, "via": via }))),
+                    (None, None) => Err(ApiError::Offline),
+                };
+                (response, None, None)
+            } else if wake_req.click_token.as_ref().is_some_and(|token| grace.is_duplicate(&device.esp_id, token)) {
+                info!("[Wake] Ignoring duplicate click: ID={}", device.esp_id);
+                // The first click is already in the history
+                (Ok(HttpResponse::Ok().json("Duplicate wake request ignored")), None, None)
+            } else {
+                let (outcome, timings, packets) = dispatch_wake_timed(&store, &metrics, &notifier, &config, &device).await;
+                let response = outcome.into_result().map(|_| {
+                    HttpResponse::Ok().json(json!({ "message": "Wake command sent", "packets": packets }))
+                });
+                (response, Some(timings), Some(outcome.as_str()))
+            }
+        },
+        None => {
+            warn!("[Wake] Device not found: ID={}", wake_req.esp_id);
+            (Err(ApiError::device_not_found()), None, Some("not_found"))
+        },
+    };
+    if let Some(result) = result {
+        history.record_attempt(&wake_source(&req, &config, "wake"), &wake_req.esp_id, result, credential);
+    }
+
+    // Errors are rendered here rather than returned so they get the timing header too
+    let mut response = response.unwrap_or_else(|e| e.error_response());
+    if config.server_timing {
+        let mut entries = vec![format!("lookup;dur={:.3}", lookup.as_secs_f64() * 1000.0)];
+        if let Some(timings) = timings {
+            entries.push(format!("dispatch;dur={:.3}", timings.dispatch.as_secs_f64() * 1000.0));
+            if let Some(ack_wait) = timings.ack_wait {
+                entries.push(format!("ack-wait;dur={:.3}", ack_wait.as_secs_f64() * 1000.0));
+            }
+        }
+        if let Ok(value) = HeaderValue::from_str(&entries.join(", ")) {
+            response.headers_mut().insert(HeaderName::from_static("server-timing"), value);
+        }
+    }
+    Ok(response)
+}
+
+/// The client behind `req`, for wakes it asks for through `via`
+fn wake_source(req: &HttpRequest, config: &Config, via: &'static str) -> WakeSource {
+    WakeSource { via, source_ip: allowlist::client_ip(req, config.trust_proxy).map(|ip| ip.to_string()) }
+}
+
+/// Result of dispatching a wake command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WakeOutcome {
+    /// Handed to the device's relay
+    Sent,
+    /// No relay is connected for the device
+    Offline,
+    /// The relay's mailbox rejected the command
+    SendFailed,
+    /// Every relay that took the command disconnected before acking it
+    RelayDropped,
+    /// Every relay still connected reported that it couldn't emit the magic packet
+    RelayFailed,
+    /// The device was woken within `WOL_WAKE_COOLDOWN_SECS`; nothing was sent
+    RateLimited(Duration),
+}
+
+impl WakeOutcome {
+    /// Short name used in notifications
+    fn as_str(self) -> &'static str {
+        match self {
+            WakeOutcome::Sent => "sent",
+            WakeOutcome::Offline => "offline",
+            WakeOutcome::SendFailed => "failed",
+            WakeOutcome::RelayDropped => "relay_dropped",
+            WakeOutcome::RelayFailed => "relay_failed",
+            WakeOutcome::RateLimited(_) => "rate_limited",
+        }
+    }
+
+    /// The success response for this outcome, or the error reported instead
+    fn into_result(self) -> Result<HttpResponse, ApiError> {
+        match self {
+            WakeOutcome::Sent => Ok(HttpResponse::Ok().json("Wake command sent")),
+            WakeOutcome::Offline => Err(ApiError::Offline),
+            WakeOutcome::SendFailed => Err(ApiError::Internal("Failed to send wake command".to_string())),
+            WakeOutcome::RelayDropped => Err(ApiError::BadGateway {
+                code: self.as_str(),
+                message: "Relay disconnected right after the wake command".to_string(),
+            }),
+            WakeOutcome::RelayFailed => Err(ApiError::BadGateway {
+                code: self.as_str(),
+                message: "Relay failed to send the magic packet".to_string(),
+            }),
+            WakeOutcome::RateLimited(wait) => Err(ApiError::RateLimited(wait)),
+        }
+    }
+
+    /// HTTP response reported to the caller for this outcome
+    fn response(self) -> HttpResponse {
+        self.into_result().unwrap_or_else(|e| e.error_response())
+    }
+}
+
+/// Delay between connection checks while verifying a wake was delivered
+const WAKE_VERIFY_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Where time went while dispatching a wake
+#[derive(Debug, Default, Clone, Copy)]
+struct WakeTimings {
+    /// Handing the command to the relays
+    dispatch: Duration,
+    /// Waiting on the relay during `WOL_WAKE_VERIFY_MS` verification
+    ack_wait: Option<Duration>,
+}
+
+/// Dispatch a wake command to the device's connected relay.
+///
+/// Every wake, whichever route or job asked for it, honours the device's
+/// `WOL_WAKE_COOLDOWN_SECS`; a wake that isn't sent doesn't start one.
+/// With `WOL_WAKE_VERIFY_MS` set, success is only reported once a relay that
+/// took the command acks it or is still connected when the window ends.
+async fn dispatch_wake(
+    store: &DeviceStore,
+    metrics: &Metrics,
+    notifier: &Notifier,
+    config: &Config,
+    device: &Device,
+) -> WakeOutcome {
+    dispatch_wake_timed(store, metrics, notifier, config, device).await.0
+}
+
+/// `dispatch_wake`, also reporting how long each step took and how many magic
+/// packets were sent by the server or requested of the relays
+async fn dispatch_wake_timed(
+    store: &DeviceStore,
+    metrics: &Metrics,
+    notifier: &Notifier,
+    config: &Config,
+    device: &Device,
+) -> (WakeOutcome, WakeTimings, u32) {
+    let started = Instant::now();
+    let mut timings = WakeTimings::default();
+    // Claimed before dispatching so concurrent requests can't all get through
+    let previous = match store.claim_wake(&device.esp_id, config.wake_cooldown) {
+        Ok(previous) => previous,
+        Err(wait) => {
+            warn!("[Wake] Rate limited: ID={}, retry_after={:?}", device.esp_id, wait);
+            return (WakeOutcome::RateLimited(wait), timings, 0);
+        },
+    };
+    let connections = store.relay_connections(&device.esp_id);
+    let mut packets = 0;
+    
+    let outcome = if connections.is_empty() && config.udp_fallback {
+        // Covers machines on the server's own LAN that have no relay
+        let target = server_wake_target(config, device).await;
+        match &target {
+            Ok(target) => {
+                for mac in &device.mac_addresses {
+                    match wol::broadcast_magic_packet(mac, *target, config.wake_repeat, config.wake_interval).await {
+                        Ok(sent) => packets += sent,
+                        Err(e) => error!("[Wake] Failed to broadcast magic packet: ID={}, MAC={}, error={}", device.esp_id, mac, e),
+                    }
+                }
+            },
+            Err(e) => error!("[Wake] Failed to resolve wake_target: ID={}, error={}", device.esp_id, e),
+        }
+        timings.dispatch = started.elapsed();
+        if let (Ok(target), true) = (target, packets > 0) {
+            info!(
+                "[Wake] No relay connected, sent magic packet from server: ID={}, target={}, packets={}",
+                device.esp_id, target, packets
+            );
+            WakeOutcome::Sent
+        } else {
+            WakeOutcome::SendFailed
+        }
+    } else if connections.is_empty() {
+        warn!("[Wake] Device offline: ID={}", device.esp_id);
+        WakeOutcome::Offline
+    } else {
+        match wake_messages(device, config) {
+            Err(e) => {
+                error!("[Wake] Cannot build wake command: ID={}, error={}", device.esp_id, e);
+                WakeOutcome::SendFailed
+            },
+            Ok(messages) => {
+                let sent_at = Instant::now();
+                // Every relay holding this esp_id gets one command per MAC; one delivery is enough
+                let mut delivered = Vec::new();
+                for (id, addr) in connections {
+                    let mut accepted = false;
+                    for message in &messages {
+                        if send_frame(&addr, &device.esp_id, message) {
+                            accepted = true;
+                            packets += config.wake_repeat;
+                        }
+                    }
+                    if accepted {
+                        delivered.push(id);
+                    }
+                }
+
+                if !delivered.is_empty() {
+                    let mut connections = store.active_connections.write_or_recover();
+                    let conns = connections.get_mut(&device.esp_id).into_iter().flatten();
+                    for conn in conns.filter(|conn| delivered.contains(&conn.id)) {
+                        conn.wake_sent_at = Some(sent_at);
+                    }
+                }
+
+                timings.dispatch = started.elapsed();
+
+                if delivered.is_empty() {
+                    WakeOutcome::SendFailed
+                } else if let Some(window) = config.wake_verify {
+                    let verified = verify_delivery(store, &device.esp_id, &delivered, sent_at, window).await;
+                    timings.ack_wait = Some(sent_at.elapsed().saturating_sub(timings.dispatch));
+                    verified
+                } else {
+                    WakeOutcome::Sent
+                }
+            },
+        }
+    };
+
+    if outcome == WakeOutcome::Sent {
+        info!("[Wake] Wake command sent successfully: ID={}, MAC={}", device.esp_id, device.mac_addresses.join(","));
+        if let Some(note) = &device.wake_note {
+            info!("[Wake] Note: ID={}, note={}", device.esp_id, note);
+        }
+        metrics.record_wake(&device.esp_id, true);
+        record_wake_success(store, &device.esp_id);
+        store.publish_device(&device.esp_id);
+    } else {
+        if outcome == WakeOutcome::RelayDropped {
+            warn!("[Wake] Relay disconnected right after the wake command: ID={}", device.esp_id);
+        }
+        store.release_wake(&device.esp_id, previous);
+        metrics.record_wake(&device.esp_id, false);
+    }
+
+    metrics.record_event(&device.esp_id, "wake", Some(outcome.as_str().to_string()));
+    let event = json!({
+        "esp_id": device.esp_id,
+        "mac": device.primary_mac(),
+        "mac_addresses": device.mac_addresses,
+        "result": outcome.as_str(),
+        "wake_note": device.wake_note,
+        "tags": device.tags,
+    });
+    store.mqtt.publish_wake(&device.esp_id, &event);
+    store.live.wake(&device.esp_id, outcome.as_str());
+    notifier.notify_tagged("wake", event, &device.tags);
+    (outcome, timings, packets)
+}
+
+/// How a wake is delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WakeRoute {
+    /// Through the device's connected relays
+    Relay,
+    /// As magic packets sent by the server itself (`WOL_UDP_FALLBACK`)
+    Server,
+}
+
+/// The route `dispatch_wake_timed` would take for `esp_id` right now, which
+/// prefers a relay over the server's own broadcast; `None` when it is offline
+fn wake_route(store: &DeviceStore, config: &Config, esp_id: &str) -> Option<WakeRoute> {
+    if store.is_online(esp_id) {
+        Some(WakeRoute::Relay)
+    } else {
+        config.udp_fallback.then_some(WakeRoute::Server)
+    }
+}
+
+/// Where the server sends `device`'s magic packets itself: its `wake_target`,
+/// or `WOL_UDP_BROADCAST`, on its `wake_port` unless the target names one
+async fn server_wake_target(config: &Config, device: &Device) -> std::io::Result<SocketAddr> {
+    let port = device.wake_port.unwrap_or(wol::DEFAULT_PORT);
+    match &device.wake_target {
+        Some(spec) => wol::resolve_target(spec, port).await,
+        None => Ok(SocketAddr::from((config.udp_broadcast, port))),
+    }
+}
+
+/// Watch the relays that took a wake command for up to `window`.
+///
+/// Sent as soon as one of them acks, RelayDropped as soon as all of them are
+/// gone, and Sent if any is still connected at the end.
+async fn verify_delivery(
+    store: &DeviceStore,
+    esp_id: &str,
+    delivered: &[u64],
+    sent_at: Instant,
+    window: Duration,
+) -> WakeOutcome {
+    loop {
+        {
+            let connections = store.active_connections.read_or_recover();
+            let remaining = connections
+                .get(esp_id)
+                .into_iter()
+                .flatten()
+                .filter(|conn| delivered.contains(&conn.id))
+                .collect::<Vec<_>>();
+            if remaining.is_empty() {
+                return WakeOutcome::RelayDropped;
+            }
+            if remaining.iter().any(|conn| conn.acked_at.is_some_and(|acked| acked >= sent_at)) {
+                return WakeOutcome::Sent;
+            }
+            if remaining.iter().all(|conn| conn.wake_failed_at.is_some_and(|failed| failed >= sent_at)) {
+                return WakeOutcome::RelayFailed;
+            }
+        }
+        if sent_at.elapsed() >= window {
+            return WakeOutcome::Sent;
+        }
+        tokio::time::sleep(WAKE_VERIFY_POLL_INTERVAL).await;
+    }
+}
+
+/// Wake query options
+#[derive(Deserialize)]
+struct SimulateQuery {
+    /// Failure mode to fake instead of waking (dev mode only)
+    simulate: Option<String>,
+}
+
+/// Answer a wake request with a canned failure and no side effects, for UI testing
+fn simulate_wake(config: &Config, mode: &str, esp_id: &str) -> Result<HttpResponse, ApiError> {
+    if !config.dev_mode {
+        return Err(ApiError::Forbidden("Wake simulation requires WOL_DEV_MODE".to_string()));
+    }
+
+    info!("[Dev] Simulating wake result: ID={}, simulate={}", esp_id, mode);
+    match mode {
+        "offline" => WakeOutcome::Offline.into_result(),
+        "unauthorized" => Err(ApiError::Unauthorized),
+        "not_found" => Err(ApiError::device_not_found()),
+        "error" => WakeOutcome::SendFailed.into_result(),
+        _ => Err(ApiError::BadRequest("simulate must be offline, unauthorized, not_found or error".to_string())),
+    }
+}
+
+/// Batch wake request
+#[derive(Deserialize)]
+struct WakeBatchRequest {
+    devices: Vec<WakeRequest>,
+    /// Delay between consecutive wakes, defaults to `WOL_WAKE_STAGGER_MS`
+    stagger_ms: Option<u64>,
+}
+
+/// Accepted `--wake-repeat` values
+const WAKE_REPEAT_RANGE: std::ops::RangeInclusive<u32> = 1..=20;
+
+/// Upper bound on `--wake-interval-ms`, so a wake can't stall for long
+const MAX_WAKE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Upper bound on a caller-supplied stagger delay
+const MAX_WAKE_STAGGER: Duration = Duration::from_secs(60);
+
+/// Upper bound on devices in one `/wake-batch` request, since each costs a password hash check
+const MAX_BATCH_DEVICES: usize = 50;
+
+/// Wake devices one after another, pausing `stagger` between dispatches so
+/// their power-on surges don't coincide
+async fn wake_in_sequence(
+    store: &DeviceStore,
+    metrics: &Metrics,
+    notifier: &Notifier,
+    config: &Config,
+    devices: &[Device],
+    stagger: Duration,
+) -> Vec<(String, WakeOutcome)> {
+    let mut outcomes = Vec::with_capacity(devices.len());
+    for (index, device) in devices.iter().enumerate() {
+        if index > 0 && !stagger.is_zero() {
+            tokio::time::sleep(stagger).await;
+        }
+        outcomes.push((device.esp_id.clone(), dispatch_wake(store, metrics, notifier, config, device).await));
+    }
+    outcomes
+}
+
+/// Wake several devices in one request, staggered by the configured delay
+#[allow(clippy::too_many_arguments)]
+async fn wake_batch(
+    store: web::Data<DeviceStore>,
+    metrics: web::Data<Metrics>,
+    notifier: web::Data<Notifier>,
+    config: web::Data<Config>,
+    history: web::Data<WakeHistory>,
+    req: HttpRequest,
+    batch_req: web::Json<WakeBatchRequest>,
+) -> impl Responder {
+    info!("[Wake] Received batch wake request: {} device(s)", batch_req.devices.len());
+    if batch_req.devices.len() > MAX_BATCH_DEVICES {
+        return ApiError::BadRequest(format!("At most {} devices per request", MAX_BATCH_DEVICES)).error_response();
+    }
+
+    let members = batch_req.devices.iter().map(|member| (member.esp_id.as_str(), member.password.as_str()));
+    let stagger = batch_req
+        .stagger_ms
+        .map(Duration::from_millis)
+        .unwrap_or(config.wake_stagger)
+        .min(MAX_WAKE_STAGGER);
+    let source = wake_source(&req, &config, "wake-batch");
+    let (all_sent, body) = wake_members(&store, &metrics, &notifier, &config, &history, &source, members, stagger).await;
+    if all_sent {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::MultiStatus().json(body)
+    }
+}
+
+/// Wake each `(esp_id, password)` member in order, reporting a result per member.
+///
+/// Every member is authenticated up front so rejected ones don't consume a
+/// stagger slot. Each result is recorded in the history as coming from the
+/// given source. Returns whether all of them were sent, and the response body.
+#[allow(clippy::too_many_arguments)]
+async fn wake_members<'a>(
+    store: &DeviceStore,
+    metrics: &Metrics,
+    notifier: &Notifier,
+    config: &Config,
+    history: &WakeHistory,
+    source: &WakeSource,
+    members: impl Iterator<Item = (&'a str, &'a str)>,
+    stagger: Duration,
+) -> (bool, serde_json::Value) {
+    let mut rejections = Vec::new();
+    let mut targets = Vec::new();
+    for (esp_id, password) in members {
+        let rejection = match store.find(esp_id) {
+            Some(device) if store.check_password_async(&device, password).await => {
+                targets.push(device);
+                None
+            },
+            Some(_) => {
+                warn!("[Wake] Password verification failed: ID={}", esp_id);
+                Some("unauthorized")
+            },
+            None => {
+                warn!("[Wake] Device not found: ID={}", esp_id);
+                Some("not_found")
+            },
+        };
+        rejections.push((esp_id, rejection));
+    }
+
+    let started = Instant::now();
+    let outcomes = wake_in_sequence(store, metrics, notifier, config, &targets, stagger).await;
+    let elapsed = started.elapsed();
+
+    let all_sent = rejections.iter().all(|(_, rejection)| rejection.is_none())
+        && outcomes.iter().all(|(_, outcome)| *outcome == WakeOutcome::Sent);
+    // Report in request order
+    let mut outcomes = outcomes.into_iter();
+    let results = rejections
+        .into_iter()
+        .map(|(esp_id, rejection)| {
+            let result = match rejection {
+                Some(rejection) => rejection,
+                None => outcomes.next().map_or("failed", |(_, outcome)| outcome.as_str()),
+            };
+            history.record_attempt(source, esp_id, result, None);
+            json!({ "esp_id": esp_id, "result": result })
+        })
+        .collect::<Vec<_>>();
+
+    let body = json!({
+        "results": results,
+        "stagger_ms": stagger.as_millis() as u64,
+        "elapsed_ms": elapsed.as_millis() as u64,
+    });
+    (all_sent, body)
+}
+
+/// Request to create or replace a device group
+#[derive(Deserialize)]
+struct GroupRequest {
+    name: String,
+    /// esp_ids (or device `id`s) in wake order
+    members: Vec<String>,
+}
+
+/// Create or replace a device group (admin)
+async fn create_group(
+    _admin: AdminAuth,
+    store: web::Data<DeviceStore>,
+    group_req: web::Json<GroupRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let GroupRequest { name, members } = group_req.into_inner();
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err(ApiError::BadRequest("Group name must not be empty".to_string()));
+    }
+
+    let (esp_ids, unknown) = {
+        let devices = store.devices.read_or_recover();
+        let mut esp_ids = Vec::new();
+        let mut unknown = Vec::new();
+        for member in &members {
+            match find_device(&devices, member) {
+                Some(device) if esp_ids.contains(&device.esp_id) => {},
+                Some(device) => esp_ids.push(device.esp_id.clone()),
+                None => unknown.push(member.as_str()),
+            }
+        }
+        (esp_ids, unknown)
+    };
+    if !unknown.is_empty() {
+        return Err(ApiError::BadRequest(format!("Unknown devices: {}", unknown.join(", "))));
+    }
+    if esp_ids.is_empty() {
+        return Err(ApiError::BadRequest("A group needs at least one member".to_string()));
+    }
+
+    let replaced = store.groups.write_or_recover().insert(name.clone(), esp_ids.clone()).is_some();
+    DeviceStore::persist(&store, DeviceStore::save_groups).await.map_err(|e| {
+        error!("[Group] Failed to save groups: {}", e);
+        ApiError::Internal(e.to_string())
+    })?;
+    info!("[Group] {} group: name={}, members={}", if replaced { "Replaced" } else { "Created" }, name, esp_ids.len());
+    Ok(HttpResponse::Ok().json(json!({ "name": name, "members": esp_ids })))
+}
+
+/// List device groups by name (admin)
+async fn list_groups(_admin: AdminAuth, store: web::Data<DeviceStore>) -> impl Responder {
+    let groups = store.groups.read_or_recover();
+    let mut listed = groups
+        .iter()
+        .map(|(name, members)| json!({ "name": name, "members": members }))
+        .collect::<Vec<_>>();
+    listed.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+    HttpResponse::Ok().json(listed)
+}
+
+/// Delete a device group, leaving its devices registered (admin)
+async fn delete_group(
+    _admin: AdminAuth,
+    store: web::Data<DeviceStore>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    if store.groups.write_or_recover().remove(name.as_str()).is_none() {
+        return Err(ApiError::NotFound("Group not found".to_string()));
+    }
+    DeviceStore::persist(&store, DeviceStore::save_groups).await.map_err(|e| {
+        error!("[Group] Failed to save groups: {}", e);
+        ApiError::Internal(e.to_string())
+    })?;
+    info!("[Group] Deleted group: name={}", name);
+    Ok(HttpResponse::Ok().json(json!({ "name": name.as_str() })))
+}
+
+/// Group wake request
+#[derive(Deserialize)]
+struct WakeGroupRequest {
+    group: String,
+    /// Checked against each member; members it doesn't match are reported `unauthorized`
+    password: String,
+    /// Delay between consecutive wakes, defaults to `WOL_WAKE_STAGGER_MS`
+    stagger_ms: Option<u64>,
+}
+
+/// Wake every member of a group as `/wake-batch` would.
+///
+/// Answers 200 when every member was sent a wake and 207 otherwise, with the
+/// result of each member (`sent`, `offline`, `unauthorized`, ...) listed in
+/// group order.
+#[allow(clippy::too_many_arguments)]
+async fn wake_group(
+    store: web::Data<DeviceStore>,
+    metrics: web::Data<Metrics>,
+    notifier: web::Data<Notifier>,
+    config: web::Data<Config>,
+    history: web::Data<WakeHistory>,
+    req: HttpRequest,
+    group_req: web::Json<WakeGroupRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let Some(members) = store.groups.read_or_recover().get(&group_req.group).cloned() else {
+        warn!("[Wake] Group not found: group={}", group_req.group);
+        return Err(ApiError::NotFound("Group not found".to_string()));
+    };
+    info!("[Wake] Received group wake request: group={}, {} device(s)", group_req.group, members.len());
+
+    let stagger = group_req
+        .stagger_ms
+        .map(Duration::from_millis)
+        .unwrap_or(config.wake_stagger)
+        .min(MAX_WAKE_STAGGER);
+    let members_iter = members.iter().map(|esp_id| (esp_id.as_str(), group_req.password.as_str()));
+    let source = wake_source(&req, &config, "wake-group");
+    let (all_sent, mut body) =
+        wake_members(&store, &metrics, &notifier, &config, &history, &source, members_iter, stagger).await;
+    body["group"] = json!(group_req.group);
+    if all_sent {
+        Ok(HttpResponse::Ok().json(body))
+    } else {
+        Ok(HttpResponse::MultiStatus().json(body))
+    }
+}
+
+/// Wake-by-MAC request
+#[derive(Deserialize)]
+struct WakeByMacRequest {
+    mac_address: String,
+    password: String,
+}
+
+/// Registered devices whose MAC matches `mac`, ordered by esp_id
+fn devices_with_mac(store: &DeviceStore, mac: &[u8; 6]) -> Vec<Device> {
+    let mut matches = store
+        .devices
+        .read_or_recover()
+        .values()
+        .filter(|device| {
+            device.mac_addresses.iter().any(|device_mac| wol::parse_mac(device_mac).is_ok_and(|device_mac| device_mac == *mac))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    matches.sort_by(|a, b| a.esp_id.cmp(&b.esp_id));
+    matches
+}
+
+/// The devices `policy` wakes through out of `candidates`, or `None` when it
+/// refuses an ambiguous MAC
+fn resolve_mac_targets(store: &DeviceStore, policy: MacAmbiguity, candidates: &[Device]) -> Option<Vec<Device>> {
+    if candidates.len() <= 1 {
+        return Some(candidates.to_vec());
+    }
+    match policy {
+        MacAmbiguity::WakeAll => Some(candidates.to_vec()),
+        // With nothing online, dispatching to the first candidate reports it offline
+        MacAmbiguity::FirstOnline => Some(vec![candidates
+            .iter()
+            .find(|device| store.is_online(&device.esp_id))
+            .unwrap_or(&candidates[0])
+            .clone()]),
+        MacAmbiguity::Reject => None,
+    }
+}
+
+/// Wake a machine by MAC address rather than esp_id.
+///
+/// Only devices whose password matches are considered; when several remain,
+/// `WOL_MAC_AMBIGUITY` decides which of them are woken.
+#[allow(clippy::too_many_arguments)]
+async fn wake_by_mac(
+    store: web::Data<DeviceStore>,
+    metrics: web::Data<Metrics>,
+    notifier: web::Data<Notifier>,
+    config: web::Data<Config>,
+    history: web::Data<WakeHistory>,
+    req: HttpRequest,
+    wake_req: web::Json<WakeByMacRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let Ok(mac) = wol::parse_mac(&wake_req.mac_address) else {
+        return Err(ApiError::BadRequest("Invalid MAC address".to_string()));
+    };
+    let mac_text = wol::format_mac(&mac);
+    info!("[Wake] Received wake-by-mac request: MAC={}", mac_text);
+
+    let matching = devices_with_mac(&store, &mac);
+    if matching.is_empty() {
+        warn!("[Wake] No device with MAC: MAC={}", mac_text);
+        return Err(ApiError::device_not_found());
+    }
+    let source = wake_source(&req, &config, "wake-by-mac");
+    let mut candidates = Vec::with_capacity(matching.len());
+    for device in matching {
+        if store.check_password_async(&device, &wake_req.password).await {
+            candidates.push(device);
+        } else {
+            history.record_attempt(&source, &device.esp_id, "unauthorized", None);
+        }
+    }
+    if candidates.is_empty() {
+        warn!("[Wake] Password verification failed: MAC={}", mac_text);
+        return Err(ApiError::Unauthorized);
+    }
+
+    let policy = config.mac_ambiguity;
+    let Some(targets) = resolve_mac_targets(&store, policy, &candidates) else {
+        warn!("[Wake] Refusing ambiguous MAC: MAC={}, candidates={}", mac_text, candidates.len());
+        return Ok(HttpResponse::Conflict().json(json!({
+            "error": "ambiguous_mac",
+            "message": "MAC address belongs to several devices",
+            "mac": mac_text,
+            "policy": policy.as_str(),
+            "candidates": candidates.iter().map(|device| &device.esp_id).collect::<Vec<_>>(),
+        })));
+    };
+
+    let outcomes = wake_in_sequence(&store, &metrics, &notifier, &config, &targets, config.wake_stagger).await;
+    for (esp_id, outcome) in &outcomes {
+        history.record_attempt(&source, esp_id, outcome.as_str(), None);
+    }
+    let all_sent = outcomes.iter().all(|(_, outcome)| *outcome == WakeOutcome::Sent);
+    let body = json!({
+        "mac": mac_text,
+        "policy": policy.as_str(),
+        "ambiguous": candidates.len() > 1,
+        "results": outcomes
+            .iter()
+            .map(|(esp_id, outcome)| json!({ "esp_id": esp_id, "result": outcome.as_str() }))
+            .collect::<Vec<_>>(),
+    });
+    Ok(match outcomes.as_slice() {
+        _ if all_sent => HttpResponse::Ok().json(body),
+        [(_, outcome)] => {
+            let status = outcome.response().status();
+            HttpResponse::build(status).json(body)
+        },
+        _ => HttpResponse::MultiStatus().json(body),
+    })
+}
+
+/// Show how `/wake-by-mac` would resolve a MAC right now, without waking anything.
+///
+/// Passwords aren't checked here, so every device registered with the MAC is
+/// listed; an actual wake only considers those whose password matches.
+async fn get_mac_routing(
+    store: web::Data<DeviceStore>,
+    config: web::Data<Config>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let Ok(mac) = wol::parse_mac(&path) else {
+        return Err(ApiError::BadRequest("Invalid MAC address".to_string()));
+    };
+
+    let candidates = devices_with_mac(&store, &mac);
+    let policy = config.mac_ambiguity;
+    let targets = resolve_mac_targets(&store, policy, &candidates);
+    Ok(HttpResponse::Ok().json(json!({
+        "mac": wol::format_mac(&mac),
+        "policy": policy.as_str(),
+        "ambiguous": candidates.len() > 1,
+        "candidates": candidates
+            .iter()
+            .map(|device| json!({ "esp_id": device.esp_id, "online": store.is_online(&device.esp_id) }))
+            .collect::<Vec<_>>(),
+        // null when the policy would refuse the request
+        "targets": targets.map(|targets| targets.into_iter().map(|device| device.esp_id).collect::<Vec<_>>()),
+    })))
+}
+
+/// Wake-and-wait request
+#[derive(Deserialize)]
+struct WakeAndWaitRequest {
+    esp_id: String,
+    password: String,
+    /// How long to wait for the machine, defaults to `WOL_WAKE_WAIT_TIMEOUT_SECS`
+    timeout_secs: Option<u64>,
+}
+
+/// Upper bound on a caller-supplied wait
+const MAX_WAKE_WAIT: Duration = Duration::from_secs(600);
+
+/// Delay between reachability probes while waiting for a machine to boot
+const WAKE_WAIT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Wake a device, then poll its `health_url` (or TCP on its IP) until it answers or the wait times out
+#[tracing::instrument(skip_all, fields(esp_id = %wait_req.esp_id))]
+#[allow(clippy::too_many_arguments)]
+async fn wake_and_wait(
+    store: web::Data<DeviceStore>,
+    metrics: web::Data<Metrics>,
+    notifier: web::Data<Notifier>,
+    config: web::Data<Config>,
+    history: web::Data<WakeHistory>,
+    req: HttpRequest,
+    wait_req: web::Json<WakeAndWaitRequest>,
+) -> Result<HttpResponse, ApiError> {
+    info!("[Wake] Received wake-and-wait request: ID={}", wait_req.esp_id);
+
+    let source = wake_source(&req, &config, "wake-and-wait");
+    let device = store.find(&wait_req.esp_id);
+    let Some(device) = device else {
+        warn!("[Wake] Device not found: ID={}", wait_req.esp_id);
+        history.record_attempt(&source, &wait_req.esp_id, "not_found", None);
+        return Err(ApiError::device_not_found());
+    };
+    if !store.check_password_async(&device, &wait_req.password).await {
+        warn!("[Wake] Password verification failed: ID={}", wait_req.esp_id);
+        history.record_attempt(&source, &device.esp_id, "unauthorized", None);
+        return Err(ApiError::Unauthorized);
+    }
+    if device.health_url.is_none() && device.ip_address.is_none() {
+        return Err(ApiError::BadRequest("Device has no health_url or ip_address to wait on".to_string()));
+    }
+
+    let outcome = dispatch_wake(&store, &metrics, &notifier, &config, &device).await;
+    history.record_attempt(&source, &device.esp_id, outcome.as_str(), None);
+    outcome.into_result()?;
+
+    let wait = wait_req
+        .timeout_secs
+        .map(Duration::from_secs)
+        .unwrap_or(config.wake_wait_timeout)
+        .min(MAX_WAKE_WAIT);
+    let started = Instant::now();
+
+    loop {
+        let up = match (&device.health_url, &device.ip_address) {
+            (Some(url), _) => probe::is_healthy(url, device.health_status, config.health_url_timeout).await,
+            (None, Some(ip)) => probe::is_reachable(ip, config.probe_port, config.probe_timeout).await,
+            (None, None) => false,
+        };
+        if up {
+            let elapsed = started.elapsed();
+            info!("[Wake] Device came online after {:?}: ID={}", elapsed, device.esp_id);
+            return Ok(HttpResponse::Ok().json(json!({
+                "reachable": true,
+                "check": if device.health_url.is_some() { "http" } else { "tcp" },
+                "time_to_online_ms": elapsed.as_millis() as u64,
+            })));
+        }
+        if started.elapsed() >= wait {
+            warn!("[Wake] Device did not come online within {:?}: ID={}", wait, device.esp_id);
+            return Ok(HttpResponse::GatewayTimeout().json(json!({
+                "reachable": false,
+                "waited_ms": started.elapsed().as_millis() as u64,
+            })));
+        }
+        tokio::time::sleep(WAKE_WAIT_POLL_INTERVAL).await;
+    }
+}
+
+/// Reachability probe request
+#[derive(Deserialize)]
+struct PingRequest {
+    password: String,
+    /// TCP port to probe instead of `WOL_PROBE_PORT`, e.g. 3389 for RDP
+    port: Option<u16>,
+}
+
+/// Check once whether a device's machine accepts TCP connections, e.g. after a wake
+#[tracing::instrument(skip_all, fields(esp_id = %esp_id))]
+async fn ping_device(
+    store: web::Data<DeviceStore>,
+    config: web::Data<Config>,
+    esp_id: web::Path<String>,
+    ping_req: web::Json<PingRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let device = store.find(&esp_id).ok_or_else(|| {
+        warn!("[Ping] Device not found: ID={}", esp_id);
+        ApiError::device_not_found()
+    })?;
+    if !store.check_password_async(&device, &ping_req.password).await {
+        warn!("[Ping] Password verification failed: ID={}", esp_id);
+        return Err(ApiError::Unauthorized);
+    }
+    let host = device
+        .ip_address
+        .as_deref()
+        .ok_or_else(|| ApiError::BadRequest("Device has no ip_address to probe".to_string()))?;
+    let port = ping_req.port.unwrap_or(config.probe_port);
+
+    let body = |reachable: bool, latency: Option<Duration>| {
+        json!({
+            "esp_id": device.esp_id,
+            "host": host,
+            "port": port,
+            "reachable": reachable,
+            "latency_ms": latency.map(|latency| (latency.as_secs_f64() * 1_000_000.0).round() / 1000.0),
+        })
+    };
+    Ok(match probe::connect(host, port, config.probe_timeout).await {
+        Probe::Connected(latency) => {
+            info!("[Ping] Reachable: ID={}, target={}:{}, latency={:?}", device.esp_id, host, port, latency);
+            HttpResponse::Ok().json(body(true, Some(latency)))
+        },
+        Probe::Failed(e) => {
+            info!("[Ping] Unreachable: ID={}, target={}:{}, error={}", device.esp_id, host, port, e);
+            let mut body = body(false, None);
+            body["error"] = json!(e.to_string());
+            HttpResponse::Ok().json(body)
+        },
+        Probe::TimedOut => {
+            info!("[Ping] Timed out after {:?}: ID={}, target={}:{}", config.probe_timeout, device.esp_id, host, port);
+            let mut body = body(false, None);
+            body["timeout_ms"] = json!(config.probe_timeout.as_millis() as u64);
+            HttpResponse::GatewayTimeout().json(body)
+        },
+    })
+}
+
+/// Build the wake commands sent to a device's relay, one per MAC, passing on
+/// the configured repeat count and spacing.
+///
+/// Fails only for a MAC that doesn't parse, as in files saved before MACs were validated.
+fn wake_messages(device: &Device, config: &Config) -> Result<Vec<String>, commands::CommandError> {
+    device
+        .mac_addresses
+        .iter()
+        .map(|mac| {
+            let params = commands::Params::from_iter([
+                ("mac_address".to_string(), json!(mac)),
+                ("repeat".to_string(), json!(config.wake_repeat)),
+                ("interval_ms".to_string(), json!(config.wake_interval.as_millis() as u64)),
+            ]);
+            commands::wake_frame(device, &params)
+        })
+        .collect()
+}
+
+/// Remember the intended power state of a device
+fn record_power_state(store: &DeviceStore, esp_id: &str, state: PowerState) {
+    {
+        let mut devices = store.devices.write_or_recover();
+        match devices.get_mut(esp_id) {
+            Some(device) => device.power_state = Some(state),
+            None => return,
+        }
+    }
+
+    store.save_later();
+}
+
+/// Count a successful wake and note that the machine is now meant to be on
+fn record_wake_success(store: &DeviceStore, esp_id: &str) {
+    {
+        let mut devices = store.devices.write_or_recover();
+        let Some(device) = devices.get_mut(esp_id) else {
+            return;
+        };
+        device.power_state = Some(PowerState::On);
+        device.wake_count += 1;
+        device.last_wake = Some(Utc::now());
+    }
+
+    store.save_later();
+}
+
+/// Record that a device was deliberately switched on or off
+#[tracing::instrument(skip_all, fields(esp_id = %power_req.esp_id))]
+async fn set_power_state(
+    store: web::Data<DeviceStore>,
+    power_req: web::Json<PowerStateRequest>,
+) -> Result<HttpResponse, ApiError> {
+    info!("[Power] Received power state update: ID={}, state={:?}", power_req.esp_id, power_req.state);
+
+    let device = store.find(&power_req.esp_id);
+    let authorized = match &device {
+        Some(device) => store.check_password_async(device, &power_req.password).await,
+        None => false,
+    };
+    match device {
+        Some(_) if !authorized => {
+            warn!("[Power] Password verification failed: ID={}", power_req.esp_id);
+            Err(ApiError::Unauthorized)
+        },
+        Some(device) => {
+            record_power_state(&store, &device.esp_id, power_req.state);
+            Ok(HttpResponse::Ok().json("Power state updated"))
+        },
+        None => {
+            warn!("[Power] Device not found: ID={}", power_req.esp_id);
+            Err(ApiError::device_not_found())
+        },
+    }
+}
+
+/// Delay between pong checks while a health check waits on relays
+const HEALTH_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Report liveness, optionally pinging relays per `WOL_HEALTH_RELAY_CHECK`.
+///
+/// Unresponsive relays turn the status to `degraded` with a 503.
+async fn health(store: web::Data<DeviceStore>, config: web::Data<Config>) -> impl Responder {
+    let (connected_devices, mut targets) = {
+        let connections = store.active_connections.read_or_recover();
+        let targets = connections
+            .iter()
+            .flat_map(|(esp_id, conns)| conns.iter().map(move |conn| (esp_id.clone(), conn.id, conn.addr.clone())))
+            .collect::<Vec<_>>();
+        (connections.len(), targets)
+    };
+    let mut body = json!({
+        "status": "ok",
+        "uptime_secs": store.started_at.elapsed().as_secs(),
+        "connected_devices": connected_devices,
+    });
+
+    match config.health_relay_check {
+        HealthRelayCheck::Off => return HttpResponse::Ok().json(body),
+        HealthRelayCheck::One if !targets.is_empty() => {
+            let pick = rand::rng().random_range(0..targets.len());
+            targets = vec![targets.swap_remove(pick)];
+        },
+        HealthRelayCheck::One | HealthRelayCheck::All => (),
+    }
+
+    let sent_at = Instant::now();
+    for (_, _, addr) in &targets {
+        addr.do_send(PingRelay);
+    }
+    let mut answered = HashMap::new();
+    loop {
+        {
+            let connections = store.active_connections.read_or_recover();
+            for (esp_id, id, _) in &targets {
+                let ponged = connections
+                    .get(esp_id)
+                    .into_iter()
+                    .flatten()
+                    .find(|conn| conn.id == *id)
+                    .and_then(|conn| conn.ponged_at)
+                    .filter(|ponged| *ponged >= sent_at);
+                if let Some(ponged) = ponged {
+                    answered.entry(*id).or_insert(ponged.duration_since(sent_at));
+                }
+            }
+        }
+        if answered.len() == targets.len() || sent_at.elapsed() >= config.health_ping_timeout {
+            break;
+        }
+        tokio::time::sleep(HEALTH_POLL_INTERVAL).await;
+    }
+
+    let relays = targets
+        .iter()
+        .map(|(esp_id, id, _)| json!({
+            "esp_id": esp_id,
+            "connection_id": id,
+            "responsive": answered.contains_key(id),
+            "rtt_ms": answered.get(id).map(|rtt| rtt.as_millis() as u64),
+        }))
+        .collect::<Vec<_>>();
+    body["relays"] = json!(relays);
+    if answered.len() == targets.len() {
+        HttpResponse::Ok().json(body)
+    } else {
+        warn!("[Health] {} of {} relays did not answer a ping", targets.len() - answered.len(), targets.len());
+        body["status"] = json!("degraded");
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+/// Readiness probe: 200 while serving requests, 503 before the devices are
+/// loaded and once shutdown has begun. Reads a single flag, so it stays cheap.
+async fn ready(store: web::Data<DeviceStore>) -> impl Responder {
+    if store.ready.load(Ordering::Relaxed) {
+        HttpResponse::Ok().json(json!({ "status": "ready" }))
+    } else {
+        HttpResponse::ServiceUnavailable().json(json!({ "status": "not_ready" }))
+    }
+}
+
+/// Request to issue a one-time wake link
+#[derive(Deserialize)]
+struct CreateLinkRequest {
+    esp_id: String,
+    password: String,
+    /// Link lifetime, defaults to `WOL_LINK_TTL_SECS`
+    ttl_secs: Option<u64>,
+}
+
+/// Longest lifetime a caller may ask for a wake link
+const MAX_LINK_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Issue a signed one-time wake link for a device
+#[tracing::instrument(skip_all, fields(esp_id = %link_req.esp_id))]
+async fn create_link(
+    store: web::Data<DeviceStore>,
+    links: web::Data<WakeLinks>,
+    config: web::Data<Config>,
+    link_req: web::Json<CreateLinkRequest>,
+) -> Result<HttpResponse, ApiError> {
+    info!("[Link] Received link request: ID={}", link_req.esp_id);
+    if link_req.ttl_secs.is_some_and(|secs| secs == 0 || secs > MAX_LINK_TTL.as_secs()) {
+        return Err(ApiError::BadRequest(format!("ttl_secs must be between 1 and {}", MAX_LINK_TTL.as_secs())));
+    }
+
+    let device = store.find(&link_req.esp_id);
+    let authorized = match &device {
+        Some(device) => store.check_password_async(device, &link_req.password).await,
+        None => false,
+    };
+    match device {
+        Some(_) if !authorized => {
+            warn!("[Link] Password verification failed: ID={}", link_req.esp_id);
+            Err(ApiError::Unauthorized)
+        },
+        Some(device) => {
+            let ttl = link_req.ttl_secs.map(Duration::from_secs).unwrap_or(config.link_ttl);
+            let (token, link) = links.issue(&device.esp_id, ttl);
+            info!("[Link] Issued wake link: ID={}, link={}", device.esp_id, link.id);
+            Ok(HttpResponse::Ok().json(json!({
+                "id": link.id,
+                "token": token,
+                "url": format!("/wake/{}", token),
+                "expires_at": link.expires_at,
+            })))
+        },
+        None => {
+            warn!("[Link] Device not found: ID={}", link_req.esp_id);
+            Err(ApiError::device_not_found())
+        },
+    }
+}
+
+/// Wake a device by redeeming a one-time link
+#[allow(clippy::too_many_arguments)]
+async fn wake_by_link(
+    store: web::Data<DeviceStore>,
+    metrics: web::Data<Metrics>,
+    notifier: web::Data<Notifier>,
+    config: web::Data<Config>,
+    links: web::Data<WakeLinks>,
+    history: web::Data<WakeHistory>,
+    req: HttpRequest,
+    token: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let esp_id = links.redeem(&token).map_err(|e| {
+        warn!("[Link] Rejected wake link: {:?}", e);
+        match e {
+            LinkError::Invalid => ApiError::NotFound("Invalid wake link".to_string()),
+            LinkError::Expired => ApiError::Gone("Wake link expired".to_string()),
+            LinkError::Revoked => ApiError::Gone("Wake link revoked".to_string()),
+            LinkError::Used => ApiError::Gone("Wake link already used".to_string()),
+        }
+    })?;
+    info!("[Link] Redeemed wake link: ID={}", esp_id);
+
+    let device = {
+        let devices = store.devices.read_or_recover();
+        devices.get(&esp_id).cloned()
+    };
+
+    let source = wake_source(&req, &config, "link");
+    match device {
+        Some(device) => {
+            let outcome = dispatch_wake(&store, &metrics, &notifier, &config, &device).await;
+            history.record_attempt(&source, &device.esp_id, outcome.as_str(), None);
+            outcome.into_result()
+        },
+        None => {
+            warn!("[Link] Device not found: ID={}", esp_id);
+            history.record_attempt(&source, &esp_id, "not_found", None);
+            Err(ApiError::device_not_found())
+        },
+    }
+}
+
+/// Optional device filter for link listings
+#[derive(Deserialize)]
+struct LinkQuery {
+    esp_id: Option<String>,
+}
+
+/// List active wake links (admin)
+async fn list_links(
+    _admin: AdminAuth,
+    links: web::Data<WakeLinks>,
+    query: web::Query<LinkQuery>,
+) -> impl Responder {
+    HttpResponse::Ok().json(links.active(query.esp_id.as_deref()))
+}
+
+/// Revoke a single wake link (admin)
+async fn revoke_link(
+    _admin: AdminAuth,
+    links: web::Data<WakeLinks>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    if !links.revoke(&id) {
+        return Err(ApiError::NotFound("Wake link not found".to_string()));
+    }
+    info!("[Link] Revoked wake link: link={}", id);
+    Ok(HttpResponse::Ok().json("Wake link revoked"))
+}
+
+/// Revoke every wake link for a device (admin)
+async fn revoke_device_links(
+    _admin: AdminAuth,
+    links: web::Data<WakeLinks>,
+    esp_id: web::Path<String>,
+) -> impl Responder {
+    let revoked = links.revoke_device(&esp_id);
+    info!("[Link] Revoked {} wake links: ID={}", revoked, esp_id);
+    HttpResponse::Ok().json(json!({ "revoked": revoked }))
+}
+
+/// Request to wake a device at a later time
+#[derive(Deserialize)]
+struct ScheduleRequest {
+    esp_id: String,
+    password: String,
+    /// RFC 3339 timestamp
+    wake_at: DateTime<Utc>,
+}
+
+/// Queue a wake for a future time, checking the password now
+#[tracing::instrument(skip_all, fields(esp_id = %schedule_req.esp_id))]
+async fn schedule_wake(
+    store: web::Data<DeviceStore>,
+    schedules: web::Data<Schedules>,
+    schedule_req: web::Json<ScheduleRequest>,
+) -> Result<HttpResponse, ApiError> {
+    info!("[Schedule] Received schedule request: ID={}, wake_at={}", schedule_req.esp_id, schedule_req.wake_at);
+
+    let device = store.find(&schedule_req.esp_id);
+    let authorized = match &device {
+        Some(device) => store.check_password_async(device, &schedule_req.password).await,
+        None => false,
+    };
+    match device {
+        Some(_) if !authorized => {
+            warn!("[Schedule] Password verification failed: ID={}", schedule_req.esp_id);
+            Err(ApiError::Unauthorized)
+        },
+        Some(_) if schedule_req.wake_at <= Utc::now() => {
+            Err(ApiError::BadRequest("wake_at must be in the future".to_string()))
+        },
+        Some(device) => {
+            let job = schedules.add(&device.esp_id, schedule_req.wake_at);
+            info!("[Schedule] Scheduled wake: ID={}, job={}, wake_at={}", job.esp_id, job.id, job.wake_at);
+            Ok(HttpResponse::Ok().json(job))
+        },
+        None => {
+            warn!("[Schedule] Device not found: ID={}", schedule_req.esp_id);
+            Err(ApiError::device_not_found())
+        },
+    }
+}
+
+/// List pending scheduled wakes, soonest first (admin)
+async fn list_schedules(_admin: AdminAuth, schedules: web::Data<Schedules>) -> impl Responder {
+    HttpResponse::Ok().json(schedules.pending())
+}
+
+/// Cancel a pending scheduled wake (admin)
+async fn cancel_schedule(
+    _admin: AdminAuth,
+    schedules: web::Data<Schedules>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    match schedules.cancel(&id) {
+        Some(job) => {
+            info!("[Schedule] Cancelled scheduled wake: ID={}, job={}", job.esp_id, job.id);
+            Ok(HttpResponse::Ok().json(job))
+        },
+        None => Err(ApiError::NotFound("Scheduled wake not found".to_string())),
+    }
+}
+
+/// Calendar feed authentication; a query parameter since calendar apps can't send headers
+#[derive(Deserialize)]
+struct CalendarQuery {
+    token: Option<String>,
+}
+
+/// Pending scheduled wakes as an iCalendar feed, gated by `WOL_CALENDAR_TOKEN`
+async fn schedules_ics(
+    store: web::Data<DeviceStore>,
+    schedules: web::Data<Schedules>,
+    config: web::Data<Config>,
+    query: web::Query<CalendarQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let Some(token) = &config.calendar_token else {
+        return Err(ApiError::Forbidden("Calendar feed disabled".to_string()));
+    };
+    let supplied = query.token.as_deref().unwrap_or_default();
+    if !admin::constant_time_eq(supplied.as_bytes(), token.as_bytes()) {
+        warn!("[Schedule] Rejected calendar feed request");
+        return Err(ApiError::InvalidToken("Invalid calendar token".to_string()));
+    }
+
+    let names = {
+        let devices = store.devices.read_or_recover();
+        devices
+            .values()
+            .map(|device| (device.esp_id.clone(), device.description.clone()))
+            .collect()
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("text/calendar; charset=utf-8")
+        .body(schedule::to_ical(&schedules.pending(), &names)))
+}
+
+/// Flush the device store to disk immediately (admin)
+async fn save_now(_admin: AdminAuth, store: web::Data<DeviceStore>) -> impl Responder {
+    let count = store.devices.read_or_recover().len();
+
+    match DeviceStore::save_async(&store).await {
+        Ok(_) => {
+            info!("[Save] Device store saved on request: {} devices, file={}", count, store.file_path);
+            HttpResponse::Ok().json(json!({
+                "devices": count,
+                "file": store.file_path,
+            }))
+        },
+        Err(e) => {
+            error!("[Save] Failed to save device store: {}", e);
+            ApiError::Internal(e.to_string()).response_with(json!({ "file": store.file_path }))
+        },
+    }
+}
+
+/// List connected relays with their reported state
+async fn get_connections(store: web::Data<DeviceStore>) -> impl Responder {
+    let mut connections = {
+        let connections = store.active_connections.read_or_recover();
+        connections
+            .iter()
+            .flat_map(|(esp_id, conns)| conns.iter().map(move |conn| (esp_id, conn)))
+            .map(|(esp_id, conn)| json!({
+                "esp_id": esp_id,
+                "connection_id": conn.id,
+                "connected_at": conn.connected_at,
+                "rssi": conn.rssi,
+            }))
+            .collect::<Vec<_>>()
+    };
+    connections.sort_by(|a, b| {
+        a["esp_id"].as_str().cmp(&b["esp_id"].as_str())
+            .then_with(|| a["connection_id"].as_u64().cmp(&b["connection_id"].as_u64()))
+    });
+
+    HttpResponse::Ok().json(connections)
+}
+
+/// Recent events query
+#[derive(Deserialize)]
+struct RecentEventsQuery {
+    /// Maximum entries returned, default 50
+    limit: Option<usize>,
+}
+
+/// Query parameters for `/history`
+#[derive(Deserialize)]
+struct HistoryQuery {
+    /// Only attempts for this device
+    esp_id: Option<String>,
+}
+
+/// Wake attempts from every wake endpoint, scheduled wakes and wake emails, oldest first
+async fn get_history(
+    _admin: AdminAuth,
+    history: web::Data<WakeHistory>,
+    query: web::Query<HistoryQuery>,
+) -> impl Responder {
+    HttpResponse::Ok().json(history.list(query.esp_id.as_deref()))
+}
+
+/// Latest connect, disconnect and wake events, newest first
+async fn recent_events(metrics: web::Data<Metrics>, query: web::Query<RecentEventsQuery>) -> impl Responder {
+    HttpResponse::Ok().json(metrics.recent_events(query.limit.unwrap_or(50)))
+}
+
+/// Bulk status request
+#[derive(Deserialize)]
+struct StatusRequest {
+    esp_ids: Vec<String>,
+}
+
+/// Upper bound on esp_ids in one status request
+const MAX_STATUS_IDS: usize = 500;
+
+/// Online flag, last-seen time and power state for many devices, in request order
+async fn bulk_status(store: web::Data<DeviceStore>, status_req: web::Json<StatusRequest>) -> impl Responder {
+    if status_req.esp_ids.len() > MAX_STATUS_IDS {
+        return ApiError::BadRequest(format!("At most {} esp_ids per request", MAX_STATUS_IDS)).error_response();
+    }
+
+    // One pass under each lock rather than a lookup per device
+    let mut results = {
+        let devices = store.devices.read_or_recover();
+        status_req
+            .esp_ids
+            .iter()
+            .map(|esp_id| match find_device(&devices, esp_id) {
+                Some(device) => json!({
+                    "esp_id": device.esp_id,
+                    "id": device.id,
+                    "last_seen": device.last_seen,
+                    "power_state": device.power_state,
+                }),
+                None => json!({ "esp_id": esp_id, "error": "not_found" }),
+            })
+            .collect::<Vec<_>>()
+    };
+    {
+        let connections = store.active_connections.read_or_recover();
+        for result in results.iter_mut().filter(|result| result.get("error").is_none()) {
+            let online = result["esp_id"].as_str().is_some_and(|esp_id| connections.contains_key(esp_id));
+            result["online"] = json!(online);
+        }
+    }
+
+    HttpResponse::Ok().json(json!({ "results": results }))
+}
+
+/// Admin request to send a registered command to a device's relays
+#[derive(Deserialize)]
+struct CommandRequest {
+    esp_id: String,
+    command: String,
+    #[serde(default)]
+    params: commands::Params,
+}
+
+/// Send any registered command to a device's relays (admin only)
+#[tracing::instrument(skip_all, fields(esp_id = %cmd_req.esp_id))]
+async fn send_command(
+    _admin: AdminAuth,
+    store: web::Data<DeviceStore>,
+    cmd_req: web::Json<CommandRequest>,
+) -> HttpResponse {
+    command_device(&store, &cmd_req.esp_id, &cmd_req.command, &cmd_req.params)
+}
+
+/// A command for the device named in the path
+#[derive(Deserialize)]
+struct DeviceCommandRequest {
+    command: String,
+    #[serde(default)]
+    params: commands::Params,
+}
+
+/// `POST /command` with the esp_id in the path, e.g. `POST /command/esp1 {"command":"reboot"}` (admin only)
+#[tracing::instrument(skip_all, fields(esp_id = %esp_id))]
+async fn send_device_command(
+    _admin: AdminAuth,
+    store: web::Data<DeviceStore>,
+    esp_id: web::Path<String>,
+    cmd_req: web::Json<DeviceCommandRequest>,
+) -> HttpResponse {
+    command_device(&store, &esp_id, &cmd_req.command, &cmd_req.params)
+}
+
+/// Build `command` for the device and send it to every relay it has connected
+fn command_device(store: &DeviceStore, esp_id: &str, command: &str, params: &commands::Params) -> HttpResponse {
+    let device = store.find(esp_id);
+    let Some(device) = device else {
+        return ApiError::device_not_found().error_response();
+    };
+
+    let frame = match commands::build(command, &device, params) {
+        Ok(frame) => frame,
+        Err(e @ commands::CommandError::NotAllowed(_)) => {
+            warn!("[Command] Command not allowed for device: ID={}, command={}", device.esp_id, command);
+            return ApiError::Forbidden(e.to_string()).response_with(json!({
+                "allowed_commands": device.allowed_commands,
+            }));
+        },
+        Err(e) => {
+            warn!("[Command] Rejected command: ID={}, error={}", device.esp_id, e);
+            return ApiError::BadRequest(e.to_string()).response_with(json!({
+                "commands": commands::COMMANDS.iter().map(|command| json!({
+                    "name": command.name,
+                    "params": command.params,
+                })).collect::<Vec<_>>(),
+            }));
+        },
+    };
+
+    let addrs = store.relay_addrs(&device.esp_id);
+    if addrs.is_empty() {
+        return ApiError::Offline.error_response();
+    }
+
+    let sent = addrs.iter().filter(|addr| send_frame(addr, &device.esp_id, &frame)).count();
+    if sent == 0 {
+        return ApiError::Internal("Failed to send command".to_string()).error_response();
+    }
+
+    info!("[Command] Sent {} to {} relay(s): ID={}", command, sent, device.esp_id);
+    HttpResponse::Ok().json(json!({ "command": command, "relays": sent }))
+}
+
+/// Admin request to message every connected relay
+#[derive(Deserialize)]
+struct BroadcastRequest {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    payload: serde_json::Value,
+}
+
+/// Send `{"type": ..., "payload": ...}` to every connected relay (admin only).
+///
+/// Devices whose `allowed_commands` don't include the type are skipped.
+async fn broadcast(
+    _admin: AdminAuth,
+    store: web::Data<DeviceStore>,
+    broadcast_req: web::Json<BroadcastRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let kind = broadcast_req.kind.trim();
+    if kind.is_empty() {
+        return Err(ApiError::BadRequest("type must not be empty".to_string()));
+    }
+    let frame = json!({ "type": kind, "payload": broadcast_req.payload }).to_string();
+
+    // Addresses are cloned out so no lock is held while sending
+    let targets = {
+        let connections = store.active_connections.read_or_recover();
+        connections
+            .iter()
+            .flat_map(|(esp_id, conns)| conns.iter().map(move |conn| (esp_id.clone(), conn.addr.clone())))
+            .collect::<Vec<_>>()
+    };
+    let (targets, skipped): (Vec<_>, Vec<_>) = {
+        let devices = store.devices.read_or_recover();
+        targets.into_iter().partition(|(esp_id, _)| {
+            devices.get(esp_id).is_none_or(|device| {
+                device.allowed_commands.is_empty() || device.allowed_commands.iter().any(|allowed| allowed == kind)
+            })
+        })
+    };
+
+    let mut failed = Vec::new();
+    for (esp_id, addr) in &targets {
+        if !send_frame(addr, esp_id, &frame) {
+            failed.push(esp_id);
+        }
+    }
+    info!(
+        "[Broadcast] Sent {} to {} of {} relay(s), {} skipped",
+        kind, targets.len() - failed.len(), targets.len(), skipped.len()
+    );
+    Ok(HttpResponse::Ok().json(json!({
+        "type": kind,
+        "sent": targets.len() - failed.len(),
+        "failed": failed.len(),
+        "failed_devices": failed,
+        "skipped": skipped.len(),
+    })))
+}
+
+/// Optional close details for a forced disconnect
+#[derive(Deserialize, Default)]
+struct DisconnectRequest {
+    /// WebSocket close code, 1000 or 3000-4999; defaults to 4000
+    code: Option<u16>,
+    reason: Option<String>,
+}
+
+/// Close code sent when an admin kicks a relay without specifying one
+const ADMIN_DISCONNECT_CODE: u16 = 4000;
+
+/// Close code sent to a relay whose connection was taken over by a newer one for the same esp_id
+const REPLACED_CLOSE_CODE: u16 = 4001;
+
+/// Close code sent to relays when the server shuts down ("going away")
+const SHUTDOWN_CLOSE_CODE: u16 = 1001;
+
+/// Forcibly disconnect a relay (admin)
+async fn disconnect_relay(
+    _admin: AdminAuth,
+    store: web::Data<DeviceStore>,
+    esp_id: web::Path<String>,
+    body: Option<web::Json<DisconnectRequest>>,
+) -> Result<HttpResponse, ApiError> {
+    let body = body.map(web::Json::into_inner).unwrap_or_default();
+    let code = body.code.unwrap_or(ADMIN_DISCONNECT_CODE);
+    if code != 1000 && !(3000..=4999).contains(&code) {
+        return Err(ApiError::BadRequest("Close code must be 1000 or within 3000-4999".to_string()));
+    }
+    let reason = body.reason.unwrap_or_else(|| "Disconnected by administrator".to_string());
+    // Close reasons must fit in a control frame (125 bytes including the code)
+    if reason.len() > 123 {
+        return Err(ApiError::BadRequest("Close reason is too long".to_string()));
+    }
+
+    let addrs = store.relay_addrs(&esp_id);
+    if addrs.is_empty() {
+        return Err(ApiError::Offline);
+    }
+
+    info!("[Admin] Disconnecting {} relay connection(s): ID={}", addrs.len(), esp_id);
+    for addr in &addrs {
+        addr.do_send(Disconnect { code, reason: reason.clone() });
+    }
+    Ok(HttpResponse::Ok().json(json!({ "disconnected": addrs.len() })))
+}
+
+/// Describe how a device is woken, so the wake can be reproduced by hand
+async fn get_wake_info(
+    store: web::Data<DeviceStore>,
+    config: web::Data<Config>,
+    esp_id: web::Path<String>,
+) -> impl Responder {
+    let device = store.find(&esp_id);
+    let Some(device) = device else {
+        return ApiError::device_not_found().error_response();
+    };
+    let relay_connected = store.is_online(&device.esp_id);
+    let route = wake_route(&store, &config, &device.esp_id);
+    // Relays broadcast on their own LAN; only the server's own packets follow wake_target and wake_port
+    let (broadcast_addresses, port, target_error) = match route {
+        Some(WakeRoute::Server) => match server_wake_target(&config, &device).await {
+            Ok(target) => (vec![target.ip().to_string()], Some(target.port()), None),
+            Err(e) => (Vec::new(), None, Some(e.to_string())),
+        },
+        Some(WakeRoute::Relay) => (vec![wol::DEFAULT_BROADCAST.to_string()], Some(wol::DEFAULT_PORT), None),
+        None => (Vec::new(), None, None),
+    };
+
+    // One magic packet is sent per MAC
+    let packets = device
+        .mac_addresses
+        .iter()
+        .map(|mac_address| match wol::parse_mac(mac_address) {
+            Ok(mac) => json!({
+                "mac_address": mac_address,
+                "mac_normalized": wol::format_mac(&mac),
+                "length": wol::PACKET_LEN,
+                "layout": "6 bytes of 0xFF followed by the 6-byte MAC repeated 16 times",
+                "hex": wol::magic_packet(&mac).iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+            }),
+            Err(e) => json!({ "mac_address": mac_address, "error": e.to_string() }),
+        })
+        .collect::<Vec<_>>();
+
+    HttpResponse::Ok().json(json!({
+        "esp_id": device.esp_id,
+        "mac_addresses": device.mac_addresses,
+        // What a wake would do right now; null while the device is offline
+        "transport": route.map(|route| match route {
+            WakeRoute::Relay => "relay",
+            WakeRoute::Server => "udp",
+        }),
+        "relay_required": !config.udp_fallback,
+        "relay_connected": relay_connected,
+        "broadcast_addresses": broadcast_addresses,
+        "wake_target": device.wake_target,
+        "wake_port": device.wake_port,
+        "target_error": target_error,
+        "port": port,
+        "protocol": "udp",
+        "repeat": config.wake_repeat,
+        "interval_ms": config.wake_interval.as_millis() as u64,
+        "packets": packets,
+    }))
+}
+
+/// Options for a loopback packet check
+#[derive(Deserialize, Default)]
+struct LoopbackRequest {
+    /// Packets to send, default 1
+    count: Option<u32>,
+    /// UDP port to capture on, default 9; 0 picks a free port
+    port: Option<u16>,
+    /// Address the packets are sent to, default 127.0.0.1
+    target: Option<IpAddr>,
+}
+
+/// Upper bound on packets sent by one loopback check
+const MAX_LOOPBACK_PACKETS: u32 = 100;
+
+/// Send a device's magic packet to a local capture socket and report whether it
+/// arrived intact (dev mode only)
+async fn loopback_check(
+    store: web::Data<DeviceStore>,
+    config: web::Data<Config>,
+    esp_id: web::Path<String>,
+    body: Option<web::Json<LoopbackRequest>>,
+) -> Result<HttpResponse, ApiError> {
+    if !config.dev_mode {
+        return Err(ApiError::Forbidden("Loopback capture requires WOL_DEV_MODE".to_string()));
+    }
+    let body = body.map(web::Json::into_inner).unwrap_or_default();
+    let count = body.count.unwrap_or(1);
+    if count == 0 || count > MAX_LOOPBACK_PACKETS {
+        return Err(ApiError::BadRequest(format!("count must be within 1-{}", MAX_LOOPBACK_PACKETS)));
+    }
+
+    let device = store.find(&esp_id).ok_or_else(ApiError::device_not_found)?;
+    // The first MAC is enough to exercise the send path
+    let mac = wol::parse_mac(device.primary_mac()).map_err(|e| ApiError::BadRequest(e.to_string()))?;
+
+    let target = body.target.unwrap_or(IpAddr::from([127, 0, 0, 1]));
+    let port = body.port.unwrap_or(wol::DEFAULT_PORT);
+    match loopback::capture_own_packets(&mac, target, port, count).await {
+        Ok(report) => {
+            info!(
+                "[Dev] Loopback check: ID={}, sent={}, captured={}, matching={}",
+                device.esp_id, report.sent, report.captured, report.matching
+            );
+            Ok(HttpResponse::Ok().json(report))
+        },
+        Err(e) => {
+            error!("[Dev] Loopback check failed: ID={}, error={}", device.esp_id, e);
+            Err(ApiError::Internal(e.to_string()))
+        },
+    }
+}
+
+/// Send a test event to every configured webhook and report each result (admin only)
+async fn test_webhooks(_admin: AdminAuth, notifier: web::Data<Notifier>) -> impl Responder {
+    if !notifier.enabled() {
+        return ApiError::BadRequest("No webhook is configured".to_string()).error_response();
+    }
+
+    let results = notifier.send_test().await;
+    info!(
+        "[Webhook] Test notification sent: {} of {} endpoint(s) succeeded",
+        results.iter().filter(|result| result.ok).count(),
+        results.len()
+    );
+    HttpResponse::Ok().json(results)
+}
+
+/// List webhook deliveries that failed every retry (admin only)
+async fn list_dead_letters(_admin: AdminAuth, notifier: web::Data<Notifier>) -> impl Responder {
+    match notifier.dead_letters() {
+        Ok(letters) => HttpResponse::Ok().json(letters),
+        Err(e) => {
+            error!("[Webhook] Failed to read dead letters: {}", e);
+            ApiError::Internal(e.to_string()).error_response()
+        },
+    }
+}
+
+/// Queue every dead-lettered webhook delivery again (admin only)
+async fn replay_dead_letters(_admin: AdminAuth, notifier: web::Data<Notifier>) -> impl Responder {
+    match notifier.replay_dead_letters() {
+        Ok(count) => {
+            info!("[Webhook] Replaying {} dead letter(s)", count);
+            HttpResponse::Ok().json(json!({ "replayed": count }))
+        },
+        Err(e) => {
+            error!("[Webhook] Failed to replay dead letters: {}", e);
+            ApiError::Internal(e.to_string()).error_response()
+        },
+    }
+}
+
+/// Query for `GET /stats`
+#[derive(Deserialize)]
+struct StatsQuery {
+    /// How many of the most-woken devices to list, default 5
+    top: Option<usize>,
+}
+
+/// Upper bound on `?top=` for `/stats`
+const MAX_STATS_TOP: usize = 100;
+
+/// A JSON overview of the server: devices, relay connections, today's wakes
+/// and the most-woken devices, for a quick look without a monitoring stack
+async fn get_stats(
+    store: web::Data<DeviceStore>,
+    metrics: web::Data<Metrics>,
+    query: web::Query<StatsQuery>,
+) -> impl Responder {
+    let top = query.top.unwrap_or(5).min(MAX_STATS_TOP);
+    let (registered, mut woken) = {
+        let devices = store.devices.read_or_recover();
+        let woken = devices
+            .values()
+            .filter(|device| device.wake_count > 0)
+            .map(|device| (device.wake_count, device.esp_id.clone(), device.description.clone(), device.last_wake))
+            .collect::<Vec<_>>();
+        (devices.len(), woken)
+    };
+    // Most wakes first, ties by esp_id so the order is stable
+    woken.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+    let top_woken = woken
+        .into_iter()
+        .take(top)
+        .map(|(wake_count, esp_id, description, last_wake)| {
+            json!({ "esp_id": esp_id, "description": description, "wake_count": wake_count, "last_wake": last_wake })
+        })
+        .collect::<Vec<_>>();
+    let (connected_devices, connections) = {
+        let connections = store.active_connections.read_or_recover();
+        (connections.len(), connections.values().map(Vec::len).sum::<usize>())
+    };
+
+    HttpResponse::Ok().json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptime_secs": store.started_at.elapsed().as_secs(),
+        "devices": registered,
+        "connected_devices": connected_devices,
+        "connections": connections,
+        "wakes_today": metrics.wakes_today(),
+        "top_woken": top_woken,
+    }))
+}
+
+/// Report the server process's resource usage (admin only)
+async fn get_system(_admin: AdminAuth, monitor: web::Data<SystemMonitor>) -> impl Responder {
+    match monitor.usage() {
+        Some(usage) => HttpResponse::Ok().json(usage),
+        None => ApiError::Unavailable("Resource usage is not available on this platform".to_string()).error_response(),
+    }
+}
+
+/// Export Prometheus metrics
+async fn get_metrics(
+    store: web::Data<DeviceStore>,
+    metrics: web::Data<Metrics>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    let mut devices = {
+        let devices = store.devices.read_or_recover();
+        let connections = store.active_connections.read_or_recover();
+        devices
+            .keys()
+            .map(|esp_id| (esp_id.clone(), connections.contains_key(esp_id)))
+            .collect::<Vec<_>>()
+    };
+    devices.sort();
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render(&devices, config.metrics_device_labels))
+}
+
+/// Render the operator banner as HTML.
+///
+/// The text is escaped, then `**bold**` spans and line breaks (literal or `\n`)
+/// are turned into markup.
+fn render_banner(text: &str, style: BannerStyle) -> String {
+    let escaped = text
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;");
+
+    let mut html = String::new();
+    for (i, part) in escaped.split("**").enumerate() {
+        // Odd segments sit between a pair of `**` markers
+        if i % 2 == 1 {
+            html.push_str("<strong>");
+            html.push_str(part);
+            html.push_str("</strong>");
+        } else {
+            html.push_str(part);
+        }
+    }
+    let html = html.replace("\\n", "<br>").replace('\n', "<br>");
+
+    let class = match style {
+        BannerStyle::Info => "banner",
+        BannerStyle::Warning => "banner warning",
+    };
+    format!(r#"<div class="{}">{}</div>"#, class, html)
+}
+
+/// Home page handler
+async fn index(frontend: web::Data<Frontend>) -> Result<HttpResponse, ApiError> {
+    let page = frontend.page().map_err(|e| ApiError::Internal(e.to_string()))?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(page.to_string()))
+}
+
+/// Structured text frames sent by a relay
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RelayMessage {
+    /// Periodic status report
+    Status {
+        #[serde(default)]
+        rssi: Option<i32>,
+    },
+    /// Acknowledges the last command, confirming delivery during wake verification
+    Ack,
+    /// Reports whether the relay emitted the magic packet for the last wake
+    WakeAck {
+        /// `sent` on success; anything else is the relay's reason for failing
+        #[serde(default)]
+        result: Option<String>,
+    },
+    /// Registers or updates the connection's own device, authorized by `WOL_REGISTRATION_TOKEN`
+    Register {
+        /// Must match the handshake's esp_id when given
+        #[serde(default)]
+        esp_id: Option<String>,
+        #[serde(default)]
+        token: String,
+        #[serde(default, alias = "mac_address", deserialize_with = "schema::mac_addresses")]
+        mac_addresses: Vec<String>,
+        #[serde(default)]
+        description: String,
+        #[serde(default)]
+        password: String,
+    },
 }
 
-/// Device data storage
-struct DeviceStore {
-    devices: Mutex<HashMap<String, Device>>,
-    file_path: String,
-    active_connections: Mutex<HashMap<String, actix::Addr<WsConnection>>>,
+/// Error codes reported to a relay in `{"type":"error","code":...}` frames
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum RelayErrorCode {
+    /// The handshake carried no `esp_id` (connection is closed)
+    MissingEspId,
+    /// The `esp_id` isn't registered (connection is closed)
+    UnknownDevice,
+    /// Other relays already hold the maximum number of connections for the `esp_id` (connection is closed)
+    TooManyConnections,
+    /// A text frame couldn't be parsed (connection stays open)
+    InvalidMessage,
+    /// A `register` frame arrived but `WOL_REGISTRATION_TOKEN` isn't set (connection stays open)
+    RegistrationDisabled,
+    /// A `register` frame carried the wrong token (connection stays open)
+    Unauthorized,
+    /// A `register` frame named another esp_id than the handshake (connection stays open)
+    EspIdMismatch,
+    /// A `register` frame was refused, e.g. for an invalid MAC; the message says why (connection stays open)
+    RegistrationFailed,
 }
 
-impl DeviceStore {
-    /// Create a new device storage instance
-    fn new(file_path: &str) -> Self {
-        if !std::path::Path::new(file_path).exists() {
-            fs::write(file_path, "{}").expect("Failed to create device file");
-        }
-        
-        let devices = match fs::read_to_string(file_path) {
-            Ok(content) => serde_json::from_str(&content).unwrap_or_default(),
-            Err(_) => HashMap::new(),
-        };
-        
-        Self {
-            devices: Mutex::new(devices),
-            file_path: file_path.to_string(),
-            active_connections: Mutex::new(HashMap::new()),
+impl RelayErrorCode {
+    fn message(self) -> &'static str {
+        match self {
+            RelayErrorCode::MissingEspId => "esp_id query parameter is required",
+            RelayErrorCode::UnknownDevice => "esp_id is not registered",
+            RelayErrorCode::TooManyConnections => "too many connections for this esp_id",
+            RelayErrorCode::InvalidMessage => "message could not be parsed",
+            RelayErrorCode::RegistrationDisabled => "self-registration is disabled",
+            RelayErrorCode::Unauthorized => "registration token is incorrect",
+            RelayErrorCode::EspIdMismatch => "esp_id differs from the one connected with",
+            RelayErrorCode::RegistrationFailed => "registration failed",
         }
     }
-
-    /// Save device data to file
-    fn save(&self) -> std::io::Result<()> {
-        let json = {
-            let devices = self.devices.lock().unwrap();
-            serde_json::to_string_pretty(&*devices)?
-        };
-        fs::write(&self.file_path, json)
-    }
 }
 
-/// Register new device
-async fn register_device(
-    store: web::Data<DeviceStore>,
-    device: web::Json<Device>,
-) -> impl Responder {
-    println!("[Register] New device registration request: ID={}", device.esp_id);
-    
-    {
-        let mut devices = store.devices.lock().unwrap();
-        devices.insert(device.esp_id.clone(), device.into_inner());
-    }
-    
-    match store.save() {
-        Ok(_) => {
-            println!("[Register] Device registered and saved successfully");
-            HttpResponse::Ok().json("Device registered successfully")
-        },
+/// Queue `frame` for one of `esp_id`'s relays, logging when its mailbox refuses it
+fn send_frame(addr: &actix::Addr<WsConnection>, esp_id: &str, frame: &str) -> bool {
+    match addr.try_send(WsMessage(frame.to_string())) {
+        Ok(()) => true,
         Err(e) => {
-            println!("[Register] Failed to save device info: {}", e);
-            HttpResponse::InternalServerError().body(e.to_string())
+            error!("[WebSocket] Failed to send to relay: ID={}, error={}", esp_id, e);
+            false
         },
     }
 }
 
-/// Get all registered devices
-async fn get_devices(store: web::Data<DeviceStore>) -> impl Responder {
-    println!("[Query] Received request for device list");
-    
-    let devices_vec = {
-        let devices = match store.devices.lock() {
-            Ok(guard) => guard,
-            Err(e) => {
-                println!("[Query] Failed to get device list: {}", e);
-                return HttpResponse::InternalServerError().json("Failed to get device list");
-            }
-        };
-        devices.values().cloned().collect::<Vec<Device>>()
-    };
-    
-    println!("[Query] Returning device list, total {} devices", devices_vec.len());
-    
-    HttpResponse::Ok()
-        .insert_header(("Access-Control-Allow-Origin", "*"))
-        .json(&devices_vec)
+/// A `{"type":"error",...}` frame for a relay
+fn error_frame(code: RelayErrorCode, message: &str) -> String {
+    json!({
+        "type": "error",
+        "code": code,
+        "message": message,
+    })
+    .to_string()
 }
 
-/// Send wake command to specified ESP8266
-async fn wake_device(
+/// How long a relay with an unknown esp_id may stay connected without registering
+const SELF_REGISTRATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// WebSocket message wrapper
+#[derive(Message)]
+#[rtype(result = "()")]
+struct WsMessage(String);
+
+/// Ask a relay's actor to close its connection
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Disconnect {
+    code: u16,
+    reason: String,
+}
+
+/// Ask a relay's actor to send a WebSocket ping; the pong is recorded in `RelayConnection::ponged_at`
+#[derive(Message)]
+#[rtype(result = "()")]
+struct PingRelay;
+
+/// Source of `WsConnection::connection_id`
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// WebSocket connection handler
+struct WsConnection {
+    esp_id: String,
+    /// Identifies this actor's entry among the esp_id's connections
+    connection_id: u64,
+    /// Address the relay connected from
+    peer: Option<IpAddr>,
+    /// Set when the handshake was refused; the actor reports it and closes
+    rejection: Option<RelayErrorCode>,
+    /// The esp_id isn't registered yet and the relay may register it with a `register` frame
+    awaiting_registration: bool,
+    /// Whether this actor added itself to `active_connections`
+    registered: bool,
+    /// When anything, a pong or otherwise, was last received from the relay
+    last_heartbeat: Instant,
+    /// When this connection last wrote the device's `last_seen`
+    last_seen_recorded: Instant,
     store: web::Data<DeviceStore>,
-    wake_req: web::Json<WakeRequest>,
-) -> impl Responder {
-    println!("[Wake] Received wake request: ID={}", wake_req.esp_id);
-    
-    let device = {
-        let devices = store.devices.lock().unwrap();
-        devices.get(&wake_req.esp_id).cloned()
-    };
-    
-    match device {
-        Some(device) => {
-            if device.password != wake_req.password {
-                println!("[Wake] Password verification failed: ID={}", wake_req.esp_id);
-                return HttpResponse::Unauthorized().json("Incorrect password");
-            }
-            
-            let addr = {
-                let connections = store.active_connections.lock().unwrap();
-                connections.get(&wake_req.esp_id).cloned()
-            };
-            
-            if let Some(addr) = addr {
-                let wake_msg = json!({
-                    "type": "wake",
-                    "mac_address": device.mac_address
-                });
-                
-                match addr.try_send(WsMessage(wake_msg.to_string())) {
-                    Ok(_) => {
-                        println!("[Wake] Wake command sent successfully: ID={}, MAC={}", wake_req.esp_id, device.mac_address);
-                        HttpResponse::Ok().json("Wake command sent")
-                    },
-                    Err(e) => {
-                        println!("[Wake] Failed to send wake command: {}", e);
-                        HttpResponse::InternalServerError().json("Failed to send wake command")
-                    },
+    config: web::Data<Config>,
+    metrics: web::Data<Metrics>,
+    notifier: web::Data<Notifier>,
+}
+
+/// Which of an esp_id's `open` connections, as (id, peer) oldest first, a new
+/// relay from `peer` takes over to stay within `limit`.
+///
+/// A relay reconnecting after a network blip usually arrives before its old
+/// connection has timed out, so it may replace connections from its own
+/// address. `None` means other relays already fill the limit and the new one
+/// is refused.
+fn takeover(open: &[(u64, Option<IpAddr>)], peer: Option<IpAddr>, limit: usize) -> Option<Vec<u64>> {
+    let excess = (open.len() + 1).saturating_sub(limit.max(1));
+    let own: Vec<u64> = open
+        .iter()
+        .filter(|(_, from)| peer.is_some() && *from == peer)
+        .map(|(id, _)| *id)
+        .take(excess)
+        .collect();
+    (own.len() == excess).then_some(own)
+}
+
+/// How often traffic from a connected relay refreshes its device's `last_seen`,
+/// so a chatty relay doesn't take the device map's write lock and trigger a
+/// save on every frame
+const LAST_SEEN_INTERVAL: Duration = Duration::from_secs(60);
+
+impl WsConnection {
+    /// Stamp the device's `last_seen` with the current time and schedule a save
+    fn record_last_seen(&mut self) {
+        self.last_seen_recorded = Instant::now();
+        let updated = match self.store.devices.write_or_recover().get_mut(&self.esp_id) {
+            Some(device) => {
+                device.last_seen = Some(Utc::now());
+                true
+            },
+            // Deleted while connected
+            None => false,
+        };
+        if updated {
+            self.store.save_later();
+        }
+    }
+
+    /// Ping the relay periodically and drop it once it has gone quiet for too
+    /// long, so a relay whose Wi-Fi died doesn't linger as online.
+    ///
+    /// The device's `keepalive_secs` takes precedence over the server default.
+    /// The idle limit is `WOL_HEARTBEAT_TIMEOUT_SECS`, but never less than two
+    /// intervals, so slow-pinged relays aren't dropped between pings.
+    fn start_keepalive(&self, keepalive_secs: Option<u64>, ctx: &mut ws::WebsocketContext<Self>) {
+        let interval = match keepalive_secs {
+            // Files edited by hand bypass registration's range check
+            Some(secs) => Duration::from_secs(secs.clamp(*KEEPALIVE_SECS_RANGE.start(), *KEEPALIVE_SECS_RANGE.end())),
+            None => self.config.keepalive_interval,
+        };
+        if interval.is_zero() {
+            return;
+        }
+        let timeout = self.config.heartbeat_timeout.map(|timeout| timeout.max(interval * 2));
+        ctx.run_interval(interval, move |act, ctx| {
+            if let Some(timeout) = timeout {
+                let idle = act.last_heartbeat.elapsed();
+                if idle > timeout {
+                    warn!("[WebSocket] No heartbeat for {:?}, dropping connection: ID={}", idle, act.esp_id);
+                    ctx.stop();
+                    return;
                 }
-            } else {
-                println!("[Wake] Device offline: ID={}", wake_req.esp_id);
-                HttpResponse::NotFound().json("Device offline")
             }
-        },
-        None => {
-            println!("[Wake] Device not found: ID={}", wake_req.esp_id);
-            HttpResponse::NotFound().json("Device not found")
-        },
+            ctx.ping(b"keepalive");
+        });
     }
-}
 
-/// Home page handler
-async fn index() -> impl Responder {
-    HttpResponse::Ok().content_type("text/html").body(
-        r#"
-        <!DOCTYPE html>
-        <html>
-        <head>
-            <title>Remote Wake System</title>
-            <meta charset="utf-8">
-            <style>
-                body {
-                    font-family: Arial, sans-serif;
-                    max-width: 800px;
-                    margin: 0 auto;
-                    padding: 20px;
-                }
-                .device-card {
-                    border: 1px solid #ddd;
-                    padding: 15px;
-                    margin: 10px 0;
-                    border-radius: 5px;
-                }
-                .wake-btn {
-                    background-color: #4CAF50;
-                    color: white;
-                    padding: 8px 16px;
-                    border: none;
-                    border-radius: 4px;
-                    cursor: pointer;
-                }
-                .wake-btn:hover {
-                    background-color: #45a049;
-                }
-                .status {
-                    margin-top: 10px;
-                    padding: 10px;
-                    display: none;
-                }
-                .success {
-                    background-color: #dff0d8;
-                    color: #3c763d;
-                    display: block;
+    /// Add this connection to `active_connections` and announce it, or
+    /// refuse it when other relays already fill the esp_id's connection limit
+    fn establish(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        {
+            let mut connections = self.store.active_connections.write_or_recover();
+            let conns = connections.entry(self.esp_id.clone()).or_default();
+            // Re-checked under the lock so two relays racing through ws_index can't both get in
+            let open: Vec<_> = conns.iter().map(|conn| (conn.id, conn.peer)).collect();
+            let Some(replaced) = takeover(&open, self.peer, self.config.max_connections_per_device) else {
+                if conns.is_empty() {
+                    connections.remove(&self.esp_id);
                 }
-                .error {
-                    background-color: #f2dede;
-                    color: #a94442;
-                    display: block;
+                drop(connections);
+                warn!(
+                    "[WebSocket] Connection limit reached: ID={}, limit={}",
+                    self.esp_id, self.config.max_connections_per_device
+                );
+                return self.reject(ctx, RelayErrorCode::TooManyConnections);
+            };
+            // The replaced actors only remove their own entries once they stop
+            conns.retain(|conn| {
+                if !replaced.contains(&conn.id) {
+                    return true;
                 }
-            </style>
-        </head>
-        <body>
-            <h1>Remote Wake System</h1>
-            <div id="status" class="status"></div>
-            <div id="devices-container"></div>
-
-            <script>
-                async function fetchDevices() {
-                    try {
-                        const response = await fetch('/devices');
-                        if (!response.ok) {
-                            throw new Error('Failed to fetch devices');
-                        }
-                        
-                        const devices = await response.json();
-                        const container = document.getElementById('devices-container');
-                        container.innerHTML = '';
-
-                        if (!devices || devices.length === 0) {
-                            container.innerHTML = '<p>No devices available</p>';
-                            return;
-                        }
+                warn!("[WebSocket] Replacing older connection from the same address: ID={}", self.esp_id);
+                conn.addr.do_send(Disconnect {
+                    code: REPLACED_CLOSE_CODE,
+                    reason: "Replaced by a newer connection".to_string(),
+                });
+                false
+            });
+            conns.push(RelayConnection {
+                id: self.connection_id,
+                addr: ctx.address(),
+                peer: self.peer,
+                connected_at: unix_now(),
+                rssi: None,
+                acked_at: None,
+                wake_sent_at: None,
+                wake_failed_at: None,
+                ponged_at: None,
+            });
+        }
+        self.registered = true;
 
-                        devices.forEach(device => {
-                            const deviceElement = document.createElement('div');
-                            deviceElement.className = 'device-card';
-                            deviceElement.innerHTML = `
-                                <h3>${device.description}</h3>
-                                <input type="password" id="pwd-${device.esp_id}" placeholder="Enter password">
-                                <button class="wake-btn" onclick="wakeDevice('${device.esp_id}')">
-                                    Wake Device
-                                </button>
-                            `;
-                            container.appendChild(deviceElement);
-                        });
-                    } catch (error) {
-                        showStatus('Connection error', false);
-                    }
-                }
+        info!("[WebSocket] New connection established: ID={}", self.esp_id);
+        self.metrics.record_connect(&self.esp_id);
+        self.record_last_seen();
+        self.store.mqtt.publish_status(&self.esp_id, true);
+        self.store.publish_device(&self.esp_id);
+        self.notifier.notify("connect", json!({ "esp_id": self.esp_id }));
 
-                async function wakeDevice(espId) {
-                    try {
-                        const passwordInput = document.getElementById(`pwd-${espId}`);
-                        const password = passwordInput ? passwordInput.value : '';
-                        
-                        const response = await fetch('/wake', {
-                            method: 'POST',
-                            headers: {
-                                'Content-Type': 'application/json',
-                            },
-                            body: JSON.stringify({ 
-                                esp_id: espId,
-                                password: password
-                            })
-                        });
-
-                        if (response.ok) {
-                            showStatus('Command sent successfully', true);
-                        } else {
-                            showStatus('Command failed', false);
-                        }
-                    } catch (error) {
-                        showStatus('Connection error', false);
-                    }
-                }
+        let (keepalive_secs, restore) = {
+            let devices = self.store.devices.read_or_recover();
+            let device = devices.get(&self.esp_id);
+            (
+                device.and_then(|device| device.keepalive_secs),
+                device
+                    .filter(|device| device.auto_restore && device.power_state == Some(PowerState::On))
+                    .cloned(),
+            )
+        };
+        self.start_keepalive(keepalive_secs, ctx);
+        if let Some(device) = restore {
+            self.restore_power(device, ctx);
+        }
+    }
 
-                function showStatus(message, isSuccess) {
-                    const status = document.getElementById('status');
-                    status.textContent = message;
-                    status.className = 'status ' + (isSuccess ? 'success' : 'error');
-                    setTimeout(() => {
-                        status.className = 'status';
-                    }, 3000);
-                }
+    /// Update this actor's entry in `active_connections`
+    fn update_connection(&self, update: impl FnOnce(&mut RelayConnection)) {
+        let mut connections = self.store.active_connections.write_or_recover();
+        let conns = connections.get_mut(&self.esp_id).into_iter().flatten();
+        if let Some(conn) = conns.into_iter().find(|conn| conn.id == self.connection_id) {
+            update(conn);
+        }
+    }
 
-                document.addEventListener('DOMContentLoaded', fetchDevices);
-                setInterval(fetchDevices, 30000);
-            </script>
-        </body>
-        </html>
-        "#
-    )
-}
+    /// Note an `ack` frame, reporting it as `wake_ack` if it answers a wake
+    fn record_ack(&self) {
+        let now = Instant::now();
+        let mut wake_sent_at = None;
+        self.update_connection(|conn| {
+            conn.acked_at = Some(now);
+            wake_sent_at = conn.wake_sent_at.take();
+        });
+        let Some(sent_at) = wake_sent_at else {
+            return;
+        };
 
-/// WebSocket message wrapper
-#[derive(Message)]
-#[rtype(result = "()")]
-struct WsMessage(String);
+        let rtt = now.duration_since(sent_at);
+        info!("[Wake] Relay acknowledged wake after {:?}: ID={}", rtt, self.esp_id);
+        self.metrics.record_event(&self.esp_id, "wake_ack", Some(format!("{}ms", rtt.as_millis())));
+        let device = self.store.devices.read_or_recover().get(&self.esp_id).cloned();
+        if let Some(device) = device {
+            self.notifier.notify_tagged("wake_ack", json!({
+                "esp_id": device.esp_id,
+                "mac": device.primary_mac(),
+                "rtt_ms": rtt.as_millis() as u64,
+                "tags": device.tags,
+            }), &device.tags);
+        }
+    }
 
-/// WebSocket connection handler
-struct WsConnection {
-    esp_id: String,
-    store: web::Data<DeviceStore>,
+    /// Note a `wake_ack` frame reporting that the magic packet wasn't sent
+    fn record_wake_failure(&self, reason: &str) {
+        let now = Instant::now();
+        self.update_connection(|conn| {
+            conn.wake_failed_at = Some(now);
+            conn.wake_sent_at = None;
+        });
+        warn!("[Wake] Relay failed to send magic packet: ID={}, result={}", self.esp_id, reason);
+        self.metrics.record_event(&self.esp_id, "wake_ack", Some(format!("failed: {}", reason)));
+    }
+
+    /// Send a structured error frame to the relay
+    fn send_error(&self, ctx: &mut ws::WebsocketContext<Self>, code: RelayErrorCode) {
+        ctx.text(error_frame(code, code.message()));
+    }
+
+    /// Report why the connection was refused and close it
+    fn reject(&self, ctx: &mut ws::WebsocketContext<Self>, code: RelayErrorCode) {
+        warn!("[WebSocket] Rejected connection: ID={}, code={:?}", self.esp_id, code);
+        self.send_error(ctx, code);
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some(code.message().to_string()),
+        }));
+        ctx.stop();
+    }
+
+    /// Register or update this connection's device from a `register` frame.
+    ///
+    /// A relay whose esp_id is unknown joins `active_connections` once its
+    /// device is registered; an already authenticated one updates its device.
+    fn register(
+        &mut self,
+        esp_id: Option<String>,
+        token: &str,
+        mac_addresses: Vec<String>,
+        description: String,
+        password: String,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let Some(expected) = &self.config.registration_token else {
+            warn!("[Register] Self-registration attempted while disabled: ID={}", self.esp_id);
+            return self.send_error(ctx, RelayErrorCode::RegistrationDisabled);
+        };
+        if !admin::constant_time_eq(expected.as_bytes(), token.as_bytes()) {
+            warn!("[Register] Incorrect registration token: ID={}", self.esp_id);
+            return self.send_error(ctx, RelayErrorCode::Unauthorized);
+        }
+        if esp_id.as_ref().is_some_and(|esp_id| *esp_id != self.esp_id) {
+            warn!("[Register] Registration for another esp_id refused: ID={}, requested={:?}", self.esp_id, esp_id);
+            return self.send_error(ctx, RelayErrorCode::EspIdMismatch);
+        }
+        if password.is_empty() {
+            return ctx.text(error_frame(RelayErrorCode::RegistrationFailed, "password is required"));
+        }
+
+        // Only the relay's own fields change; the rest carries over from the device it updates
+        let previous = self.store.devices.read_or_recover().get(&self.esp_id).cloned();
+        let device = Device {
+            mac_addresses,
+            description,
+            password,
+            ..previous.unwrap_or_else(|| Device::new(&self.esp_id))
+        };
+        // Hashing is slow, so it runs on the blocking pool; waiting holds back
+        // the relay's next frames until its device is stored
+        let hashing = web::block(move || hash_passwords(device)).into_actor(self);
+        ctx.wait(hashing.map(|hashed, act, ctx| match hashed {
+            Ok(device) => act.store_registration(device, ctx),
+            Err(e) => {
+                error!("[Register] Failed to hash password: {}", e);
+                ctx.text(error_frame(RelayErrorCode::RegistrationFailed, "failed to hash password"));
+            },
+        }));
+    }
+
+    /// Second half of `register`, once the device's password is hashed
+    fn store_registration(&mut self, device: Device, ctx: &mut ws::WebsocketContext<Self>) {
+        // A relay awaiting registration hasn't authenticated, so it may not take over a device registered meanwhile
+        let (device, created) = match upsert_device(&self.store, &self.config, device, !self.awaiting_registration) {
+            Ok(registered) => registered,
+            Err(e) => return ctx.text(error_frame(RelayErrorCode::RegistrationFailed, &e.to_string())),
+        };
+        info!("[Register] Device {} over WebSocket: ID={}", if created { "registered" } else { "updated" }, self.esp_id);
+        if self.awaiting_registration {
+            self.awaiting_registration = false;
+            self.establish(ctx);
+        }
+
+        let store = self.store.clone();
+        let addr = ctx.address();
+        actix::spawn(async move {
+            let esp_id = device.esp_id.clone();
+            let frame = match DeviceStore::persist(&store, move |store| store.insert(&device)).await {
+                Ok(()) => {
+                    store.publish_device(&esp_id);
+                    json!({ "type": "register_ack", "esp_id": esp_id, "created": created }).to_string()
+                },
+                Err(e) => {
+                    error!("[Register] Failed to save device info: {}", e);
+                    error_frame(RelayErrorCode::RegistrationFailed, "failed to save device")
+                },
+            };
+            addr.do_send(WsMessage(frame));
+        });
+    }
+
+    /// Re-wake the device after a reconnect if it was meant to be on and isn't reachable
+    fn restore_power(&self, device: Device, ctx: &mut ws::WebsocketContext<Self>) {
+        let addr = ctx.address();
+        let metrics = self.metrics.clone();
+        let notifier = self.notifier.clone();
+        let store = self.store.clone();
+        let config = self.config.clone();
+        let port = self.config.probe_port;
+        let limit = self.config.probe_timeout;
+
+        actix::spawn(async move {
+            if let Some(ip) = &device.ip_address {
+                if probe::is_reachable(ip, port, limit).await {
+                    info!("[Restore] Device already reachable, skipping wake: ID={}", device.esp_id);
+                    return;
+                }
+            }
+
+            info!("[Restore] Waking device that was on before the relay dropped: ID={}", device.esp_id);
+            let messages = match wake_messages(&device, &config) {
+                Ok(messages) => messages,
+                Err(e) => {
+                    error!("[Restore] Cannot build wake command: ID={}, error={}", device.esp_id, e);
+                    return;
+                },
+            };
+            for message in messages {
+                addr.do_send(WsMessage(message));
+            }
+            metrics.record_wake(&device.esp_id, true);
+            metrics.record_event(&device.esp_id, "wake", Some("auto_restore".to_string()));
+            let event = json!({
+                "esp_id": device.esp_id,
+                "mac": device.primary_mac(),
+                "mac_addresses": device.mac_addresses,
+                "result": WakeOutcome::Sent.as_str(),
+                "source": "auto_restore",
+                "wake_note": device.wake_note,
+                "tags": device.tags,
+            });
+            store.mqtt.publish_wake(&device.esp_id, &event);
+            notifier.notify_tagged("wake", event, &device.tags);
+        });
+    }
 }
 
 impl Handler<WsMessage> for WsConnection {
@@ -314,26 +3564,105 @@ impl Handler<WsMessage> for WsConnection {
     }
 }
 
+impl Handler<PingRelay> for WsConnection {
+    type Result = ();
+
+    fn handle(&mut self, _msg: PingRelay, ctx: &mut Self::Context) {
+        ctx.ping(b"health");
+    }
+}
+
+impl Handler<Disconnect> for WsConnection {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Self::Context) {
+        info!("[WebSocket] Closing connection on request: ID={}, code={}, reason={}", self.esp_id, msg.code, msg.reason);
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::from(msg.code),
+            description: Some(msg.reason),
+        }));
+        ctx.stop();
+    }
+}
+
 impl Actor for WsConnection {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        println!("[WebSocket] New connection established: ID={}", self.esp_id);
-        let mut connections = self.store.active_connections.lock().unwrap();
-        connections.insert(self.esp_id.clone(), ctx.address());
+        if let Some(code) = self.rejection {
+            return self.reject(ctx, code);
+        }
+        if self.awaiting_registration {
+            info!("[WebSocket] Unregistered device connected, awaiting registration: ID={}", self.esp_id);
+            ctx.run_later(SELF_REGISTRATION_TIMEOUT, |act, ctx| {
+                if act.awaiting_registration {
+                    warn!("[WebSocket] No registration received, closing: ID={}", act.esp_id);
+                    act.send_error(ctx, RelayErrorCode::UnknownDevice);
+                    ctx.close(Some(ws::CloseReason {
+                        code: ws::CloseCode::Policy,
+                        description: Some(RelayErrorCode::UnknownDevice.message().to_string()),
+                    }));
+                    ctx.stop();
+                }
+            });
+            return;
+        }
+        self.establish(ctx);
     }
 
     fn stopped(&mut self, _ctx: &mut Self::Context) {
-        println!("[WebSocket] Connection closed: ID={}", self.esp_id);
-        let mut connections = self.store.active_connections.lock().unwrap();
-        connections.remove(&self.esp_id);
+        if !self.registered {
+            return;
+        }
+
+        info!("[WebSocket] Connection closed: ID={}", self.esp_id);
+        self.record_last_seen();
+        self.metrics.record_disconnect(&self.esp_id);
+        self.notifier.notify("disconnect", json!({ "esp_id": self.esp_id }));
+        let online = {
+            let mut connections = self.store.active_connections.write_or_recover();
+            if let Some(conns) = connections.get_mut(&self.esp_id) {
+                conns.retain(|conn| conn.id != self.connection_id);
+                if conns.is_empty() {
+                    connections.remove(&self.esp_id);
+                }
+            }
+            connections.contains_key(&self.esp_id)
+        };
+        // Another relay of the same device, such as its replacement, keeps it online
+        if !online {
+            self.store.mqtt.publish_status(&self.esp_id, false);
+            self.store.publish_device(&self.esp_id);
+        }
     }
 }
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsConnection {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        if msg.is_ok() {
+            self.last_heartbeat = Instant::now();
+            if self.last_seen_recorded.elapsed() >= LAST_SEEN_INTERVAL {
+                self.record_last_seen();
+            }
+        }
         match msg {
             Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(ws::Message::Pong(_)) => self.update_connection(|conn| conn.ponged_at = Some(Instant::now())),
+            Ok(ws::Message::Text(text)) => match serde_json::from_str::<RelayMessage>(&text) {
+                Ok(RelayMessage::Status { rssi }) => self.update_connection(|conn| conn.rssi = rssi),
+                Ok(RelayMessage::Ack) => self.record_ack(),
+                Ok(RelayMessage::WakeAck { result }) => match result.as_deref() {
+                    None | Some("sent") => self.record_ack(),
+                    Some(reason) => self.record_wake_failure(reason),
+                },
+                Ok(RelayMessage::Register { esp_id, token, mac_addresses, description, password }) => {
+                    self.register(esp_id, &token, mac_addresses, description, password, ctx)
+                },
+                Err(e) => {
+                    warn!("[WebSocket] Ignoring unrecognized message: ID={}, error={}", self.esp_id, e);
+                    self.send_error(ctx, RelayErrorCode::InvalidMessage);
+                },
+            },
             Ok(ws::Message::Close(reason)) => {
                 ctx.close(reason);
             },
@@ -348,34 +3677,618 @@ async fn ws_index(
     stream: web::Payload,
     query: web::Query<HashMap<String, String>>,
     store: web::Data<DeviceStore>,
+    config: web::Data<Config>,
+    metrics: web::Data<Metrics>,
+    notifier: web::Data<Notifier>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let esp_id = query.get("esp_id").cloned().unwrap_or_default();
+
+    // Only the device itself knows its password; anyone else claiming the
+    // esp_id is refused before the upgrade so they never receive its commands
+    let device = store.devices.read_or_recover().get(&esp_id).cloned();
+    let known = device.is_some();
+    if let Some(device) = device {
+        let supplied = query.get("password").map(String::as_str).unwrap_or_default();
+        // Named credentials are for people waking the device, not for its relay
+        if !matches!(store.authenticate_async(&device, supplied).await, Some(None)) {
+            warn!("[WebSocket] Password verification failed: ID={}", esp_id);
+            return Err(ApiError::Unauthorized.into());
+        }
+    }
+
+    // With a registration token set, an unknown relay may stay to register itself
+    let awaiting_registration = !esp_id.is_empty() && !known && config.registration_token.is_some();
+    let peer = allowlist::client_ip(&req, config.trust_proxy);
+    let rejection = if esp_id.is_empty() {
+        Some(RelayErrorCode::MissingEspId)
+    } else if !known && !awaiting_registration {
+        Some(RelayErrorCode::UnknownDevice)
+    } else if !awaiting_registration && takeover(&store.relay_peers(&esp_id), peer, config.max_connections_per_device).is_none() {
+        warn!(
+            "[WebSocket] Connection limit reached: ID={}, limit={}",
+            esp_id, config.max_connections_per_device
+        );
+        Some(RelayErrorCode::TooManyConnections)
+    } else {
+        None
+    };
     
     let ws = WsConnection { 
         esp_id, 
-        store: store.clone()
+        connection_id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+        peer,
+        rejection,
+        awaiting_registration,
+        registered: false,
+        last_heartbeat: Instant::now(),
+        last_seen_recorded: Instant::now(),
+        store: store.clone(),
+        config: config.clone(),
+        metrics: metrics.clone(),
+        notifier: notifier.clone(),
     };
     
     ws::start(ws, &req, stream)
 }
 
+/// Follow device list changes from the web UI; see [`LiveUpdates`]
+async fn live_index(req: HttpRequest, stream: web::Payload, store: web::Data<DeviceStore>) -> Result<HttpResponse, actix_web::Error> {
+    ws::start(LiveConnection::new(&store.live), &req, stream)
+}
+
+/// Current time as a unix timestamp (seconds)
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Resolve on the first SIGINT (Ctrl-C) or, on Unix, SIGTERM
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+/// On a shutdown signal, stop accepting connections, close every relay and
+/// let actix finish the requests still in flight
+async fn shut_down_on_signal(server: actix_web::dev::ServerHandle, store: web::Data<DeviceStore>) {
+    if let Err(e) = shutdown_signal().await {
+        error!("[System] Failed to listen for shutdown signals: {}", e);
+        return;
+    }
+    info!("[System] Shutting down");
+    store.ready.store(false, Ordering::Relaxed);
+    server.pause().await;
+    // Open WebSockets would otherwise hold the workers until the shutdown timeout
+    let closed = store.close_relays(SHUTDOWN_CLOSE_CODE, "Server shutting down");
+    info!("[System] Closed {} relay connection(s)", closed);
+    server.stop(true).await;
+}
+
+/// Command-line options; each falls back to its environment variable, then the default
+#[derive(Parser)]
+#[command(version, about = "Wake-on-LAN server for ESP8266 relays")]
+struct Args {
+    /// Address to listen on
+    #[arg(long, env = "WOL_BIND", default_value = "0.0.0.0")]
+    bind: String,
+    /// Port to listen on
+    #[arg(long, env = "WOL_PORT", default_value_t = 54001)]
+    port: u16,
+    /// Where devices are stored
+    #[arg(long, env = "WOL_BACKEND", value_enum, default_value_t = Backend::Json)]
+    backend: Backend,
+    /// Device data file or database [default: devices.json, or devices.db for sqlite];
+    /// unused when WOL_DEVICE_DIR is set
+    #[arg(long, env = "WOL_DATA_FILE")]
+    data_file: Option<String>,
+    /// Bearer key for the admin endpoints, including /register; they are disabled without one
+    #[arg(long, env = "WOL_ADMIN_KEY", hide_env_values = true)]
+    admin_key: Option<String>,
+    /// Magic packets sent per MAC for each wake [default: 3]
+    #[arg(long, env = "WOL_WAKE_REPEAT")]
+    wake_repeat: Option<u32>,
+    /// Delay between repeated magic packets, in milliseconds [default: 100]
+    #[arg(long, env = "WOL_WAKE_INTERVAL_MS")]
+    wake_interval_ms: Option<u64>,
+    /// Webhook endpoint notified after each wake and relay event; repeat or
+    /// comma-separate for several. Webhooks are off without one
+    #[arg(long, env = "WOL_WEBHOOK_URL", value_delimiter = ',')]
+    webhook_url: Vec<String>,
+    /// MQTT broker (host[:port]) to publish device status and wakes to; off without one
+    #[arg(long, env = "WOL_MQTT_BROKER")]
+    mqtt_broker: Option<String>,
+    /// Browser origins allowed to call the API, comma-separated, or * for any
+    #[arg(long, env = "WOL_ALLOWED_ORIGINS", value_delimiter = ',')]
+    allowed_origins: Vec<String>,
+    /// Client CIDR blocks allowed to wake devices, comma-separated; any client may without one
+    #[arg(long, env = "WOL_WAKE_ALLOW_CIDR", value_delimiter = ',', value_parser = allowlist::parse_cidr)]
+    wake_allow_cidr: Vec<ipnet::IpNet>,
+
+    /// Trust X-Forwarded-For for the client address, as set by a reverse proxy in front
+    #[arg(long)]
+    trust_proxy: bool,
+
+    /// PEM certificate chain; serve HTTPS when given together with --tls-key
+    #[arg(long, env = "WOL_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls-cert
+    #[arg(long, env = "WOL_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+    /// Validate configuration and data, then exit without serving
+    #[arg(long)]
+    check_config: bool,
+    #[command(subcommand)]
+    command: Option<ArgsCommand>,
+}
+
+#[derive(Subcommand)]
+enum ArgsCommand {
+    /// Manage devices without starting the server (list | add | remove)
+    Device {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+}
+
 #[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let store = web::Data::new(DeviceStore::new("devices.json"));
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let bind_addr = format!("{}:{}", args.bind, args.port);
+    let data_file = args.data_file.clone().unwrap_or_else(|| args.backend.default_file().to_string());
+    if args.check_config {
+        let tls = (args.tls_cert.as_deref(), args.tls_key.as_deref());
+        std::process::exit(if check::run(args.backend, &data_file, &bind_addr, tls) { 0 } else { 1 });
+    }
+    if let Some(ArgsCommand::Device { args: device_args }) = &args.command {
+        return cli::run(args.backend, &data_file, device_args);
+    }
+
+    let mut config = Config::from_env()?;
+    config.admin_key = args.admin_key.clone();
+    if let Some(repeat) = args.wake_repeat {
+        config.wake_repeat = repeat;
+    }
+    if let Some(interval_ms) = args.wake_interval_ms {
+        config.wake_interval = Duration::from_millis(interval_ms);
+    }
+    if args.mqtt_broker.is_some() {
+        config.mqtt_broker = args.mqtt_broker.clone();
+    }
+    if !args.allowed_origins.is_empty() {
+        config.allowed_origins = args
+            .allowed_origins
+            .iter()
+            .map(|origin| origin.trim().to_string())
+            .filter(|origin| !origin.is_empty())
+            .collect();
+    }
+    if !args.wake_allow_cidr.is_empty() {
+        config.wake_allow_cidrs = args.wake_allow_cidr.clone();
+    }
+    config.trust_proxy |= args.trust_proxy;
+    if !args.webhook_url.is_empty() {
+        config.webhook_urls = args
+            .webhook_url
+            .iter()
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty())
+            .collect();
+    }
+    if !WAKE_REPEAT_RANGE.contains(&config.wake_repeat) {
+        anyhow::bail!("--wake-repeat must be between {} and {}", WAKE_REPEAT_RANGE.start(), WAKE_REPEAT_RANGE.end());
+    }
+    if config.wake_interval > MAX_WAKE_INTERVAL {
+        anyhow::bail!("--wake-interval-ms must be at most {}", MAX_WAKE_INTERVAL.as_millis());
+    }
+    logging::init(&config)?;
+    if config.admin_key.is_none() {
+        warn!("[System] No admin key set, device registration and the admin endpoints are disabled");
+    }
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::load(cert, key)?),
+        (None, None) => None,
+        _ => anyhow::bail!("--tls-cert and --tls-key must be given together"),
+    };
+    let config = web::Data::new(config);
+    let metrics = web::Data::new(Metrics::default());
+    let load = web::Data::new(LoadMonitor::default());
+    let monitor = web::Data::new(SystemMonitor::new());
+    let notifier = web::Data::from(Notifier::new(
+        config.webhook_urls.clone(),
+        config.webhook_routes.clone(),
+        config.webhook_events.clone(),
+        config.webhook_concurrency,
+        config.webhook_queue,
+        config.webhook_retry.clone(),
+        config.quiet_hours,
+    ));
+    notifier.start();
+    let links = web::Data::new(WakeLinks::new(match &config.link_secret {
+        Some(secret) => secret.as_bytes().to_vec(),
+        None => {
+            info!("[System] WOL_LINK_SECRET not set, wake links will not survive a restart");
+            links::random_secret()
+        },
+    }));
+
+    let store = web::Data::new(DeviceStore::new(
+        args.backend,
+        &data_file,
+        config.device_dir.as_deref(),
+        config.backup.clone(),
+        config.storage_key.as_deref().map(StorageCipher::new),
+        config.groups_file.clone(),
+        match &config.mqtt_broker {
+            Some(broker) => Mqtt::connect(broker).map_err(|e| anyhow::anyhow!("Invalid WOL_MQTT_BROKER: {}", e))?,
+            None => Mqtt::disabled(),
+        },
+    )?);
+    DeviceStore::start_debounced_saver(store.clone(), config.save_debounce, config.save_jitter);
+    let schedules = web::Data::new(Schedules::default());
+    janitor::start(store.clone(), config.clone(), links.clone(), schedules.clone());
+    let grace = web::Data::new(WakeGrace::new(config.wake_grace));
+    let banner = config
+        .banner
+        .as_deref()
+        .map(|text| render_banner(text, config.banner_style))
+        .unwrap_or_default();
+    let frontend = Frontend::load(&config.static_dir, &banner, config.udp_fallback, grace.window().as_millis());
+    if let Err(e) = frontend.page() {
+        error!("[System] {}", e);
+    }
+    let frontend = web::Data::new(frontend);
+    let history = web::Data::new(WakeHistory::open(config.history_file.clone(), config.history_limit));
+    // Midnight in the server's time zone; wakes recorded since then count toward `/stats` after a restart
+    let today = chrono::Local::now()
+        .date_naive()
+        .and_hms_opt(0, 0, 0)
+        .and_then(|midnight| midnight.and_local_timezone(chrono::Local).earliest())
+        .map_or_else(Utc::now, |midnight| midnight.with_timezone(&Utc));
+    metrics.seed_wakes_today(history.successes_since(today) as u64);
+    WakeHistory::start_writer(history.clone());
+    schedule::start(schedules.clone(), store.clone(), metrics.clone(), notifier.clone(), config.clone(), history.clone());
+    if let Some(settings) = config.email.clone() {
+        email::start(settings, store.clone(), metrics.clone(), notifier.clone(), config.clone(), history.clone());
+    }
     
-    println!("[System] Server started at http://127.0.0.1:54001");
-    println!("[System] WebSocket service is running");
+    info!(
+        "[System] Server starting: bind={} ({}), backend={:?}, data={}",
+        bind_addr,
+        if tls.is_some() { "https" } else { "http" },
+        args.backend,
+        match &config.device_dir {
+            Some(dir) => format!("{} (one file per device)", dir.display()),
+            None => data_file,
+        }
+    );
+    info!("[System] WebSocket service is running");
 
-    HttpServer::new(move || {
+    let app_store = store.clone();
+    let app_history = history.clone();
+    let static_dir = config.static_dir.clone();
+    let server = HttpServer::new(move || {
         App::new()
-            .app_data(store.clone())
+            .app_data(app_store.clone())
+            .app_data(config.clone())
+            .app_data(metrics.clone())
+            .app_data(links.clone())
+            .app_data(load.clone())
+            .app_data(monitor.clone())
+            .app_data(notifier.clone())
+            .app_data(schedules.clone())
+            .app_data(grace.clone())
+            .app_data(app_history.clone())
+            .app_data(frontend.clone())
+            .app_data(web::JsonConfig::default().error_handler(error::json_error))
+            .wrap(middleware::from_fn(allowlist::restrict_wakes))
+            .wrap(middleware::from_fn(timeout::request_timeout))
+            .wrap(middleware::from_fn(shedding::shed_load))
+            // Outside the others, so preflights skip them and errors carry the headers too
+            .wrap(middleware::Condition::new(
+                !config.allowed_origins.is_empty() || !config.ws_allowed_origins.is_empty(),
+                cors::middleware(&config.allowed_origins, &config.ws_allowed_origins),
+            ))
+            // Outermost, so shed, timed-out and preflight requests are logged as well
+            .wrap(middleware::from_fn(access_log::log_request))
             .route("/", web::get().to(index))
+            .service(actix_files::Files::new("/static", &static_dir))
             .route("/register", web::post().to(register_device))
             .route("/devices", web::get().to(get_devices))
+            .route("/devices/export", web::get().to(export_devices))
+            .route("/devices/import", web::post().to(import_devices))
+            .route("/devices/{esp_id}", web::delete().to(delete_device))
+            .route("/devices/{esp_id}", web::patch().to(update_device))
             .route("/wake", web::post().to(wake_device))
+            .route("/wake-batch", web::post().to(wake_batch))
+            .route("/wake-group", web::post().to(wake_group))
+            .route("/groups", web::get().to(list_groups))
+            .route("/groups", web::post().to(create_group))
+            .route("/groups/{name}", web::delete().to(delete_group))
+            .route("/wake-and-wait", web::post().to(wake_and_wait))
+            .route("/wake-by-mac", web::post().to(wake_by_mac))
+            .route("/wake-by-mac/{mac}/routing", web::get().to(get_mac_routing))
+            .route("/power-state", web::post().to(set_power_state))
+            .route("/wake/{token}", web::get().to(wake_by_link))
+            .route("/wake/{esp_id}/ping", web::post().to(ping_device))
+            .route("/schedule", web::post().to(schedule_wake))
+            .route("/schedule", web::get().to(list_schedules))
+            .route("/schedule/{id}", web::delete().to(cancel_schedule))
+            .route("/schedules.ics", web::get().to(schedules_ics))
+            .route("/links", web::post().to(create_link))
+            .route("/links", web::get().to(list_links))
+            .route("/links/{id}", web::delete().to(revoke_link))
+            .route("/devices/{esp_id}/links", web::delete().to(revoke_device_links))
+            .route("/devices/{esp_id}/wake-info", web::get().to(get_wake_info))
+            .route("/devices/{esp_id}/loopback-check", web::post().to(loopback_check))
             .route("/ws", web::get().to(ws_index))
-    })
-    .bind("0.0.0.0:54001")?
-    .run()
-    .await
+            .route("/live", web::get().to(live_index))
+            .route("/command", web::post().to(send_command))
+            .route("/command/{esp_id}", web::post().to(send_device_command))
+            .route("/status", web::post().to(bulk_status))
+            .route("/events/recent", web::get().to(recent_events))
+            .route("/history", web::get().to(get_history))
+            .route("/connections", web::get().to(get_connections))
+            .route("/connections/{esp_id}/disconnect", web::post().to(disconnect_relay))
+            .route("/health", web::get().to(health))
+            .route("/ready", web::get().to(ready))
+            .route("/metrics", web::get().to(get_metrics))
+            .route("/system", web::get().to(get_system))
+            .route("/stats", web::get().to(get_stats))
+            .route("/broadcast", web::post().to(broadcast))
+            .route("/webhooks/test", web::post().to(test_webhooks))
+            .route("/webhooks/dead-letters", web::get().to(list_dead_letters))
+            .route("/webhooks/dead-letters/replay", web::post().to(replay_dead_letters))
+            .route("/save", web::post().to(save_now))
+    });
+    // Signals are handled below so relays can be closed before actix's graceful stop
+    let server = server.disable_signals();
+    let server = match tls {
+        Some(tls) => server.bind_rustls_0_23(&bind_addr, tls)?,
+        None => server.bind(&bind_addr)?,
+    };
+    let server = server.run();
+    actix::spawn(shut_down_on_signal(server.handle(), store.clone()));
+    store.ready.store(true, Ordering::Relaxed);
+    server.await?;
+
+    // The history writer may not have caught up with the last attempts
+    let pending = history.clone();
+    if let Err(e) = web::block(move || pending.flush()).await {
+        error!("[System] Failed to write wake history on shutdown: {}", e);
+    }
+    match DeviceStore::save_async(&store).await {
+        Ok(()) => info!("[System] Device data saved, exiting"),
+        Err(e) => error!("[System] Failed to save device data on shutdown: {}", e),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use std::fs;
+
+    /// An empty directory for one test's files
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wol-server-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A store kept in `dir` holding one device, `desk`, whose password is `secret`
+    fn test_store(dir: &Path) -> web::Data<DeviceStore> {
+        let file = dir.join("devices.json");
+        let store = DeviceStore::new(
+            Backend::Json,
+            file.to_str().unwrap(),
+            None,
+            None,
+            None,
+            dir.join("groups.json"),
+            Mqtt::disabled(),
+        )
+        .unwrap();
+        let device = Device {
+            mac_addresses: vec!["aa:bb:cc:dd:ee:01".to_string()],
+            password_hash: Some(password::hash("secret")),
+            ..Device::new("desk")
+        };
+        store.devices.write_or_recover().insert(device.esp_id.clone(), device);
+        web::Data::new(store)
+    }
+
+    fn test_config() -> web::Data<Config> {
+        web::Data::new(Config::from_env().unwrap())
+    }
+
+    fn test_notifier(config: &Config) -> web::Data<Notifier> {
+        web::Data::from(Notifier::new(Vec::new(), HashMap::new(), Vec::new(), 1, 1, config.webhook_retry.clone(), None))
+    }
+
+    /// A WebSocket upgrade request for `uri`
+    fn upgrade(uri: &str) -> TestRequest {
+        TestRequest::get()
+            .uri(uri)
+            .insert_header(("Upgrade", "websocket"))
+            .insert_header(("Connection", "Upgrade"))
+            .insert_header(("Sec-WebSocket-Version", "13"))
+            .insert_header(("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="))
+    }
+
+    #[actix_web::test]
+    async fn relay_upgrade_without_the_device_password_is_refused() {
+        let dir = scratch_dir("ws-auth");
+        let config = test_config();
+        let app = init_service(
+            App::new()
+                .app_data(test_store(&dir))
+                .app_data(config.clone())
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(test_notifier(&config))
+                .route("/ws", web::get().to(ws_index)),
+        )
+        .await;
+
+        for uri in ["/ws?esp_id=desk", "/ws?esp_id=desk&password=wrong"] {
+            let response = call_service(&app, upgrade(uri).to_request()).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{}", uri);
+        }
+        let response = call_service(&app, upgrade("/ws?esp_id=desk&password=secret").to_request()).await;
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    const RELAY: Option<IpAddr> = Some(IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 20)));
+    const OTHER: Option<IpAddr> = Some(IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 99)));
+
+    #[actix_web::test]
+    async fn browser_relay_handshake_gets_cors_and_upgrade_headers() {
+        const ORIGIN: &str = "http://relay.example";
+        let dir = scratch_dir("ws-handshake");
+        let config = test_config();
+        let app = init_service(
+            App::new()
+                .app_data(test_store(&dir))
+                .app_data(config.clone())
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(test_notifier(&config))
+                .wrap(cors::middleware(&[], &[ORIGIN.to_string()]))
+                .route("/devices", web::get().to(get_devices))
+                .route("/ws", web::get().to(ws_index)),
+        )
+        .await;
+
+        let preflight = TestRequest::default()
+            .method(actix_web::http::Method::OPTIONS)
+            .uri("/ws?esp_id=desk")
+            .insert_header(("Origin", ORIGIN))
+            .insert_header(("Access-Control-Request-Method", "GET"))
+            .insert_header(("Access-Control-Request-Headers", "sec-websocket-protocol"))
+            .to_request();
+        let response = call_service(&app, preflight).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers.get("access-control-allow-origin").unwrap(), ORIGIN);
+        assert!(headers.get("access-control-allow-methods").unwrap().to_str().unwrap().contains("GET"));
+        assert!(headers.get("access-control-allow-headers").unwrap().to_str().unwrap().contains("sec-websocket-protocol"));
+
+        let handshake = upgrade("/ws?esp_id=desk&password=secret").insert_header(("Origin", ORIGIN)).to_request();
+        let response = call_service(&app, handshake).await;
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        let headers = response.headers();
+        assert_eq!(headers.get("upgrade").unwrap(), "websocket");
+        // RFC 6455's worked example for the key `upgrade` sends
+        assert_eq!(headers.get("sec-websocket-accept").unwrap(), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(headers.get("access-control-allow-origin").unwrap(), ORIGIN);
+
+        // The relay origin is only for /ws
+        let request = TestRequest::get().uri("/devices").insert_header(("Origin", ORIGIN)).to_request();
+        let response = call_service(&app, request).await;
+        assert!(response.headers().get("access-control-allow-origin").is_none());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn device_lookups_share_the_lock() {
+        let dir = scratch_dir("rwlock");
+        let store = test_store(&dir);
+        // With an exclusive lock this lookup would wait for the guard held here
+        let held = store.devices.read_or_recover();
+        let (done, finished) = std::sync::mpsc::channel();
+        let reader = store.clone();
+        std::thread::spawn(move || done.send(reader.find("desk").is_some()).unwrap());
+        assert_eq!(finished.recv_timeout(Duration::from_secs(5)), Ok(true));
+        drop(held);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    /// Lookups racing against writes, as under the polling UI; run with
+    /// `cargo test --release -- --ignored --nocapture device_lookups_under_load`
+    #[test]
+    #[ignore]
+    fn device_lookups_under_load() {
+        const READERS: usize = 8;
+        const LOOKUPS: usize = 200_000;
+        let dir = scratch_dir("rwlock-load");
+        let store = test_store(&dir);
+        let stop = std::sync::Arc::new(AtomicBool::new(false));
+
+        let writer = {
+            let (store, stop) = (store.clone(), stop.clone());
+            std::thread::spawn(move || {
+                let mut writes = 0u64;
+                while !stop.load(Ordering::Relaxed) {
+                    if let Some(device) = store.devices.write_or_recover().get_mut("desk") {
+                        device.last_seen = Some(Utc::now());
+                    }
+                    writes += 1;
+                    std::thread::sleep(Duration::from_micros(100));
+                }
+                writes
+            })
+        };
+        let started = Instant::now();
+        let readers: Vec<_> = (0..READERS)
+            .map(|_| {
+                let store = store.clone();
+                std::thread::spawn(move || (0..LOOKUPS).filter(|_| store.find("desk").is_some()).count())
+            })
+            .collect();
+        let found: usize = readers.into_iter().map(|reader| reader.join().unwrap()).sum();
+        let elapsed = started.elapsed();
+        stop.store(true, Ordering::Relaxed);
+        let writes = writer.join().unwrap();
+
+        assert_eq!(found, READERS * LOOKUPS);
+        println!(
+            "{} lookups on {} threads alongside {} writes in {:?} ({:.0} lookups/s)",
+            found,
+            READERS,
+            writes,
+            elapsed,
+            found as f64 / elapsed.as_secs_f64()
+        );
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn reconnecting_relay_takes_over_before_old_connection_stops() {
+        // The old connection is still registered when the relay reconnects after a blip
+        let mut open = vec![(1, RELAY)];
+        let replaced = takeover(&open, RELAY, 1).expect("same relay is admitted");
+        assert_eq!(replaced, vec![1]);
+        open.retain(|(id, _)| !replaced.contains(id));
+        open.push((2, RELAY));
+
+        // The stale actor's stopped() only removes its own entry, leaving the replacement
+        open.retain(|(id, _)| *id != 1);
+        assert_eq!(open, vec![(2, RELAY)]);
+    }
+
+    #[test]
+    fn other_relay_is_refused_at_the_limit() {
+        assert_eq!(takeover(&[(1, RELAY)], OTHER, 1), None);
+        assert_eq!(takeover(&[(1, RELAY)], None, 1), None);
+        assert_eq!(takeover(&[(1, None)], None, 1), None);
+    }
+
+    #[test]
+    fn relays_below_the_limit_replace_nothing() {
+        assert_eq!(takeover(&[], OTHER, 1), Some(vec![]));
+        assert_eq!(takeover(&[(1, RELAY)], RELAY, 2), Some(vec![]));
+        // Only as many of its own connections as needed make room, oldest first
+        assert_eq!(takeover(&[(1, RELAY), (2, OTHER), (3, RELAY)], RELAY, 2), Some(vec![1, 3]));
+        assert_eq!(takeover(&[(1, RELAY), (2, OTHER), (3, RELAY)], RELAY, 3), Some(vec![1]));
+    }
 }
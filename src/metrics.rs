@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use chrono::{Local, NaiveDate};
+
+use crate::events::{LifecycleEvent, RecentEvents};
+use crate::locks::MutexExt;
+
+/// Prometheus counters collected from the wake and connection paths
+#[derive(Default)]
+pub struct Metrics {
+    wakes_total: AtomicU64,
+    wake_failures_total: AtomicU64,
+    relay_connections_total: AtomicU64,
+    relay_disconnections_total: AtomicU64,
+    device_wakes: Mutex<HashMap<String, u64>>,
+    /// Successful wakes on the server's local day, with that day
+    wakes_today: Mutex<Option<(NaiveDate, u64)>>,
+    /// Live view of connects, disconnects and wakes
+    events: RecentEvents,
+}
+
+impl Metrics {
+    /// Count a wake command, successful or not
+    pub fn record_wake(&self, esp_id: &str, success: bool) {
+        if !success {
+            self.wake_failures_total.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        self.wakes_total.fetch_add(1, Ordering::Relaxed);
+        let mut device_wakes = self.device_wakes.lock_or_recover();
+        *device_wakes.entry(esp_id.to_string()).or_insert(0) += 1;
+
+        let today = Local::now().date_naive();
+        match &mut *self.wakes_today.lock_or_recover() {
+            Some((day, count)) if *day == today => *count += 1,
+            slot => *slot = Some((today, 1)),
+        }
+    }
+
+    /// Successful wakes so far on the server's local day, through any endpoint
+    pub fn wakes_today(&self) -> u64 {
+        match *self.wakes_today.lock_or_recover() {
+            Some((day, count)) if day == Local::now().date_naive() => count,
+            _ => 0,
+        }
+    }
+
+    /// Start today's count at `count`, for wakes made before a restart
+    pub fn seed_wakes_today(&self, count: u64) {
+        *self.wakes_today.lock_or_recover() = Some((Local::now().date_naive(), count));
+    }
+
+    /// Count a relay WebSocket connecting
+    pub fn record_connect(&self, esp_id: &str) {
+        self.relay_connections_total.fetch_add(1, Ordering::Relaxed);
+        self.events.push(esp_id, "connect", None);
+    }
+
+    /// Count a relay WebSocket disconnecting
+    pub fn record_disconnect(&self, esp_id: &str) {
+        self.relay_disconnections_total.fetch_add(1, Ordering::Relaxed);
+        self.events.push(esp_id, "disconnect", None);
+    }
+
+    /// Add an entry to the recent-events buffer
+    pub fn record_event(&self, esp_id: &str, event: &'static str, detail: Option<String>) {
+        self.events.push(esp_id, event, detail);
+    }
+
+    /// Most recent lifecycle events, newest first
+    pub fn recent_events(&self, limit: usize) -> Vec<LifecycleEvent> {
+        self.events.latest(limit)
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    ///
+    /// `devices` lists every registered esp_id with its online flag. Per-device
+    /// series are only emitted when `per_device` is set, since each device adds
+    /// its own label value.
+    pub fn render(&self, devices: &[(String, bool)], per_device: bool) -> String {
+        let online = devices.iter().filter(|(_, online)| *online).count();
+        let mut out = String::new();
+
+        write_metric(&mut out, "wol_wakes_total", "counter", "Wake commands sent", self.wakes_total.load(Ordering::Relaxed));
+        write_metric(&mut out, "wol_wake_failures_total", "counter", "Wake commands that could not be sent", self.wake_failures_total.load(Ordering::Relaxed));
+        write_metric(&mut out, "wol_relay_connections_total", "counter", "Relay WebSocket connections accepted", self.relay_connections_total.load(Ordering::Relaxed));
+        write_metric(&mut out, "wol_relay_disconnections_total", "counter", "Relay WebSocket connections closed", self.relay_disconnections_total.load(Ordering::Relaxed));
+        write_metric(&mut out, "wol_devices_registered", "gauge", "Registered devices", devices.len() as u64);
+        write_metric(&mut out, "wol_devices_online", "gauge", "Devices with a connected relay", online as u64);
+
+        if per_device {
+            let device_wakes = self.device_wakes.lock_or_recover();
+
+            let _ = writeln!(out, "# HELP wol_device_wakes_total Wake commands sent per device");
+            let _ = writeln!(out, "# TYPE wol_device_wakes_total counter");
+            for (esp_id, _) in devices {
+                let count = device_wakes.get(esp_id).copied().unwrap_or(0);
+                let _ = writeln!(out, "wol_device_wakes_total{{esp_id=\"{}\"}} {}", escape_label(esp_id), count);
+            }
+
+            let _ = writeln!(out, "# HELP wol_device_online Whether the device's relay is connected");
+            let _ = writeln!(out, "# TYPE wol_device_online gauge");
+            for (esp_id, online) in devices {
+                let _ = writeln!(out, "wol_device_online{{esp_id=\"{}\"}} {}", escape_label(esp_id), u8::from(*online));
+            }
+        }
+
+        out
+    }
+}
+
+/// Append a single unlabeled metric with its HELP and TYPE lines
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Escape a label value per the exposition format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
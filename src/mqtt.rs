@@ -0,0 +1,104 @@
+use std::time::Duration;
+
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::unix_now;
+
+/// Port used when `WOL_MQTT_BROKER` names only a host
+const DEFAULT_PORT: u16 = 1883;
+
+/// Requests queued for the broker before publishes are dropped
+const QUEUE_CAPACITY: usize = 100;
+
+/// Pause before reconnecting after the broker connection fails
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Publishes device status and wake events to an MQTT broker.
+///
+/// `wol/{esp_id}/status` carries a retained `online`/`offline`;
+/// `wol/{esp_id}/wake` carries the same JSON as the `wake` webhook.
+/// Publishing never waits on the broker: while it is unreachable, messages
+/// beyond the queue are dropped with a warning. Every method is a no-op when
+/// no broker is configured.
+pub struct Mqtt {
+    client: Option<AsyncClient>,
+}
+
+impl Mqtt {
+    pub fn disabled() -> Self {
+        Self { client: None }
+    }
+
+    /// Connect to `broker` (`host[:port]`, optionally prefixed with `mqtt://`),
+    /// keeping the connection up from a background task
+    pub fn connect(broker: &str) -> Result<Self, String> {
+        let (host, port) = parse_broker(broker)?;
+        let mut options = MqttOptions::new(format!("wol-server-{}", std::process::id()), host.clone(), port);
+        options.set_keep_alive(Duration::from_secs(30));
+        let (client, mut event_loop) = AsyncClient::new(options, QUEUE_CAPACITY);
+
+        actix::spawn(async move {
+            // Only the first failure of an outage is worth a warning
+            let mut failing = false;
+            loop {
+                match event_loop.poll().await {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("[MQTT] Connected to broker {}:{}", host, port);
+                        failing = false;
+                    },
+                    Ok(_) => {},
+                    Err(e) => {
+                        if !failing {
+                            warn!("[MQTT] Broker {}:{} unreachable, retrying: {}", host, port, e);
+                            failing = true;
+                        }
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                    },
+                }
+            }
+        });
+        Ok(Self { client: Some(client) })
+    }
+
+    /// Publish whether any relay of `esp_id` is connected
+    pub fn publish_status(&self, esp_id: &str, online: bool) {
+        self.publish(&format!("wol/{}/status", esp_id), true, if online { "online" } else { "offline" }.into());
+    }
+
+    /// Publish the outcome of a wake
+    pub fn publish_wake(&self, esp_id: &str, event: &Value) {
+        if self.client.is_none() {
+            return;
+        }
+        let mut event = event.clone();
+        if let Value::Object(fields) = &mut event {
+            fields.insert("timestamp".to_string(), json!(unix_now()));
+        }
+        self.publish(&format!("wol/{}/wake", esp_id), false, event.to_string().into_bytes());
+    }
+
+    fn publish(&self, topic: &str, retain: bool, payload: Vec<u8>) {
+        let Some(client) = &self.client else {
+            return;
+        };
+        if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, retain, payload) {
+            warn!("[MQTT] Dropped message for {}: {}", topic, e);
+        }
+    }
+}
+
+/// Split `host[:port]`, with or without an `mqtt://` scheme
+fn parse_broker(spec: &str) -> Result<(String, u16), String> {
+    let address = spec.trim();
+    let address = address.strip_prefix("mqtt://").unwrap_or(address).trim_end_matches('/');
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| format!("'{}' is not a valid port", port))?),
+        None => (address, DEFAULT_PORT),
+    };
+    if host.is_empty() {
+        return Err(format!("'{}' has no host", spec));
+    }
+    Ok((host.to_string(), port))
+}
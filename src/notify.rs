@@ -0,0 +1,428 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{Local, NaiveTime};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::{Notify, Semaphore};
+use tracing::{error, info, warn};
+
+use crate::locks::MutexExt;
+use crate::unix_now;
+
+/// Per-delivery timeout for outbound webhook calls
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How failed deliveries are retried and where they end up afterwards
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt
+    pub retries: u32,
+    /// Delay before the first retry; doubles for each one after
+    pub base_delay: Duration,
+    /// JSON-lines file receiving deliveries that failed every attempt
+    pub dead_letter: PathBuf,
+}
+
+/// Events held back during quiet hours
+const QUIET_EVENTS: &[&str] = &["wake", "wake_ack"];
+
+/// How often held-back events are checked for a digest once quiet hours end
+const DIGEST_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Daily local-time window in which wake notifications are held back; wakes still happen
+#[derive(Debug, Clone, Copy)]
+pub struct QuietHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    /// Send each endpoint one `wake_digest` when the window ends instead of dropping the events
+    pub digest: bool,
+}
+
+impl QuietHours {
+    /// Parse `HH:MM-HH:MM`; the window may wrap past midnight
+    pub fn parse(spec: &str, digest: bool) -> Result<Self, String> {
+        let (start, end) = spec.split_once('-').ok_or_else(|| "expected HH:MM-HH:MM".to_string())?;
+        let parse = |time: &str| {
+            NaiveTime::parse_from_str(time.trim(), "%H:%M").map_err(|_| format!("'{}' is not a HH:MM time", time.trim()))
+        };
+        Ok(Self { start: parse(start)?, end: parse(end)?, digest })
+    }
+
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// A webhook call waiting to be sent
+struct Delivery {
+    url: String,
+    body: Value,
+    /// Attempts made so far
+    attempts: u32,
+}
+
+/// Result of sending a test payload to one webhook
+#[derive(Debug, Serialize)]
+pub struct TestDelivery {
+    pub url: String,
+    /// HTTP status returned, if the endpoint answered
+    pub status: Option<u16>,
+    pub ok: bool,
+    pub elapsed_ms: u64,
+    pub error: Option<String>,
+}
+
+/// A delivery that failed every attempt, as stored in the dead-letter file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub url: String,
+    pub body: Value,
+    pub attempts: u32,
+    pub error: String,
+    /// Unix seconds
+    pub failed_at: u64,
+}
+
+/// Outbound webhook dispatcher.
+///
+/// Events are queued and delivered by a single dispatcher task that holds at
+/// most `concurrency` calls in flight. When the queue is full the oldest
+/// pending delivery is dropped to make room.
+///
+/// Every event goes to `urls`; device events additionally go to the routes
+/// of any tag the device carries.
+pub struct Notifier {
+    urls: Vec<String>,
+    /// Device tag -> extra webhook endpoints
+    routes: HashMap<String, Vec<String>>,
+    /// Events delivered; empty delivers every event
+    events: Vec<String>,
+    client: reqwest::Client,
+    queue: Mutex<VecDeque<Delivery>>,
+    capacity: usize,
+    pending: Notify,
+    in_flight: Arc<Semaphore>,
+    retry: RetryPolicy,
+    /// Serializes dead-letter file access
+    dead_letter_lock: Mutex<()>,
+    quiet_hours: Option<QuietHours>,
+    /// Deliveries held back during quiet hours for the digest
+    held: Mutex<Vec<Delivery>>,
+}
+
+impl Notifier {
+    /// Create a notifier posting `events` (all when empty) to `urls` plus per-tag `routes`;
+    /// webhooks are disabled when both are empty
+    pub fn new(
+        urls: Vec<String>,
+        routes: HashMap<String, Vec<String>>,
+        events: Vec<String>,
+        concurrency: usize,
+        capacity: usize,
+        retry: RetryPolicy,
+        quiet_hours: Option<QuietHours>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            urls,
+            routes,
+            events,
+            client: reqwest::Client::builder()
+                .timeout(DELIVERY_TIMEOUT)
+                .build()
+                .expect("Failed to build webhook client"),
+            queue: Mutex::new(VecDeque::new()),
+            capacity: capacity.max(1),
+            pending: Notify::new(),
+            in_flight: Arc::new(Semaphore::new(concurrency.max(1))),
+            retry,
+            dead_letter_lock: Mutex::new(()),
+            quiet_hours,
+            held: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Whether any webhook is configured
+    pub fn enabled(&self) -> bool {
+        !self.urls.is_empty() || self.routes.values().any(|urls| !urls.is_empty())
+    }
+
+    /// Queue `event` for delivery to every untagged webhook
+    pub fn notify(&self, event: &str, payload: Value) {
+        self.notify_tagged(event, payload, &[]);
+    }
+
+    /// Queue `event` for the untagged webhooks and those routed to any of `tags`
+    pub fn notify_tagged(&self, event: &str, mut payload: Value, tags: &[String]) {
+        if !self.events.is_empty() && !self.events.iter().any(|wanted| wanted == event) {
+            return;
+        }
+        let mut targets = self.urls.iter().collect::<Vec<_>>();
+        for url in tags.iter().filter_map(|tag| self.routes.get(tag)).flatten() {
+            // A device matching several tags routed to the same endpoint is notified once
+            if !targets.contains(&url) {
+                targets.push(url);
+            }
+        }
+        if targets.is_empty() {
+            return;
+        }
+        if let Value::Object(fields) = &mut payload {
+            fields.insert("event".to_string(), json!(event));
+            fields.insert("timestamp".to_string(), json!(unix_now()));
+        }
+
+        let deliveries = targets.into_iter().map(|url| Delivery { url: url.clone(), body: payload.clone(), attempts: 0 });
+        let quiet = self.quiet_hours.filter(|quiet| quiet.contains(Local::now().time()));
+        match quiet {
+            Some(quiet) if QUIET_EVENTS.contains(&event) => {
+                if quiet.digest {
+                    self.held.lock_or_recover().extend(deliveries);
+                }
+            },
+            _ => {
+                if quiet.is_none() {
+                    self.send_digest();
+                }
+                for delivery in deliveries {
+                    self.enqueue(delivery);
+                }
+            },
+        }
+    }
+
+    /// Queue one `wake_digest` per endpoint for the events held during quiet hours
+    fn send_digest(&self) {
+        let held = std::mem::take(&mut *self.held.lock_or_recover());
+        if held.is_empty() {
+            return;
+        }
+
+        let mut by_url: Vec<(String, Vec<Value>)> = Vec::new();
+        for delivery in held {
+            match by_url.iter_mut().find(|(url, _)| *url == delivery.url) {
+                Some((_, events)) => events.push(delivery.body),
+                None => by_url.push((delivery.url, vec![delivery.body])),
+            }
+        }
+        info!("[Webhook] Quiet hours over, sending digests to {} webhook(s)", by_url.len());
+        for (url, events) in by_url {
+            let body = json!({
+                "event": "wake_digest",
+                "timestamp": unix_now(),
+                "count": events.len(),
+                "events": events,
+            });
+            self.enqueue(Delivery { url, body, attempts: 0 });
+        }
+    }
+
+    /// Add a delivery to the queue, dropping the oldest one when full
+    fn enqueue(&self, delivery: Delivery) {
+        {
+            let mut queue = self.queue.lock_or_recover();
+            if queue.len() >= self.capacity {
+                if let Some(dropped) = queue.pop_front() {
+                    warn!("[Webhook] Queue full, dropped oldest delivery: url={}, event={}", dropped.url, dropped.body["event"]);
+                }
+            }
+            queue.push_back(delivery);
+        }
+        self.pending.notify_one();
+    }
+
+    /// Start the dispatcher task; call once from within the runtime
+    pub fn start(self: &Arc<Self>) {
+        if !self.enabled() {
+            return;
+        }
+        info!(
+            "[Webhook] Delivering events to {} webhook(s) plus routes for {} tag(s)",
+            self.urls.len(),
+            self.routes.len()
+        );
+
+        if let Some(quiet) = self.quiet_hours.filter(|quiet| quiet.digest) {
+            let notifier = Arc::clone(self);
+            actix::spawn(async move {
+                let mut interval = tokio::time::interval(DIGEST_CHECK_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    if !quiet.contains(Local::now().time()) {
+                        notifier.send_digest();
+                    }
+                }
+            });
+        }
+
+        let notifier = Arc::clone(self);
+        actix::spawn(async move {
+            loop {
+                // Take a slot first so pending deliveries wait in the bounded queue
+                let permit = match Arc::clone(&notifier.in_flight).acquire_owned().await {
+                    Ok(permit) => permit,
+                    Err(_) => return,
+                };
+                let delivery = notifier.next_delivery().await;
+
+                let notifier = Arc::clone(&notifier);
+                actix::spawn(async move {
+                    let result = deliver(&notifier.client, &delivery).await;
+                    drop(permit);
+                    if let Err(e) = result {
+                        notifier.retry_or_bury(delivery, e).await;
+                    }
+                });
+            }
+        });
+    }
+
+    /// Every configured endpoint, untagged ones first, without duplicates
+    fn all_urls(&self) -> Vec<&String> {
+        let mut routed = self.routes.values().flatten().collect::<Vec<_>>();
+        routed.sort();
+        let mut urls = self.urls.iter().collect::<Vec<_>>();
+        for url in routed {
+            if !urls.contains(&url) {
+                urls.push(url);
+            }
+        }
+        urls
+    }
+
+    /// Send a synthetic `test` event to every configured endpoint right away, bypassing the queue
+    pub async fn send_test(&self) -> Vec<TestDelivery> {
+        let body = json!({
+            "event": "test",
+            "timestamp": unix_now(),
+            "message": "Test notification from wol-server",
+        });
+
+        let mut results = Vec::new();
+        for url in self.all_urls() {
+            let started = Instant::now();
+            let result = post(&self.client, url, &body).await;
+            let elapsed_ms = started.elapsed().as_millis() as u64;
+            results.push(match result {
+                Ok(status) => TestDelivery {
+                    url: url.clone(),
+                    status: Some(status.as_u16()),
+                    ok: status.is_success(),
+                    elapsed_ms,
+                    error: None,
+                },
+                Err(e) => TestDelivery { url: url.clone(), status: None, ok: false, elapsed_ms, error: Some(e.to_string()) },
+            });
+        }
+        results
+    }
+
+    /// Queue a failed delivery again after its backoff, or dead-letter it once retries run out
+    async fn retry_or_bury(&self, mut delivery: Delivery, error: String) {
+        delivery.attempts += 1;
+        if delivery.attempts <= self.retry.retries {
+            // Back off outside the semaphore so waiting retries don't hold delivery slots
+            let delay = self.retry.base_delay.saturating_mul(1 << (delivery.attempts - 1).min(16));
+            warn!(
+                "[Webhook] Delivery failed, retrying in {:?}: url={}, attempt={}, error={}",
+                delay, delivery.url, delivery.attempts, error
+            );
+            tokio::time::sleep(delay).await;
+            self.enqueue(delivery);
+            return;
+        }
+
+        warn!("[Webhook] Giving up on delivery after {} attempt(s): url={}, error={}", delivery.attempts, delivery.url, error);
+        let letter = DeadLetter {
+            url: delivery.url,
+            body: delivery.body,
+            attempts: delivery.attempts,
+            error,
+            failed_at: unix_now(),
+        };
+        if let Err(e) = self.append_dead_letter(&letter) {
+            error!("[Webhook] Failed to write dead letter: {}", e);
+        }
+    }
+
+    fn append_dead_letter(&self, letter: &DeadLetter) -> io::Result<()> {
+        let _guard = self.dead_letter_lock.lock_or_recover();
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.retry.dead_letter)?;
+        writeln!(file, "{}", serde_json::to_string(letter)?)
+    }
+
+    /// Deliveries that failed every attempt, oldest first
+    pub fn dead_letters(&self) -> io::Result<Vec<DeadLetter>> {
+        let _guard = self.dead_letter_lock.lock_or_recover();
+        self.read_dead_letters()
+    }
+
+    /// Queue every dead letter for delivery again and clear the file, returning how many were queued
+    pub fn replay_dead_letters(&self) -> io::Result<usize> {
+        let letters = {
+            let _guard = self.dead_letter_lock.lock_or_recover();
+            let letters = self.read_dead_letters()?;
+            if !letters.is_empty() {
+                fs::write(&self.retry.dead_letter, "")?;
+            }
+            letters
+        };
+
+        let count = letters.len();
+        for letter in letters {
+            self.enqueue(Delivery { url: letter.url, body: letter.body, attempts: 0 });
+        }
+        Ok(count)
+    }
+
+    fn read_dead_letters(&self) -> io::Result<Vec<DeadLetter>> {
+        let content = match fs::read_to_string(&self.retry.dead_letter) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        Ok(content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(letter) => Some(letter),
+                Err(e) => {
+                    warn!("[Webhook] Skipping unreadable dead letter: {}", e);
+                    None
+                },
+            })
+            .collect())
+    }
+
+    /// Wait for and pop the oldest queued delivery
+    async fn next_delivery(&self) -> Delivery {
+        loop {
+            if let Some(delivery) = self.queue.lock_or_recover().pop_front() {
+                return delivery;
+            }
+            self.pending.notified().await;
+        }
+    }
+}
+
+/// Post a single delivery, describing why it failed
+async fn deliver(client: &reqwest::Client, delivery: &Delivery) -> Result<(), String> {
+    match post(client, &delivery.url, &delivery.body).await {
+        Ok(status) if status.is_success() => Ok(()),
+        Ok(status) => Err(format!("rejected with status {}", status)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+async fn post(client: &reqwest::Client, url: &str, body: &Value) -> reqwest::Result<reqwest::StatusCode> {
+    client.post(url).json(body).send().await.map(|response| response.status())
+}
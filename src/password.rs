@@ -0,0 +1,34 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+
+/// Memory cost in KiB (19 MiB), the OWASP-recommended Argon2id minimum
+const MEMORY_KIB: u32 = 19 * 1024;
+
+/// Passes over memory
+const ITERATIONS: u32 = 2;
+
+/// Lanes; wakes are infrequent, so one thread per hash is enough
+const PARALLELISM: u32 = 1;
+
+fn hasher() -> Argon2<'static> {
+    let params = Params::new(MEMORY_KIB, ITERATIONS, PARALLELISM, None).expect("valid Argon2 parameters");
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+}
+
+/// Hash `password` into a PHC string (`$argon2id$v=19$m=...`)
+pub fn hash(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    hasher()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Argon2 hashing with valid parameters")
+        .to_string()
+}
+
+/// Check `password` against a PHC string from `hash`.
+///
+/// The parameters are read from the string itself, so hashes made with older
+/// cost settings keep verifying after these constants change.
+pub fn verify(stored: &str, password: &str) -> bool {
+    PasswordHash::new(stored).is_ok_and(|parsed| hasher().verify_password(password.as_bytes(), &parsed).is_ok())
+}
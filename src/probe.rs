@@ -0,0 +1,46 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use std::sync::OnceLock;
+
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+/// How a TCP connect probe ended
+pub enum Probe {
+    /// Connected after the given time
+    Connected(Duration),
+    /// Refused, unresolvable or otherwise failed before `limit`
+    Failed(io::Error),
+    /// Nothing answered within `limit`
+    TimedOut,
+}
+
+/// Try a TCP connection to `host:port`, giving up after `limit`
+pub async fn connect(host: &str, port: u16, limit: Duration) -> Probe {
+    let started = Instant::now();
+    match timeout(limit, TcpStream::connect((host, port))).await {
+        Ok(Ok(_)) => Probe::Connected(started.elapsed()),
+        Ok(Err(e)) => Probe::Failed(e),
+        Err(_) => Probe::TimedOut,
+    }
+}
+
+/// Check whether `host:port` accepts a TCP connection within `limit`
+pub async fn is_reachable(host: &str, port: u16, limit: Duration) -> bool {
+    matches!(connect(host, port, limit).await, Probe::Connected(_))
+}
+
+/// Check whether a GET of `url` answers within `limit` with `expected`, or any 2xx when unset
+pub async fn is_healthy(url: &str, expected: Option<u16>, limit: Duration) -> bool {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    let client = CLIENT.get_or_init(reqwest::Client::new);
+
+    match client.get(url).timeout(limit).send().await {
+        Ok(response) => match expected {
+            Some(expected) => response.status().as_u16() == expected,
+            None => response.status().is_success(),
+        },
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::backup::BackupPolicy;
+use crate::crypto::{self, StorageCipher};
+use crate::device_dir::DeviceDir;
+use crate::sqlite::SqliteRepository;
+use crate::{atomic_file, schema, Device};
+
+/// Storage selected with `--backend`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// `devices.json`, or one file per device when WOL_DEVICE_DIR is set
+    Json,
+    /// A SQLite database with one row per device
+    Sqlite,
+}
+
+impl Backend {
+    /// Data file used when `--data-file` is not given
+    pub fn default_file(self) -> &'static str {
+        match self {
+            Backend::Json => "devices.json",
+            Backend::Sqlite => "devices.db",
+        }
+    }
+}
+
+/// Where device data is persisted.
+///
+/// The server only reads through this once, when `DeviceStore` loads its
+/// in-memory copy; after that requests are answered from the copy and the
+/// repository just receives changes. `get` serves the offline `device`
+/// commands. Every call blocks on IO.
+pub trait DeviceRepository: Send + Sync {
+    /// Every stored device, keyed by esp_id
+    fn list(&self) -> io::Result<HashMap<String, Device>>;
+    /// One stored device, read without loading the others where the storage allows
+    fn get(&self, esp_id: &str) -> io::Result<Option<Device>>;
+    /// Store a device, replacing any with the same esp_id
+    fn insert(&self, device: &Device) -> io::Result<()>;
+    /// Returns whether the device was stored
+    fn remove(&self, esp_id: &str) -> io::Result<bool>;
+    /// Make storage hold exactly `devices`, for changes made in memory
+    fn save_all(&self, devices: &HashMap<String, Device>) -> io::Result<()>;
+}
+
+/// Open the storage for `backend`, upgrading data saved by older versions
+pub fn open(
+    backend: Backend,
+    file_path: &str,
+    dir: Option<&Path>,
+    backup: Option<BackupPolicy>,
+    cipher: Option<StorageCipher>,
+) -> anyhow::Result<Box<dyn DeviceRepository>> {
+    match (backend, dir) {
+        (Backend::Json, Some(dir)) => {
+            let device_dir = DeviceDir::open(dir, backup, cipher)
+                .map_err(|e| anyhow::anyhow!("Failed to load device directory {}: {}", dir.display(), e))?;
+            Ok(Box::new(device_dir))
+        },
+        (Backend::Json, None) => Ok(Box::new(DeviceFile::open(file_path, backup, cipher)?)),
+        (Backend::Sqlite, Some(_)) => anyhow::bail!("WOL_DEVICE_DIR only applies to the json backend"),
+        (Backend::Sqlite, None) => {
+            if cipher.is_some() {
+                anyhow::bail!("WOL_STORAGE_KEY is not supported by the sqlite backend");
+            }
+            let db = SqliteRepository::open(Path::new(file_path), backup)
+                .map_err(|e| anyhow::anyhow!("Failed to open database {}: {}", file_path, e))?;
+            Ok(Box::new(db))
+        },
+    }
+}
+
+/// Every device in one JSON object keyed by esp_id, rewritten as a whole
+pub struct DeviceFile {
+    path: PathBuf,
+    /// Backup rotation applied before each write
+    backup: Option<BackupPolicy>,
+    /// Encrypts the file when `WOL_STORAGE_KEY` is set
+    cipher: Option<StorageCipher>,
+}
+
+impl DeviceFile {
+    /// Open `path`, creating it if needed and rewriting it if saved in an older format
+    fn open(path: &str, backup: Option<BackupPolicy>, cipher: Option<StorageCipher>) -> anyhow::Result<Self> {
+        let file = Self { path: PathBuf::from(path), backup, cipher };
+        if !file.path.exists() {
+            fs::write(&file.path, crypto::encode("{}".to_string(), file.cipher.as_ref()))
+                .map_err(|e| anyhow::anyhow!("Failed to create device file {}: {}", path, e))?;
+        }
+
+        let content = fs::read_to_string(&file.path).map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path, e))?;
+        let json = crypto::decode(&content, file.cipher.as_ref())
+            .map_err(|e| anyhow::anyhow!("Failed to load {}: {}", path, e))?;
+        // Refuse to start rather than come up empty and overwrite the registrations
+        let devices = schema::parse_devices(&json).map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", path, e))?;
+        if schema::needs_upgrade(&json, &devices) {
+            info!("[Storage] Rewriting {} in the current format", path);
+            file.save_all(&devices).map_err(|e| anyhow::anyhow!("Failed to save {}: {}", path, e))?;
+        }
+        Ok(file)
+    }
+
+    /// The file's JSON, decrypted if needed
+    fn read(&self) -> io::Result<String> {
+        let content = fs::read_to_string(&self.path)?;
+        crypto::decode(&content, self.cipher.as_ref()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+}
+
+impl DeviceRepository for DeviceFile {
+    fn list(&self) -> io::Result<HashMap<String, Device>> {
+        schema::parse_devices(&self.read()?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// The whole file is still read and decrypted, but only this device's entry is parsed.
+    /// `open` rewrites older files, so entries are keyed by their esp_id.
+    fn get(&self, esp_id: &str) -> io::Result<Option<Device>> {
+        let mut value: Value = serde_json::from_str(&self.read()?)?;
+        let Some(entry) = value.as_object_mut().and_then(|devices| devices.remove(esp_id)) else {
+            return Ok(None);
+        };
+        let device = schema::parse_device(entry, Some(esp_id))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("device {}: {}", esp_id, e)))?;
+        Ok(Some(device).filter(|device| device.esp_id == esp_id))
+    }
+
+    fn insert(&self, device: &Device) -> io::Result<()> {
+        let mut devices = self.list()?;
+        devices.insert(device.esp_id.clone(), device.clone());
+        self.save_all(&devices)
+    }
+
+    fn remove(&self, esp_id: &str) -> io::Result<bool> {
+        let mut devices = self.list()?;
+        if devices.remove(esp_id).is_none() {
+            return Ok(false);
+        }
+        self.save_all(&devices)?;
+        Ok(true)
+    }
+
+    fn save_all(&self, devices: &HashMap<String, Device>) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(devices)?;
+        if let Some(backup) = &self.backup {
+            if let Err(e) = backup.rotate(&self.path) {
+                warn!("[Backup] Failed to back up device file: {}", e);
+            }
+        }
+        atomic_file::write(&self.path, crypto::encode(json, self.cipher.as_ref()))
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::web;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::history::{WakeHistory, WakeSource};
+use crate::locks::{MutexExt, RwLockExt};
+use crate::metrics::Metrics;
+use crate::notify::Notifier;
+use crate::{dispatch_wake, DeviceStore};
+
+/// How often pending jobs are checked for being due
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Length of each calendar event; wakes are instantaneous but calendars want a span
+const EVENT_LENGTH: chrono::Duration = chrono::Duration::minutes(5);
+
+/// A wake queued for a future time
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduledWake {
+    /// Random job identifier
+    pub id: String,
+    pub esp_id: String,
+    pub wake_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Pending scheduled wakes, kept in memory
+#[derive(Default)]
+pub struct Schedules {
+    jobs: Mutex<Vec<ScheduledWake>>,
+}
+
+impl Schedules {
+    /// Queue a wake of `esp_id` at `wake_at`
+    pub fn add(&self, esp_id: &str, wake_at: DateTime<Utc>) -> ScheduledWake {
+        let mut id = [0u8; 9];
+        rand::rng().fill_bytes(&mut id);
+
+        let job = ScheduledWake {
+            id: URL_SAFE_NO_PAD.encode(id),
+            esp_id: esp_id.to_string(),
+            wake_at,
+            created_at: Utc::now(),
+        };
+        self.jobs.lock_or_recover().push(job.clone());
+        job
+    }
+
+    /// Pending jobs, soonest first
+    pub fn pending(&self) -> Vec<ScheduledWake> {
+        let mut jobs = self.jobs.lock_or_recover().clone();
+        jobs.sort_by_key(|job| job.wake_at);
+        jobs
+    }
+
+    /// Cancel a pending job, returning it if it existed
+    pub fn cancel(&self, id: &str) -> Option<ScheduledWake> {
+        let mut jobs = self.jobs.lock_or_recover();
+        let index = jobs.iter().position(|job| job.id == id)?;
+        Some(jobs.remove(index))
+    }
+
+    /// Cancel every pending job for `esp_id`, returning how many there were
+    pub fn cancel_device(&self, esp_id: &str) -> usize {
+        let mut jobs = self.jobs.lock_or_recover();
+        let before = jobs.len();
+        jobs.retain(|job| job.esp_id != esp_id);
+        before - jobs.len()
+    }
+
+    /// Remove and return every job due at `now`
+    fn take_due(&self, now: DateTime<Utc>) -> Vec<ScheduledWake> {
+        let mut jobs = self.jobs.lock_or_recover();
+        let (due, pending) = jobs.drain(..).partition(|job| job.wake_at <= now);
+        *jobs = pending;
+        due
+    }
+}
+
+/// Start the task that performs scheduled wakes when they fall due
+pub fn start(
+    schedules: web::Data<Schedules>,
+    store: web::Data<DeviceStore>,
+    metrics: web::Data<Metrics>,
+    notifier: web::Data<Notifier>,
+    config: web::Data<Config>,
+    history: web::Data<WakeHistory>,
+) {
+    // Jobs are queued by `POST /schedule`, whose client isn't kept with them
+    let source = WakeSource { via: "schedule", source_ip: None };
+    actix::spawn(async move {
+        let mut interval = tokio::time::interval(SCHEDULE_INTERVAL);
+        loop {
+            interval.tick().await;
+            for job in schedules.take_due(Utc::now()) {
+                let device = store.devices.read_or_recover().get(&job.esp_id).cloned();
+                let Some(device) = device else {
+                    warn!("[Schedule] Dropping job for removed device: ID={}, job={}", job.esp_id, job.id);
+                    continue;
+                };
+                let outcome = dispatch_wake(&store, &metrics, &notifier, &config, &device).await;
+                history.record_attempt(&source, &device.esp_id, outcome.as_str(), None);
+                info!("[Schedule] Ran scheduled wake: ID={}, job={}, result={}", job.esp_id, job.id, outcome.as_str());
+            }
+        }
+    });
+}
+
+/// Render jobs as an iCalendar feed, one VEVENT per wake.
+///
+/// `names` maps esp_ids to the label used in event titles.
+pub fn to_ical(jobs: &[ScheduledWake], names: &HashMap<String, String>) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//wol-server//Scheduled wakes//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        "X-WR-CALNAME:Scheduled wakes".to_string(),
+    ];
+    for job in jobs {
+        let name = names.get(&job.esp_id).map_or(job.esp_id.as_str(), String::as_str);
+        lines.extend([
+            "BEGIN:VEVENT".to_string(),
+            format!("UID:{}@wol-server", job.id),
+            format!("DTSTAMP:{}", ical_time(job.created_at)),
+            format!("DTSTART:{}", ical_time(job.wake_at)),
+            format!("DTEND:{}", ical_time(job.wake_at + EVENT_LENGTH)),
+            format!("SUMMARY:{}", ical_text(&format!("Wake {}", name))),
+            format!("DESCRIPTION:{}", ical_text(&format!("Scheduled wake of device {}", job.esp_id))),
+            "END:VEVENT".to_string(),
+        ]);
+    }
+    lines.push("END:VCALENDAR".to_string());
+
+    lines.iter().map(|line| fold(line)).collect::<Vec<_>>().join("")
+}
+
+/// UTC date-time in iCalendar basic format
+fn ical_time(time: DateTime<Utc>) -> String {
+    time.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Escape a TEXT value (RFC 5545 section 3.3.11)
+fn ical_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\r', "")
+        .replace('\n', "\\n")
+}
+
+/// Terminate a content line with CRLF, folding it at 75 octets without splitting characters
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + 2);
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            // The leading space counts towards the continuation line
+            width = 1;
+        }
+        folded.push(c);
+        width += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
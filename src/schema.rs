@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+
+use crate::{new_device_id, Device};
+
+/// Parse the contents of a device file.
+///
+/// The current format is an object keyed by esp_id. Files written by early
+/// versions may instead hold a plain list, or entries without an `esp_id` of
+/// their own; both load with the esp_id taken from the entry or its key.
+/// Missing fields fall back to their serde defaults and renamed fields are
+/// accepted under their old names, so only genuinely broken data is refused.
+pub fn parse_devices(json: &str) -> Result<HashMap<String, Device>, String> {
+    devices_from_value(serde_json::from_str(json).map_err(|e| e.to_string())?)
+}
+
+/// Like `parse_devices`, for a document that is already parsed
+pub fn devices_from_value(value: Value) -> Result<HashMap<String, Device>, String> {
+    let entries = match value {
+        Value::Object(map) => map.into_iter().map(|(key, value)| (Some(key), value)).collect::<Vec<_>>(),
+        Value::Array(list) => list.into_iter().map(|value| (None, value)).collect(),
+        _ => return Err("expected an object keyed by esp_id".to_string()),
+    };
+
+    let mut devices = HashMap::with_capacity(entries.len());
+    for (key, value) in entries {
+        let device = parse_device(value, key.as_deref())
+            .map_err(|e| format!("device {}: {}", key.as_deref().unwrap_or("entry"), e))?;
+        devices.insert(device.esp_id.clone(), device);
+    }
+    Ok(devices)
+}
+
+/// Parse one device, using `fallback_id` when the entry has no `esp_id`.
+///
+/// Devices saved before stable ids existed are given one here; the store then
+/// sees the file as outdated and saves the new id straight away.
+pub fn parse_device(mut value: Value, fallback_id: Option<&str>) -> Result<Device, String> {
+    if let (Value::Object(map), Some(fallback_id)) = (&mut value, fallback_id) {
+        map.entry("esp_id").or_insert_with(|| Value::String(fallback_id.to_string()));
+    }
+    let mut device: Device = serde_json::from_value(value).map_err(|e| e.to_string())?;
+    if device.id.is_empty() {
+        device.id = new_device_id();
+    }
+    Ok(device)
+}
+
+/// Whether `json` differs from what saving `devices` would write, i.e. the
+/// file predates the current format
+pub fn needs_upgrade(json: &str, devices: &HashMap<String, Device>) -> bool {
+    let current = serde_json::to_value(devices).ok();
+    serde_json::from_str::<Value>(json).ok() != current
+}
+
+/// Deserialize `mac_addresses` from a list, or from the single string stored
+/// under `mac_address` before devices could have several
+pub fn mac_addresses<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(mac) => vec![mac],
+        OneOrMany::Many(macs) => macs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `devices.json` as the first release wrote it: one MAC per device and no ids
+    const V1_FILE: &str = include_str!("../tests/fixtures/devices_v1.json");
+
+    #[test]
+    fn v1_file_upgrades_cleanly() {
+        let devices = parse_devices(V1_FILE).unwrap();
+        assert_eq!(devices.len(), 2);
+        let desk = &devices["desk"];
+        assert_eq!(desk.esp_id, "desk");
+        assert_eq!(desk.mac_addresses, vec!["AA:BB:CC:DD:EE:01".to_string()]);
+        assert_eq!(desk.description, "Desk PC");
+        assert_eq!(desk.password, "secret");
+        assert!(!desk.id.is_empty());
+        assert!(needs_upgrade(V1_FILE, &devices));
+
+        // Once re-saved, the file loads back unchanged and needs no further upgrade
+        let saved = serde_json::to_string_pretty(&devices).unwrap();
+        let reloaded = parse_devices(&saved).unwrap();
+        assert!(!needs_upgrade(&saved, &reloaded));
+        assert_eq!(reloaded["nas"].id, devices["nas"].id);
+        assert_eq!(reloaded["nas"].mac_addresses, devices["nas"].mac_addresses);
+    }
+}
@@ -0,0 +1,79 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderValue, RETRY_AFTER};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, ResponseError};
+use tracing::warn;
+
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::DeviceStore;
+use crate::locks::RwLockExt;
+
+/// Counts in-flight HTTP requests so load can be judged per request
+#[derive(Default)]
+pub struct LoadMonitor {
+    in_flight: AtomicUsize,
+}
+
+/// Decrements the in-flight count when a request finishes (or is dropped)
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Routes that keep being served under load: waking, the relay WebSocket and
+/// the liveness probe, which would otherwise get the server restarted
+fn is_critical(path: &str) -> bool {
+    matches!(
+        path,
+        "/health"
+            | "/ws"
+            | "/wake"
+            | "/wake-and-wait"
+            | "/wake-batch"
+            | "/wake-group"
+            | "/wake-by-mac"
+            | "/power-state"
+    ) || path.starts_with("/wake/")
+}
+
+/// Middleware returning 503 for non-critical routes while load exceeds `WOL_SHED_THRESHOLD`.
+///
+/// Load is the number of in-flight HTTP requests plus connected relays.
+pub async fn shed_load(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let threshold = req
+        .app_data::<web::Data<Config>>()
+        .map(|config| config.shed_threshold)
+        .unwrap_or(0);
+    let Some(monitor) = req.app_data::<web::Data<LoadMonitor>>().cloned() else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+
+    let in_flight = monitor.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+    let _guard = InFlight(&monitor.in_flight);
+
+    if threshold > 0 && !is_critical(req.path()) {
+        let relays = req
+            .app_data::<web::Data<DeviceStore>>()
+            .map(|store| store.active_connections.read_or_recover().values().map(Vec::len).sum::<usize>())
+            .unwrap_or(0);
+
+        if in_flight + relays > threshold {
+            warn!("[Load] Shedding request under load: path={}, load={}", req.path(), in_flight + relays);
+            let mut response = ApiError::Unavailable("Server busy, try again later".to_string()).error_response();
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from_static("5"));
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
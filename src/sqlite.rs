@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::backup::BackupPolicy;
+use crate::locks::MutexExt;
+use crate::repository::DeviceRepository;
+use crate::{schema, Device};
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS devices (
+    esp_id TEXT PRIMARY KEY,
+    id TEXT NOT NULL,
+    mac_address TEXT NOT NULL,
+    description TEXT NOT NULL,
+    data TEXT NOT NULL
+)";
+
+/// Devices as rows of a SQLite database.
+///
+/// The lookup columns are kept alongside the full device as JSON in `data`,
+/// which loads through the same schema upgrades as the JSON backends.
+/// `mac_address` holds every MAC of the device, comma-separated.
+pub struct SqliteRepository {
+    conn: Mutex<Connection>,
+    path: PathBuf,
+    /// Backup rotation applied before each write
+    backup: Option<BackupPolicy>,
+}
+
+impl SqliteRepository {
+    /// Open or create the database at `path`, upgrading rows saved by older versions
+    pub fn open(path: &Path, backup: Option<BackupPolicy>) -> io::Result<Self> {
+        let conn = Connection::open(path).map_err(io::Error::other)?;
+        conn.execute_batch(SCHEMA).map_err(io::Error::other)?;
+        let db = Self { conn: Mutex::new(conn), path: path.to_path_buf(), backup };
+
+        let devices = db.list()?;
+        info!("[Storage] Loaded {} device(s) from {}", devices.len(), path.display());
+        // Only rows whose contents change are rewritten
+        db.save_all(&devices)?;
+        Ok(db)
+    }
+
+    /// Open an existing database without creating or changing anything, for `--check-config`
+    pub fn open_read_only(path: &Path) -> io::Result<Self> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(io::Error::other)?;
+        Ok(Self { conn: Mutex::new(conn), path: path.to_path_buf(), backup: None })
+    }
+
+    fn back_up(&self) {
+        if let Some(backup) = &self.backup {
+            if let Err(e) = backup.rotate(&self.path) {
+                warn!("[Backup] Failed to back up database {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+impl DeviceRepository for SqliteRepository {
+    fn list(&self) -> io::Result<HashMap<String, Device>> {
+        let conn = self.conn.lock_or_recover();
+        let mut stmt = conn.prepare("SELECT esp_id, data FROM devices").map_err(io::Error::other)?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+            .map_err(io::Error::other)?;
+
+        let mut devices = HashMap::new();
+        for row in rows {
+            let (esp_id, data) = row.map_err(io::Error::other)?;
+            let device = parse_row(&esp_id, &data)?;
+            devices.insert(device.esp_id.clone(), device);
+        }
+        Ok(devices)
+    }
+
+    fn get(&self, esp_id: &str) -> io::Result<Option<Device>> {
+        let conn = self.conn.lock_or_recover();
+        let data = conn
+            .query_row("SELECT data FROM devices WHERE esp_id = ?1", [esp_id], |row| row.get::<_, String>(0))
+            .optional()
+            .map_err(io::Error::other)?;
+        data.map(|data| parse_row(esp_id, &data)).transpose()
+    }
+
+    fn insert(&self, device: &Device) -> io::Result<()> {
+        self.back_up();
+        let conn = self.conn.lock_or_recover();
+        upsert(&conn, device)
+    }
+
+    fn remove(&self, esp_id: &str) -> io::Result<bool> {
+        self.back_up();
+        let conn = self.conn.lock_or_recover();
+        let removed = conn
+            .execute("DELETE FROM devices WHERE esp_id = ?1", [esp_id])
+            .map_err(io::Error::other)?;
+        Ok(removed > 0)
+    }
+
+    fn save_all(&self, devices: &HashMap<String, Device>) -> io::Result<()> {
+        let mut conn = self.conn.lock_or_recover();
+        let stored = {
+            let mut stmt = conn.prepare("SELECT esp_id, data FROM devices").map_err(io::Error::other)?;
+            let rows = stmt
+                .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+                .map_err(io::Error::other)?;
+            rows.collect::<Result<HashMap<_, _>, _>>().map_err(io::Error::other)?
+        };
+
+        let removed = stored.keys().filter(|esp_id| !devices.contains_key(*esp_id)).collect::<Vec<_>>();
+        let mut changed = Vec::new();
+        for device in devices.values() {
+            if stored.get(&device.esp_id) != Some(&serde_json::to_string(device)?) {
+                changed.push(device);
+            }
+        }
+        if removed.is_empty() && changed.is_empty() {
+            return Ok(());
+        }
+
+        self.back_up();
+        let tx = conn.transaction().map_err(io::Error::other)?;
+        for esp_id in removed {
+            tx.execute("DELETE FROM devices WHERE esp_id = ?1", [esp_id]).map_err(io::Error::other)?;
+        }
+        for device in changed {
+            upsert(&tx, device)?;
+        }
+        tx.commit().map_err(io::Error::other)
+    }
+}
+
+fn upsert(conn: &Connection, device: &Device) -> io::Result<()> {
+    conn.execute(
+        "INSERT INTO devices (esp_id, id, mac_address, description, data) VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT(esp_id) DO UPDATE SET
+             id = excluded.id,
+             mac_address = excluded.mac_address,
+             description = excluded.description,
+             data = excluded.data",
+        params![
+            device.esp_id,
+            device.id,
+            device.mac_addresses.join(","),
+            device.description,
+            serde_json::to_string(device)?
+        ],
+    )
+    .map_err(io::Error::other)?;
+    Ok(())
+}
+
+fn parse_row(esp_id: &str, data: &str) -> io::Result<Device> {
+    serde_json::from_str::<Value>(data)
+        .map_err(|e| e.to_string())
+        .and_then(|value| schema::parse_device(value, Some(esp_id)))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("device {}: {}", esp_id, e)))
+}
@@ -0,0 +1,82 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System};
+
+use crate::locks::MutexExt;
+
+/// How long a usage snapshot is reused before sampling again
+const CACHE_TTL: Duration = Duration::from_secs(5);
+
+/// Resource usage of the server process
+#[derive(Debug, Clone, Serialize)]
+pub struct ProcessUsage {
+    /// Resident memory
+    pub memory_bytes: u64,
+    pub virtual_memory_bytes: u64,
+    /// CPU use since the previous sample; 100 means one full core
+    pub cpu_percent: f32,
+    /// Not available on every platform
+    pub open_files: Option<usize>,
+    pub open_files_limit: Option<usize>,
+    /// Not available on every platform
+    pub threads: Option<usize>,
+    pub uptime_secs: u64,
+    /// Logical CPUs on the host
+    pub cpus: usize,
+    /// When this snapshot was taken (unix seconds)
+    pub sampled_at: u64,
+}
+
+/// Samples the server's own resource usage, caching results for a few seconds
+pub struct SystemMonitor {
+    pid: Option<Pid>,
+    state: Mutex<MonitorState>,
+}
+
+struct MonitorState {
+    system: System,
+    last: Option<(Instant, ProcessUsage)>,
+}
+
+impl SystemMonitor {
+    pub fn new() -> Self {
+        Self {
+            pid: sysinfo::get_current_pid().ok(),
+            state: Mutex::new(MonitorState { system: System::new(), last: None }),
+        }
+    }
+
+    /// Current usage, or None if the platform can't report on this process
+    pub fn usage(&self) -> Option<ProcessUsage> {
+        let pid = self.pid?;
+        let mut state = self.state.lock_or_recover();
+        if let Some((taken, usage)) = &state.last {
+            if taken.elapsed() < CACHE_TTL {
+                return Some(usage.clone());
+            }
+        }
+
+        // Only our own process, and only the fields reported below
+        state.system.refresh_processes_specifics(
+            ProcessesToUpdate::Some(&[pid]),
+            true,
+            ProcessRefreshKind::nothing().with_memory().with_cpu().with_tasks(),
+        );
+        let process = state.system.process(pid)?;
+        let usage = ProcessUsage {
+            memory_bytes: process.memory(),
+            virtual_memory_bytes: process.virtual_memory(),
+            cpu_percent: process.cpu_usage(),
+            open_files: process.open_files(),
+            open_files_limit: process.open_files_limit(),
+            threads: process.tasks().map(|tasks| tasks.len()),
+            uptime_secs: process.run_time(),
+            cpus: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            sampled_at: crate::unix_now(),
+        };
+        state.last = Some((Instant::now(), usage.clone()));
+        Some(usage)
+    }
+}
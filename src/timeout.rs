@@ -0,0 +1,45 @@
+use std::time::Instant;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use tracing::warn;
+
+use crate::config::Config;
+use crate::error::ApiError;
+
+/// Routes allowed to run longer: the relay WebSocket, handlers that wake several
+/// devices or wait on purpose, and `/health` when it pings relays; all of them
+/// bound their own duration
+fn is_long_running(path: &str) -> bool {
+    matches!(path, "/ws" | "/wake-and-wait" | "/wake-batch" | "/wake-group" | "/wake-by-mac" | "/health")
+}
+
+/// Middleware answering 504 when a handler takes longer than `WOL_REQUEST_TIMEOUT_SECS`.
+///
+/// The timeout covers producing the response, not streaming its body.
+pub async fn request_timeout(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let limit = req
+        .app_data::<web::Data<Config>>()
+        .map(|config| config.request_timeout)
+        .unwrap_or_default();
+    if limit.is_zero() || is_long_running(req.path()) {
+        return next.call(req).await;
+    }
+
+    // The request moves into the handler, so note what to log beforehand
+    let (method, path) = (req.method().clone(), req.path().to_string());
+    let started = Instant::now();
+    match tokio::time::timeout(limit, next.call(req)).await {
+        Ok(result) => result,
+        Err(_) => {
+            warn!("[Timeout] Request timed out after {:?}: method={}, path={}", started.elapsed(), method, path);
+            // Returned as an error since no request is left to build a ServiceResponse from
+            Err(ApiError::TimedOut.into())
+        },
+    }
+}
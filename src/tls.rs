@@ -0,0 +1,34 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use rustls::ServerConfig;
+
+/// Build the HTTPS configuration from a PEM certificate chain and private key.
+///
+/// The chain may hold intermediates after the leaf; the key may be PKCS#8,
+/// PKCS#1 or SEC1.
+pub fn load(cert_path: &Path, key_path: &Path) -> anyhow::Result<ServerConfig> {
+    let certs = rustls_pemfile::certs(&mut open(cert_path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to parse TLS certificate {}", cert_path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificate found in {}", cert_path.display());
+    }
+    let key = rustls_pemfile::private_key(&mut open(key_path)?)
+        .with_context(|| format!("Failed to parse TLS key {}", key_path.display()))?
+        .with_context(|| format!("No private key found in {}", key_path.display()))?;
+
+    ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .with_context(|| format!("TLS key {} cannot be used with certificate {}", key_path.display(), cert_path.display()))
+}
+
+fn open(path: &Path) -> anyhow::Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("Cannot open {}", path.display()))?;
+    Ok(BufReader::new(file))
+}
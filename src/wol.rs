@@ -0,0 +1,165 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+/// Standard Wake-on-LAN UDP port
+pub const DEFAULT_PORT: u16 = 9;
+
+/// Limited broadcast address used when no directed target is known
+pub const DEFAULT_BROADCAST: &str = "255.255.255.255";
+
+/// Length of a magic packet: 6 sync bytes plus 16 copies of the MAC
+pub const PACKET_LEN: usize = 6 + 16 * 6;
+
+/// Why a MAC address string couldn't be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MacParseError {
+    /// Not one of the `aa:bb:cc:dd:ee:ff`, `aa-bb-cc-dd-ee-ff` or `aabb.ccdd.eeff` forms
+    InvalidFormat,
+    /// A group contained something other than hex digits
+    InvalidHex,
+}
+
+impl fmt::Display for MacParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MacParseError::InvalidFormat => write!(
+                f,
+                "expected six hex octets like aa:bb:cc:dd:ee:ff, aa-bb-cc-dd-ee-ff or aabb.ccdd.eeff"
+            ),
+            MacParseError::InvalidHex => write!(f, "MAC address contains non-hex characters"),
+        }
+    }
+}
+
+impl std::error::Error for MacParseError {}
+
+/// Parse a MAC address in colon, dash or dotted (Cisco) notation, in either case
+pub fn parse_mac(input: &str) -> Result<[u8; 6], MacParseError> {
+    let input = input.trim();
+    let hex = if input.contains(':') || input.contains('-') {
+        let groups = input.split([':', '-']).collect::<Vec<_>>();
+        let separators_match = input.chars().filter(|c| *c == ':').count() == 5
+            || input.chars().filter(|c| *c == '-').count() == 5;
+        if groups.len() != 6 || !separators_match || groups.iter().any(|g| g.len() != 2) {
+            return Err(MacParseError::InvalidFormat);
+        }
+        groups.concat()
+    } else if input.contains('.') {
+        let groups = input.split('.').collect::<Vec<_>>();
+        if groups.len() != 3 || groups.iter().any(|g| g.len() != 4) {
+            return Err(MacParseError::InvalidFormat);
+        }
+        groups.concat()
+    } else {
+        return Err(MacParseError::InvalidFormat);
+    };
+    // Checked up front so the byte slicing below can't split a multi-byte character
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(MacParseError::InvalidHex);
+    }
+
+    let mut mac = [0u8; 6];
+    for (i, octet) in mac.iter_mut().enumerate() {
+        *octet = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| MacParseError::InvalidHex)?;
+    }
+    Ok(mac)
+}
+
+/// Format a MAC as lowercase colon-separated hex
+pub fn format_mac(mac: &[u8; 6]) -> String {
+    mac.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":")
+}
+
+/// Build the magic packet for `mac`
+pub fn magic_packet(mac: &[u8; 6]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(PACKET_LEN);
+    packet.extend_from_slice(&[0xFF; 6]);
+    for _ in 0..16 {
+        packet.extend_from_slice(mac);
+    }
+    packet
+}
+
+/// Check a device's `wake_target`: an IPv4 or IPv6 address or a hostname,
+/// optionally with a port (`192.168.10.255:7`, `[2001:db8::5]:9`), such as a
+/// subnet-directed broadcast address or the machine's own unicast address.
+///
+/// Returns the host the packets go to and the port, if the target names one.
+pub fn parse_target(spec: &str) -> Result<(String, Option<u16>), String> {
+    let spec = spec.trim();
+    let invalid = || format!("'{}' is not an IP address or hostname with an optional port", spec);
+    if let Ok(ip) = spec.parse::<IpAddr>() {
+        return Ok((ip.to_string(), None));
+    }
+    if let Ok(addr) = spec.parse::<SocketAddr>() {
+        return Ok((addr.ip().to_string(), Some(addr.port())));
+    }
+    let (host, port) = match spec.rsplit_once(':') {
+        Some((host, port)) => (host, Some(port.parse::<u16>().map_err(|_| invalid())?)),
+        None => (spec, None),
+    };
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && label.len() <= 63
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+    };
+    if host.len() > 253 || !host.trim_end_matches('.').split('.').all(valid_label) {
+        return Err(invalid());
+    }
+    Ok((host.to_string(), port))
+}
+
+/// Resolve a `wake_target` (see [`parse_target`]) to the address packets are
+/// sent to, on `default_port` unless it names its own
+pub async fn resolve_target(spec: &str, default_port: u16) -> io::Result<SocketAddr> {
+    let (host, port) = parse_target(spec).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let port = port.unwrap_or(default_port);
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port));
+    }
+    let resolved = tokio::net::lookup_host((host.as_str(), port)).await?.next();
+    resolved.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve to any address", host)))
+}
+
+/// Send `count` copies of the magic packet for `mac` to `target`, `interval` apart.
+///
+/// Returns how many went out: a failure after the first packet stops the
+/// rest but still counts as sent, since the machine may already be waking.
+pub async fn send_magic_packet(mac: &[u8; 6], target: SocketAddr, count: u32, interval: Duration) -> io::Result<u32> {
+    let bind = if target.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+    let socket = UdpSocket::bind(bind).await?;
+    // IPv6 has no broadcast; an all-nodes multicast target needs nothing extra
+    if target.is_ipv4() {
+        socket.set_broadcast(true)?;
+    }
+
+    let packet = magic_packet(mac);
+    let mut sent = 0;
+    for i in 0..count {
+        if i > 0 && !interval.is_zero() {
+            tokio::time::sleep(interval).await;
+        }
+        match socket.send_to(&packet, target).await {
+            Ok(_) => sent += 1,
+            Err(e) if sent == 0 => return Err(e),
+            Err(_) => break,
+        }
+    }
+    Ok(sent)
+}
+
+/// Wake `mac` from this host by sending `count` packets to `target`, a
+/// broadcast address or the machine itself, returning how many were sent.
+///
+/// Nothing confirms a single packet arrived, hence the repeats. Malformed MACs
+/// are reported as `InvalidInput`.
+pub async fn broadcast_magic_packet(mac: &str, target: SocketAddr, count: u32, interval: Duration) -> io::Result<u32> {
+    let mac = parse_mac(mac).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    send_magic_packet(&mac, target, count, interval).await
+}
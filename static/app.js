@@ -0,0 +1,190 @@
+// Pause before reconnecting to /live, so a server that is down isn't hammered
+const LIVE_RETRY_MS = 5000;
+
+// Latest relay details by esp_id, refreshed with the full device list
+let connections = {};
+
+async function fetchDevices() {
+    try {
+        const response = await fetch('/devices');
+        if (!response.ok) {
+            throw new Error('Failed to fetch devices');
+        }
+        
+        const devices = await response.json();
+        connections = await fetchConnections();
+        const container = document.getElementById('devices-container');
+        container.innerHTML = '';
+
+        if (!devices || devices.length === 0) {
+            container.innerHTML = '<p id="no-devices">No devices available</p>';
+            return;
+        }
+
+        devices.forEach(device => container.appendChild(renderDevice(device)));
+    } catch (error) {
+        showStatus('Connection error', false);
+    }
+}
+
+function renderDevice(device) {
+    const deviceElement = document.createElement('div');
+    deviceElement.id = `device-${device.esp_id}`;
+    // The server can still broadcast the packet itself for relay-less devices
+    const wakeable = device.online || UDP_FALLBACK;
+    deviceElement.className = wakeable ? 'device-card' : 'device-card offline';
+    const conn = device.online ? connections[device.esp_id] : null;
+    const signal = conn && conn.rssi !== null
+        ? `<p class="signal">Relay signal: ${conn.rssi} dBm</p>`
+        : '';
+    const lastSeen = !device.online
+        ? `<p class="signal">Last seen: ${device.last_seen ? new Date(device.last_seen).toLocaleString() : 'never'}</p>`
+        : '';
+    const wakes = device.wake_count > 0
+        ? `<p class="signal">Woken ${device.wake_count} time${device.wake_count === 1 ? '' : 's'}, last ${new Date(device.last_wake).toLocaleString()}</p>`
+        : '<p class="signal">Never woken</p>';
+    deviceElement.innerHTML = `
+        <h3>${device.description}</h3>
+        <p class="macs">${device.mac_addresses.join(', ')}</p>
+        ${signal}
+        ${lastSeen}
+        ${wakes}
+        <input type="password" id="pwd-${device.esp_id}" placeholder="Enter password">
+        <button class="wake-btn" onclick="wakeDevice('${device.esp_id}')" ${wakeable ? '' : 'disabled'}>
+            ${wakeable ? 'Wake Device' : 'Relay offline'}
+        </button>
+    `;
+    return deviceElement;
+}
+
+// Replace a device's card in place, keeping any password already typed into it
+function updateDevice(device) {
+    const container = document.getElementById('devices-container');
+    const card = renderDevice(device);
+    const existing = document.getElementById(card.id);
+    if (existing) {
+        card.querySelector('input').value = existing.querySelector('input').value;
+        existing.replaceWith(card);
+        return;
+    }
+    const placeholder = document.getElementById('no-devices');
+    if (placeholder) {
+        placeholder.remove();
+    }
+    container.appendChild(card);
+}
+
+function removeDevice(espId) {
+    const card = document.getElementById(`device-${espId}`);
+    if (card) {
+        card.remove();
+    }
+}
+
+// Follow /live for device changes; after a drop, reconnect and reload the list
+// since updates sent in the meantime are lost
+function followUpdates() {
+    const scheme = location.protocol === 'https:' ? 'wss:' : 'ws:';
+    const socket = new WebSocket(`${scheme}//${location.host}/live`);
+    socket.onopen = fetchDevices;
+    socket.onmessage = message => {
+        const update = JSON.parse(message.data);
+        if (update.type === 'device') {
+            updateDevice(update.device);
+        } else if (update.type === 'removed') {
+            removeDevice(update.esp_id);
+        } else if (update.type === 'wake') {
+            fetchEvents();
+        } else if (update.type === 'resync') {
+            fetchDevices();
+        }
+    };
+    socket.onclose = () => setTimeout(followUpdates, LIVE_RETRY_MS);
+}
+
+async function fetchConnections() {
+    try {
+        const response = await fetch('/connections');
+        if (!response.ok) {
+            return {};
+        }
+        const byId = {};
+        (await response.json()).forEach(conn => byId[conn.esp_id] = conn);
+        return byId;
+    } catch (error) {
+        return {};
+    }
+}
+
+// Clicks within the server's grace window reuse a token so a double-click wakes once
+const clickTokens = {};
+
+function clickToken(espId) {
+    const now = Date.now();
+    const last = clickTokens[espId];
+    if (!last || now - last.at >= WAKE_GRACE_MS) {
+        clickTokens[espId] = { token: Math.random().toString(36).slice(2) + now.toString(36), at: now };
+    }
+    return clickTokens[espId].token;
+}
+
+async function wakeDevice(espId) {
+    try {
+        const passwordInput = document.getElementById(`pwd-${espId}`);
+        const password = passwordInput ? passwordInput.value : '';
+        const token = clickToken(espId);
+        
+        const response = await fetch('/wake', {
+            method: 'POST',
+            headers: {
+                'Content-Type': 'application/json',
+            },
+            body: JSON.stringify({ 
+                esp_id: espId,
+                password: password,
+                click_token: token
+            })
+        });
+
+        if (response.ok) {
+            showStatus('Command sent successfully', true);
+        } else {
+            const body = await response.json().catch(() => null);
+            showStatus(body && body.message ? body.message : 'Command failed', false);
+        }
+    } catch (error) {
+        showStatus('Connection error', false);
+    }
+}
+
+async function fetchEvents() {
+    try {
+        const response = await fetch('/events/recent?limit=20');
+        if (!response.ok) {
+            return;
+        }
+        const list = document.getElementById('events');
+        list.innerHTML = '';
+        (await response.json()).forEach(entry => {
+            const item = document.createElement('li');
+            const time = new Date(entry.timestamp).toLocaleTimeString();
+            item.textContent = `${time} ${entry.esp_id} ${entry.event}` + (entry.detail ? ` (${entry.detail})` : '');
+            list.appendChild(item);
+        });
+    } catch (error) {
+        // Keep the last list on transient errors
+    }
+}
+
+function showStatus(message, isSuccess) {
+    const status = document.getElementById('status');
+    status.textContent = message;
+    status.className = 'status ' + (isSuccess ? 'success' : 'error');
+    setTimeout(() => {
+        status.className = 'status';
+    }, 3000);
+}
+
+document.addEventListener('DOMContentLoaded', followUpdates);
+document.addEventListener('DOMContentLoaded', fetchEvents);
+setInterval(fetchEvents, 5000);